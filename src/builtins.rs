@@ -0,0 +1,1652 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::iter::empty;
+use std::rc::Rc;
+
+use crate::dates;
+use crate::formats;
+use crate::interpreter::{
+    binop, flat_map_ok, map_ok, one, recurse_paths, recurse_paths_with, recurse_values,
+    recurse_with, Env, PathIter, RuntimeError, ValueIter,
+};
+use crate::jq_parser::{Operator, Pipeline};
+use crate::json_parser::{self, JsonValue};
+use crate::math;
+use crate::modules;
+use crate::path::{self, Path};
+use crate::regex::{Pattern, RegexCache};
+
+const ISO8601: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// evaluates every argument against `input` and calls `f` once for each
+/// combination of their outputs. like jq, the last argument is the outermost loop
+fn with_args<'a>(
+    args: &'a [Pipeline],
+    env: &Env<'a>,
+    input: JsonValue,
+    f: impl Fn(&JsonValue, &[JsonValue]) -> Result<JsonValue, RuntimeError> + 'a,
+) -> ValueIter<'a> {
+    let mut combos: Vec<Vec<JsonValue>> = vec![vec![]];
+    for arg in args.iter().rev() {
+        let outputs = match outputs(arg, env, &input) {
+            Ok(o) => o,
+            Err(e) => return one(Err(e)),
+        };
+        combos = combos
+            .into_iter()
+            .flat_map(|combo| {
+                outputs.iter().map(move |o| {
+                    let mut c = combo.clone();
+                    c.insert(0, o.clone());
+                    c
+                })
+            })
+            .collect();
+    }
+    Box::new(combos.into_iter().map(move |c| f(&input, &c)))
+}
+
+/// every output of an argument, or its first error
+fn outputs(arg: &Pipeline, env: &Env, input: &JsonValue) -> Result<Vec<JsonValue>, RuntimeError> {
+    arg.eval(env, input.clone()).collect()
+}
+
+fn as_path(v: &JsonValue) -> Result<&Path, RuntimeError> {
+    v.as_array()
+        .ok_or_else(|| RuntimeError::Path("Path must be specified as an array".to_string()))
+}
+
+fn length(v: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    match v {
+        JsonValue::Null => Ok(JsonValue::Num(0.0)),
+        JsonValue::Num(n) => Ok(JsonValue::Num(n.abs())),
+        JsonValue::Str(s) => Ok(JsonValue::Num(s.chars().count() as f64)),
+        JsonValue::Array(a) => Ok(JsonValue::Num(a.len() as f64)),
+        JsonValue::Object(o) => Ok(JsonValue::Num(o.len() as f64)),
+        JsonValue::Boolean(_) => Err(RuntimeError::invalid(v, "has no length")),
+    }
+}
+
+pub(crate) fn split(s: &str, sep: &str) -> JsonValue {
+    let parts: Vec<JsonValue> = if s.is_empty() {
+        vec![]
+    } else if sep.is_empty() {
+        s.chars().map(|c| JsonValue::Str(c.to_string())).collect()
+    } else {
+        s.split(sep)
+            .map(|e| JsonValue::Str(e.to_string()))
+            .collect()
+    };
+    JsonValue::Array(parts.into())
+}
+
+fn string_input<'a>(v: &'a JsonValue, what: &str) -> Result<&'a str, RuntimeError> {
+    v.as_str()
+        .ok_or_else(|| RuntimeError::Type(format!("{} input must be a string", what)))
+}
+
+fn is_trimmable(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\u{b}' | '\u{c}')
+}
+
+fn implode(v: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    let codepoints = v
+        .as_array()
+        .ok_or_else(|| RuntimeError::Type("implode input must be an array".to_string()))?;
+    codepoints
+        .iter()
+        .map(|c| {
+            c.as_num()
+                .and_then(|n| char::from_u32(*n as u32))
+                .ok_or_else(|| RuntimeError::invalid(c, "is not a valid codepoint"))
+        })
+        .collect::<Result<String, _>>()
+        .map(JsonValue::Str)
+}
+
+/// `join`, which like jq's is `reduce .[] as $x (null; . + $sep + $x)` with
+/// nulls as empty strings and numbers and booleans converted to text
+fn join(v: &JsonValue, sep: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    let mut joined = None;
+    for e in path::iterate(v.clone())? {
+        let e = match e {
+            JsonValue::Null => JsonValue::Str(String::new()),
+            e @ (JsonValue::Num(_) | JsonValue::Boolean(_)) => JsonValue::Str(e.to_string()),
+            e => e,
+        };
+        let acc = match joined {
+            None => JsonValue::Str(String::new()),
+            Some(acc) => binop(Operator::Add, acc, sep.clone())?,
+        };
+        joined = Some(binop(Operator::Add, acc, e)?);
+    }
+    Ok(joined.unwrap_or_else(|| JsonValue::Str(String::new())))
+}
+
+fn from_json(v: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    let s = v
+        .as_str()
+        .ok_or_else(|| RuntimeError::invalid(v, "only strings can be parsed"))?;
+    json_parser::parse_value(s)
+        .map_err(|e| RuntimeError::Type(format!("{} (while parsing '{}')", e, s)))
+}
+
+fn to_number(v: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    match v {
+        JsonValue::Num(_) => Ok(v.clone()),
+        JsonValue::Str(s) => match json_parser::parse_value(s) {
+            Ok(n @ JsonValue::Num(_)) => Ok(n),
+            _ => Err(RuntimeError::Type(format!("Cannot parse '{}' as JSON", s))),
+        },
+        v => Err(RuntimeError::invalid(v, "cannot be parsed as a number")),
+    }
+}
+
+/// `a | contains(b)`: substrings for strings, and recursively for arrays (every
+/// element of b is contained in some element of a) and objects (key by key)
+fn contains(a: &JsonValue, b: &JsonValue) -> Result<bool, RuntimeError> {
+    fn contained(a: &JsonValue, b: &JsonValue) -> bool {
+        match (a, b) {
+            (JsonValue::Object(a), JsonValue::Object(b)) => b
+                .iter()
+                .all(|(k, bv)| a.get(k).is_some_and(|av| contained(av, bv))),
+            (JsonValue::Array(a), JsonValue::Array(b)) => {
+                b.iter().all(|bv| a.iter().any(|av| contained(av, bv)))
+            }
+            (JsonValue::Str(a), JsonValue::Str(b)) => a.contains(b.as_str()),
+            // below the top level, values of different types just aren't contained
+            (a, b) => a == b,
+        }
+    }
+
+    if a.type_name() != b.type_name() {
+        return Err(RuntimeError::Type(format!(
+            "{} ({}) and {} ({}) cannot have their containment checked",
+            a.type_name(),
+            a,
+            b.type_name(),
+            b
+        )));
+    }
+    Ok(contained(a, b))
+}
+
+fn regex_input(v: &JsonValue) -> Result<&str, RuntimeError> {
+    v.as_str()
+        .ok_or_else(|| RuntimeError::invalid(v, "cannot be matched, as it is not a string"))
+}
+
+/// the regex and flags a regex builtin was called with. like jq, the
+/// one-argument forms also take them together as `[re, flags]`
+fn regex_args(a: &[JsonValue]) -> Result<(&JsonValue, &JsonValue), RuntimeError> {
+    const NO_FLAGS: &JsonValue = &JsonValue::Null;
+    match a {
+        [re, flags] => Ok((re, flags)),
+        [re @ JsonValue::Str(_)] => Ok((re, NO_FLAGS)),
+        [JsonValue::Array(pair)] if !pair.is_empty() => {
+            Ok((&pair[0], pair.get(1).unwrap_or(NO_FLAGS)))
+        }
+        [v] => Err(RuntimeError::Type(format!(
+            "{} not a string or array",
+            v.type_name()
+        ))),
+        _ => unreachable!("regex builtins take one or two arguments"),
+    }
+}
+
+/// the outputs of a builtin that gathers them into an array, one by one
+fn spread(arrays: ValueIter<'_>) -> ValueIter<'_> {
+    flat_map_ok(arrays, elements)
+}
+
+/// `.[]`
+fn elements<'a>(v: JsonValue) -> ValueIter<'a> {
+    match path::iterate(v) {
+        Ok(items) => Box::new(items.into_iter().map(Ok)),
+        Err(e) => one(Err(e)),
+    }
+}
+
+/// `sub` and `gsub`. the replacement is a filter run on the named captures of
+/// each match, and every combination of its outputs makes a result
+fn substitute(
+    input: &JsonValue,
+    pattern: &Pattern,
+    global: bool,
+    replacement: &Pipeline,
+    env: &Env,
+) -> Result<JsonValue, RuntimeError> {
+    let s = regex_input(input)?;
+    let regions = if global {
+        pattern.find_all(s)
+    } else {
+        pattern.find(s)
+    };
+
+    let mut results = vec![JsonValue::Str(String::new())];
+    let mut last = 0;
+    for region in regions {
+        let (from, to) = region.pos(0).unwrap_or((last, last));
+        let gap = JsonValue::Str(s[last..from].to_string());
+        let outputs = replacement
+            .eval(env, pattern.named_captures(s, &region))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut next = vec![];
+        for prefix in &results {
+            let prefix = binop(Operator::Add, prefix.clone(), gap.clone())?;
+            for output in &outputs {
+                next.push(binop(Operator::Add, prefix.clone(), output.clone())?);
+            }
+        }
+        results = next;
+        last = to;
+    }
+    let tail = JsonValue::Str(s[last..].to_string());
+    results
+        .into_iter()
+        .map(|r| binop(Operator::Add, r, tail.clone()))
+        .collect::<Result<Vec<_>, _>>()
+        .map(JsonValue::from)
+}
+
+/// `indices(i)`: where `i` occurs in a string or array. overlapping
+/// occurrences all count, and string positions are in codepoints
+fn indices(v: &JsonValue, i: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    let positions = |found: Vec<usize>| {
+        found
+            .into_iter()
+            .map(|p| JsonValue::Num(p as f64))
+            .collect()
+    };
+    match (v, i) {
+        (JsonValue::Null, _) => Ok(JsonValue::Null),
+        (JsonValue::Str(_), JsonValue::Str(n)) if n.is_empty() => Ok(JsonValue::Null),
+        (JsonValue::Array(_), JsonValue::Array(n)) if n.is_empty() => Ok(JsonValue::Null),
+        (JsonValue::Str(s), JsonValue::Str(n)) => {
+            let chars = s.chars().collect::<Vec<_>>();
+            let needle = n.chars().collect::<Vec<_>>();
+            Ok(positions(
+                (0..chars.len())
+                    .filter(|p| chars[*p..].starts_with(&needle))
+                    .collect(),
+            ))
+        }
+        (JsonValue::Array(a), JsonValue::Array(needle)) => Ok(positions(
+            (0..a.len())
+                .filter(|p| a[*p..].starts_with(needle))
+                .collect(),
+        )),
+        (JsonValue::Array(a), i) => Ok(positions((0..a.len()).filter(|p| a[*p] == *i).collect())),
+        (v, i) => path::get(v, i),
+    }
+}
+
+fn keys(v: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    match v {
+        JsonValue::Object(o) => Ok(o.keys().map(|k| JsonValue::Str(k.clone())).collect()),
+        JsonValue::Array(a) => Ok((0..a.len()).map(|i| JsonValue::Num(i as f64)).collect()),
+        v => Err(RuntimeError::invalid(v, "has no keys")),
+    }
+}
+
+fn has(v: &JsonValue, key: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    match (v, key) {
+        (JsonValue::Object(o), JsonValue::Str(k)) => Ok(JsonValue::Boolean(o.contains_key(k))),
+        (JsonValue::Array(a), JsonValue::Num(n)) => {
+            Ok(JsonValue::Boolean(*n >= 0.0 && *n < a.len() as f64))
+        }
+        (v, key) => Err(RuntimeError::Type(format!(
+            "Cannot check whether {} has a {} key",
+            v.type_name(),
+            key.type_name()
+        ))),
+    }
+}
+
+fn to_entries(v: JsonValue) -> Result<JsonValue, RuntimeError> {
+    let entries = path::entries(v)?
+        .into_iter()
+        .map(|(k, v)| {
+            JsonValue::from(BTreeMap::from([
+                ("key".to_string(), k),
+                ("value".to_string(), v),
+            ]))
+        })
+        .collect::<Vec<_>>();
+    Ok(JsonValue::Array(entries.into()))
+}
+
+/// like jq, entries can name their key `key`, `k`, `name`, `Name`, `K` or `Key`
+/// and their value `value` or `v`, and keys that aren't strings are stringified
+fn from_entries(v: JsonValue) -> Result<JsonValue, RuntimeError> {
+    let mut object = BTreeMap::new();
+    for entry in path::iterate(v)? {
+        // `.key // .k // ... // .Key`: the first truthy one, or else whatever `.Key` is
+        let key = match ["key", "k", "name", "Name", "K"]
+            .iter()
+            .map(|k| path::get(&entry, &JsonValue::Str(k.to_string())))
+            .find(|k| matches!(k, Ok(k) if k.is_truthy()))
+        {
+            Some(key) => key?,
+            None => path::get(&entry, &JsonValue::Str("Key".to_string()))?,
+        };
+        let value = match &entry {
+            JsonValue::Object(o) if o.contains_key("value") => o["value"].clone(),
+            _ => path::get(&entry, &JsonValue::Str("v".to_string()))?,
+        };
+        object.insert(formats::to_text(&key), value);
+    }
+    Ok(JsonValue::Object(object.into()))
+}
+
+fn add(values: Vec<JsonValue>) -> Result<JsonValue, RuntimeError> {
+    values
+        .into_iter()
+        .try_fold(JsonValue::Null, |acc, v| binop(Operator::Add, acc, v))
+}
+
+fn flatten(v: JsonValue, depth: f64) -> Result<JsonValue, RuntimeError> {
+    if depth < 0.0 {
+        return Err(RuntimeError::Type(
+            "flatten depth must not be negative".to_string(),
+        ));
+    }
+    let mut flat = vec![];
+    for e in path::iterate(v)? {
+        match e {
+            JsonValue::Array(_) if depth != 0.0 => match flatten(e, depth - 1.0)? {
+                JsonValue::Array(inner) => flat.extend(Rc::unwrap_or_clone(inner)),
+                _ => unreachable!("flattening an array gives an array"),
+            },
+            e => flat.push(e),
+        }
+    }
+    Ok(JsonValue::Array(flat.into()))
+}
+
+fn reverse(v: JsonValue) -> Result<JsonValue, RuntimeError> {
+    match v {
+        JsonValue::Null => Ok(JsonValue::Array(vec![].into())),
+        JsonValue::Str(s) => Ok(JsonValue::Str(s.chars().rev().collect())),
+        JsonValue::Array(a) => {
+            let mut a = Rc::unwrap_or_clone(a);
+            a.reverse();
+            Ok(JsonValue::Array(a.into()))
+        }
+        v => Err(RuntimeError::invalid(
+            &v,
+            "cannot be reversed, as it is not an array",
+        )),
+    }
+}
+
+/// the element with the first smallest or last largest key, which is what jq's
+/// `min` and `max` pick
+fn extreme<T>(keyed: Vec<(JsonValue, T)>, want: Ordering) -> Option<T> {
+    keyed
+        .into_iter()
+        .reduce(|best, e| {
+            let ord = e.0.compare(&best.0);
+            if ord == want || (ord.is_eq() && want.is_gt()) {
+                e
+            } else {
+                best
+            }
+        })
+        .map(|(_, e)| e)
+}
+
+/// `any(generator; condition)` and `all(generator; condition)`, stopping at the
+/// first output that decides the answer
+fn quantify<'a>(
+    outputs: ValueIter<'a>,
+    cond: Option<&'a Pipeline>,
+    env: &Env<'a>,
+    any: bool,
+) -> ValueIter<'a> {
+    for output in outputs {
+        let checks = match (output, cond) {
+            (Ok(v), Some(cond)) => cond.eval(env, v),
+            (output, None) => one(output),
+            (Err(e), _) => return one(Err(e)),
+        };
+        for check in checks {
+            match check {
+                Ok(c) if c.is_truthy() == any => return one(Ok(JsonValue::Boolean(any))),
+                Ok(_) => {}
+                Err(e) => return one(Err(e)),
+            }
+        }
+    }
+    one(Ok(JsonValue::Boolean(!any)))
+}
+
+/// `tostream`: `[path, leaf]` for every leaf in document order, and a closing
+/// `[path]` (pointing at the last child) after the children of each array or object
+fn to_stream(path: Path, v: JsonValue, events: &mut Vec<JsonValue>) {
+    let children = match v {
+        JsonValue::Array(ref a) if !a.is_empty() => path::entries(v).unwrap_or_default(),
+        JsonValue::Object(ref o) if !o.is_empty() => path::entries(v).unwrap_or_default(),
+        v => {
+            events.push(JsonValue::from(vec![JsonValue::Array(path.into()), v]));
+            return;
+        }
+    };
+    let mut last = None;
+    for (key, child) in children {
+        let mut child_path = path.clone();
+        child_path.push(key.clone());
+        to_stream(child_path, child, events);
+        last = Some(key);
+    }
+    let mut closing = path;
+    closing.extend(last);
+    events.push(JsonValue::from(vec![JsonValue::Array(closing.into())]));
+}
+
+fn stream_event(e: &JsonValue) -> Result<(&Path, Option<&JsonValue>), RuntimeError> {
+    match e.as_array().map(|e| e.as_slice()) {
+        Some([JsonValue::Array(p), v]) => Ok((p, Some(v))),
+        Some([JsonValue::Array(p)]) => Ok((p, None)),
+        _ => Err(RuntimeError::invalid(e, "is not a valid stream event")),
+    }
+}
+
+/// `fromstream(f)`: puts the values `tostream` took apart back together
+fn from_stream(events: ValueIter<'_>) -> ValueIter<'_> {
+    let mut value = JsonValue::Null;
+    let mut done = false;
+    Box::new(events.filter_map(move |e| {
+        let e = match e {
+            Ok(e) => e,
+            Err(e) => return Some(Err(e)),
+        };
+        if done {
+            value = JsonValue::Null;
+        }
+        let (path, leaf) = match stream_event(&e) {
+            Ok(event) => event,
+            Err(err) => return Some(Err(err)),
+        };
+        match leaf {
+            Some(leaf) => {
+                done = path.is_empty();
+                match path::setpath(
+                    std::mem::replace(&mut value, JsonValue::Null),
+                    path,
+                    leaf.clone(),
+                ) {
+                    Ok(v) => value = v,
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+            None => done = path.len() == 1,
+        }
+        done.then(|| Ok(value.clone()))
+    }))
+}
+
+/// `truncate_stream(f)`: drops the first `depth` keys from the paths of the
+/// events `f` produces, and the events that don't go that deep
+fn truncate_stream(depth: JsonValue, events: ValueIter<'_>) -> ValueIter<'_> {
+    let depth = match depth {
+        JsonValue::Num(d) => d.max(0.0) as usize,
+        _ => {
+            return one(Err(RuntimeError::Type(
+                "truncate_stream depth must be a number".to_string(),
+            )))
+        }
+    };
+    Box::new(events.filter_map(move |e| {
+        let e = match e {
+            Ok(e) => e,
+            Err(err) => return Some(Err(err)),
+        };
+        let (path, leaf) = match stream_event(&e) {
+            Ok(event) => event,
+            Err(err) => return Some(Err(err)),
+        };
+        if path.len() <= depth {
+            return None;
+        }
+        let mut truncated = vec![JsonValue::Array(path[depth..].to_vec().into())];
+        truncated.extend(leaf.cloned());
+        Some(Ok(JsonValue::Array(truncated.into())))
+    }))
+}
+
+fn sortable(v: JsonValue) -> Result<Vec<JsonValue>, RuntimeError> {
+    match v {
+        JsonValue::Array(a) => Ok(Rc::unwrap_or_clone(a)),
+        v => Err(RuntimeError::invalid(
+            &v,
+            "cannot be sorted, as it is not an array",
+        )),
+    }
+}
+
+/// pairs each element with its sort key, the array of `f`'s outputs for it, so
+/// that `f` runs once per element rather than once per comparison. the pairs
+/// come back stably sorted by key
+fn sorted_by(
+    v: JsonValue,
+    f: &Pipeline,
+    env: &Env,
+) -> Result<Vec<(JsonValue, JsonValue)>, RuntimeError> {
+    let mut keyed = sortable(v)?
+        .into_iter()
+        .map(|e| Ok((JsonValue::Array(outputs(f, env, &e)?.into()), e)))
+        .collect::<Result<Vec<_>, RuntimeError>>()?;
+    keyed.sort_by(|(a, _), (b, _)| a.compare(b));
+    Ok(keyed)
+}
+
+/// runs of elements with equal keys, from pairs already sorted by key
+fn groups(sorted: Vec<(JsonValue, JsonValue)>) -> Vec<Vec<JsonValue>> {
+    let mut groups: Vec<(JsonValue, Vec<JsonValue>)> = vec![];
+    for (key, e) in sorted {
+        match groups.last_mut() {
+            Some((k, group)) if k.compare(&key).is_eq() => group.push(e),
+            _ => groups.push((key, vec![e])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// `min_by(f)` and `max_by(f)`
+fn extreme_by(
+    v: JsonValue,
+    f: &Pipeline,
+    env: &Env,
+    want: Ordering,
+) -> Result<JsonValue, RuntimeError> {
+    let keyed = sortable(v)?
+        .into_iter()
+        .map(|e| Ok((JsonValue::Array(outputs(f, env, &e)?.into()), e)))
+        .collect::<Result<Vec<_>, RuntimeError>>()?;
+    Ok(extreme(keyed, want).unwrap_or(JsonValue::Null))
+}
+
+/// every path below the input, not including the empty path to the input itself
+fn sub_paths<'a>(input: JsonValue) -> PathIter<'a> {
+    Box::new(recurse_paths((vec![], input)).filter(|r| !matches!(r, Ok((p, _)) if p.is_empty())))
+}
+
+fn undefined<'a, T: 'a>(
+    name: &str,
+    arity: usize,
+) -> Box<dyn Iterator<Item = Result<T, RuntimeError>> + 'a> {
+    one(Err(RuntimeError::Undefined(format!(
+        "{}/{} is not defined",
+        name, arity
+    ))))
+}
+
+/// `env` and `$ENV`: the process's environment variables as an object
+pub(crate) fn environment() -> JsonValue {
+    std::env::vars_os()
+        .map(|(k, v)| {
+            let v = JsonValue::Str(v.to_string_lossy().into_owned());
+            (k.to_string_lossy().into_owned(), v)
+        })
+        .collect()
+}
+
+/// whether `v` is picked out by the type selector `name`, e.g. `numbers`
+fn selected(name: &str, v: &JsonValue) -> bool {
+    match name {
+        "iterables" => matches!(v, JsonValue::Array(_) | JsonValue::Object(_)),
+        "scalars" => !matches!(v, JsonValue::Array(_) | JsonValue::Object(_)),
+        "values" => !matches!(v, JsonValue::Null),
+        // arrays, objects, ... are the type names in the plural
+        _ => name.strip_suffix('s') == Some(v.type_name()),
+    }
+}
+
+/// every builtin as `(name, arity)`, so calls can be checked before anything runs.
+/// keep it in step with `call`
+pub(crate) const BUILTINS: &[(&str, usize)] = &[
+    ("empty", 0),
+    ("error", 0),
+    ("error", 1),
+    ("not", 0),
+    ("select", 1),
+    ("type", 0),
+    ("arrays", 0),
+    ("objects", 0),
+    ("strings", 0),
+    ("numbers", 0),
+    ("booleans", 0),
+    ("nulls", 0),
+    ("iterables", 0),
+    ("scalars", 0),
+    ("values", 0),
+    ("input", 0),
+    ("inputs", 0),
+    ("env", 0),
+    ("input_filename", 0),
+    ("input_line_number", 0),
+    ("modulemeta", 0),
+    ("recurse", 0),
+    ("recurse", 1),
+    ("length", 0),
+    ("split", 1),
+    ("split", 2),
+    ("splits", 1),
+    ("splits", 2),
+    ("test", 1),
+    ("test", 2),
+    ("match", 1),
+    ("match", 2),
+    ("capture", 1),
+    ("capture", 2),
+    ("scan", 1),
+    ("scan", 2),
+    ("sub", 2),
+    ("sub", 3),
+    ("gsub", 2),
+    ("gsub", 3),
+    ("ltrimstr", 1),
+    ("rtrimstr", 1),
+    ("trim", 0),
+    ("ltrim", 0),
+    ("rtrim", 0),
+    ("startswith", 1),
+    ("endswith", 1),
+    ("ascii_downcase", 0),
+    ("ascii_upcase", 0),
+    ("explode", 0),
+    ("implode", 0),
+    ("ascii", 0),
+    ("join", 1),
+    ("tostring", 0),
+    ("tojson", 0),
+    ("fromjson", 0),
+    ("tonumber", 0),
+    ("utf8bytelength", 0),
+    ("contains", 1),
+    ("inside", 1),
+    ("indices", 1),
+    ("index", 1),
+    ("rindex", 1),
+    ("keys", 0),
+    ("keys_unsorted", 0),
+    ("has", 1),
+    ("in", 1),
+    ("to_entries", 0),
+    ("from_entries", 0),
+    ("with_entries", 1),
+    ("add", 0),
+    ("add", 1),
+    ("flatten", 0),
+    ("flatten", 1),
+    ("reverse", 0),
+    ("min", 0),
+    ("max", 0),
+    ("any", 0),
+    ("all", 0),
+    ("any", 1),
+    ("all", 1),
+    ("any", 2),
+    ("all", 2),
+    ("tostream", 0),
+    ("fromstream", 1),
+    ("truncate_stream", 1),
+    ("sort", 0),
+    ("sort_by", 1),
+    ("group_by", 1),
+    ("unique", 0),
+    ("unique_by", 1),
+    ("min_by", 1),
+    ("max_by", 1),
+    ("now", 0),
+    ("mktime", 0),
+    ("gmtime", 0),
+    ("localtime", 0),
+    ("strftime", 1),
+    ("strflocaltime", 1),
+    ("strptime", 1),
+    ("todate", 0),
+    ("todateiso8601", 0),
+    ("date", 0),
+    ("fromdate", 0),
+    ("fromdateiso8601", 0),
+    ("dateadd", 2),
+    ("datesub", 2),
+    ("path", 1),
+    ("paths", 0),
+    ("paths", 1),
+    ("leaf_paths", 0),
+    ("getpath", 1),
+    ("setpath", 2),
+    ("delpaths", 1),
+    ("del", 1),
+    ("pick", 1),
+    ("infinite", 0),
+    ("nan", 0),
+    ("isinfinite", 0),
+    ("isnan", 0),
+    ("isnormal", 0),
+    ("floor", 0),
+    ("ceil", 0),
+    ("round", 0),
+    ("trunc", 0),
+    ("fabs", 0),
+    ("abs", 0),
+    ("sqrt", 0),
+    ("cbrt", 0),
+    ("exp", 0),
+    ("exp2", 0),
+    ("exp10", 0),
+    ("pow10", 0),
+    ("expm1", 0),
+    ("log", 0),
+    ("log2", 0),
+    ("log10", 0),
+    ("log1p", 0),
+    ("logb", 0),
+    ("significand", 0),
+    ("sin", 0),
+    ("cos", 0),
+    ("tan", 0),
+    ("asin", 0),
+    ("acos", 0),
+    ("atan", 0),
+    ("sinh", 0),
+    ("cosh", 0),
+    ("tanh", 0),
+    ("asinh", 0),
+    ("acosh", 0),
+    ("atanh", 0),
+    ("gamma", 0),
+    ("lgamma", 0),
+    ("tgamma", 0),
+    ("nearbyint", 0),
+    ("rint", 0),
+    ("j0", 0),
+    ("j1", 0),
+    ("y0", 0),
+    ("y1", 0),
+    ("lgamma_r", 0),
+    ("frexp", 0),
+    ("modf", 0),
+    ("pow", 2),
+    ("atan2", 2),
+    ("hypot", 2),
+    ("copysign", 2),
+    ("fmin", 2),
+    ("fmax", 2),
+    ("fmod", 2),
+    ("fdim", 2),
+    ("drem", 2),
+    ("ldexp", 2),
+    ("scalb", 2),
+    ("scalbln", 2),
+    ("nextafter", 2),
+    ("nexttoward", 2),
+    ("fma", 3),
+];
+
+pub(crate) fn is_defined(name: &str, arity: usize) -> bool {
+    BUILTINS.contains(&(name, arity))
+}
+
+pub(crate) fn call<'a>(
+    name: &'a str,
+    args: &'a [Pipeline],
+    cache: &'a RegexCache,
+    env: &Env<'a>,
+    input: JsonValue,
+) -> ValueIter<'a> {
+    match (name, args.len()) {
+        ("empty", 0) => Box::new(empty()),
+        ("error", 0) => one(Err(RuntimeError::Custom(input))),
+        ("error", 1) => flat_map_ok(args[0].eval(env, input), |v| {
+            one(Err(RuntimeError::Custom(v)))
+        }),
+        ("not", 0) => one(Ok(JsonValue::Boolean(!input.is_truthy()))),
+        ("select", 1) => Box::new(
+            args[0]
+                .eval(env, input.clone())
+                .filter_map(move |r| match r {
+                    Ok(c) if c.is_truthy() => Some(Ok(input.clone())),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                }),
+        ),
+        ("type", 0) => one(Ok(JsonValue::Str(input.type_name().to_string()))),
+        (
+            "arrays" | "objects" | "strings" | "numbers" | "booleans" | "nulls" | "iterables"
+            | "scalars" | "values",
+            0,
+        ) => {
+            if selected(name, &input) {
+                one(Ok(input))
+            } else {
+                Box::new(empty())
+            }
+        }
+        ("input", 0) => one(env
+            .next_input()
+            .unwrap_or_else(|| Err(RuntimeError::Type("No more inputs".to_string())))),
+        ("inputs", 0) => {
+            let env = env.clone();
+            Box::new(std::iter::from_fn(move || env.next_input()))
+        }
+        ("env", 0) => one(Ok(environment())),
+        ("input_filename", 0) => one(Ok(env.input_filename())),
+        ("input_line_number", 0) => one(Ok(env.input_line_number())),
+        ("modulemeta", 0) => one(match &input {
+            JsonValue::Str(name) => modules::meta(env.modules(), name),
+            _ => Err(RuntimeError::Type(
+                "modulemeta input module not a string".to_string(),
+            )),
+        }),
+        ("recurse", 0) => recurse_values(input),
+        ("recurse", 1) => recurse_with(&args[0], env, input),
+        ("length", 0) => one(length(&input)),
+        ("split", 1) => with_args(args, env, input, |v, a| match (v, &a[0]) {
+            (JsonValue::Str(s), JsonValue::Str(sep)) => Ok(split(s, sep)),
+            _ => Err(RuntimeError::Type(
+                "split input and separator must be strings".to_string(),
+            )),
+        }),
+        ("split", 2) => with_args(args, env, input, |v, a| {
+            Ok(cache.get(&a[0], &a[1])?.split(regex_input(v)?))
+        }),
+        ("splits", 1 | 2) => spread(with_args(args, env, input, |v, a| {
+            let (re, flags) = regex_args(a)?;
+            Ok(cache.get(re, flags)?.split(regex_input(v)?))
+        })),
+        ("test", 1 | 2) => with_args(args, env, input, |v, a| {
+            let (re, flags) = regex_args(a)?;
+            Ok(JsonValue::Boolean(
+                cache.get(re, flags)?.is_match(regex_input(v)?),
+            ))
+        }),
+        ("match", 1 | 2) => spread(with_args(args, env, input, |v, a| {
+            let (re, flags) = regex_args(a)?;
+            let (pattern, s) = (cache.get(re, flags)?, regex_input(v)?);
+            Ok(pattern
+                .find(s)
+                .iter()
+                .map(|r| pattern.match_object(s, r))
+                .collect())
+        })),
+        ("capture", 1 | 2) => spread(with_args(args, env, input, |v, a| {
+            let (re, flags) = regex_args(a)?;
+            let (pattern, s) = (cache.get(re, flags)?, regex_input(v)?);
+            Ok(pattern
+                .find(s)
+                .iter()
+                .map(|r| pattern.named_captures(s, r))
+                .collect())
+        })),
+        ("scan", 1 | 2) => spread(with_args(args, env, input, |v, a| {
+            let (re, flags) = regex_args(a)?;
+            let (pattern, s) = (cache.get(re, flags)?, regex_input(v)?);
+            Ok(pattern
+                .find_all(s)
+                .iter()
+                .map(|r| pattern.scanned(s, r))
+                .collect())
+        })),
+        ("sub" | "gsub", 2 | 3) => {
+            // the regex and flags are values but the replacement is a filter, so
+            // this is `with_args` over just the first and third arguments
+            let flags = match args.get(2) {
+                Some(flags) => outputs(flags, env, &input),
+                None => Ok(vec![JsonValue::Null]),
+            };
+            let combos = match (outputs(&args[0], env, &input), flags) {
+                (Ok(res), Ok(flags)) => flags
+                    .into_iter()
+                    .flat_map(|f| res.iter().map(move |re| (re.clone(), f.clone())))
+                    .collect::<Vec<_>>(),
+                (Err(e), _) | (_, Err(e)) => return one(Err(e)),
+            };
+            let global = name == "gsub";
+            let env = env.clone();
+            spread(Box::new(combos.into_iter().map(move |(re, flags)| {
+                let pattern = cache.get(&re, &flags)?;
+                substitute(&input, &pattern, global, &args[1], &env)
+            })))
+        }
+        ("ltrimstr", 1) => with_args(args, env, input, |v, a| match (v, &a[0]) {
+            (JsonValue::Str(s), JsonValue::Str(prefix)) => Ok(JsonValue::Str(
+                s.strip_prefix(prefix.as_str()).unwrap_or(s).to_string(),
+            )),
+            _ => Ok(v.clone()),
+        }),
+        ("rtrimstr", 1) => with_args(args, env, input, |v, a| match (v, &a[0]) {
+            (JsonValue::Str(s), JsonValue::Str(suffix)) => Ok(JsonValue::Str(
+                s.strip_suffix(suffix.as_str()).unwrap_or(s).to_string(),
+            )),
+            _ => Ok(v.clone()),
+        }),
+        ("trim", 0) => one(string_input(&input, "trim")
+            .map(|s| JsonValue::Str(s.trim_matches(is_trimmable).to_string()))),
+        ("ltrim", 0) => one(string_input(&input, "trim")
+            .map(|s| JsonValue::Str(s.trim_start_matches(is_trimmable).to_string()))),
+        ("rtrim", 0) => one(string_input(&input, "trim")
+            .map(|s| JsonValue::Str(s.trim_end_matches(is_trimmable).to_string()))),
+        ("startswith", 1) => with_args(args, env, input, |v, a| match (v, &a[0]) {
+            (JsonValue::Str(s), JsonValue::Str(prefix)) => {
+                Ok(JsonValue::Boolean(s.starts_with(prefix.as_str())))
+            }
+            _ => Err(RuntimeError::Type(
+                "startswith() requires string inputs".to_string(),
+            )),
+        }),
+        ("endswith", 1) => with_args(args, env, input, |v, a| match (v, &a[0]) {
+            (JsonValue::Str(s), JsonValue::Str(suffix)) => {
+                Ok(JsonValue::Boolean(s.ends_with(suffix.as_str())))
+            }
+            _ => Err(RuntimeError::Type(
+                "endswith() requires string inputs".to_string(),
+            )),
+        }),
+        ("ascii_downcase", 0) => {
+            one(string_input(&input, "ascii_downcase")
+                .map(|s| JsonValue::Str(s.to_ascii_lowercase())))
+        }
+        ("ascii_upcase", 0) => {
+            one(string_input(&input, "ascii_upcase")
+                .map(|s| JsonValue::Str(s.to_ascii_uppercase())))
+        }
+        ("explode", 0) => one(string_input(&input, "explode")
+            .map(|s| s.chars().map(|c| JsonValue::Num(c as u32 as f64)).collect())),
+        ("implode", 0) => one(implode(&input)),
+        ("ascii", 0) => one(match input {
+            JsonValue::Num(n) if (0.0..128.0).contains(&n) => {
+                Ok(JsonValue::Str(((n as u8) as char).to_string()))
+            }
+            v => Err(RuntimeError::invalid(&v, "is not an ASCII codepoint")),
+        }),
+        ("join", 1) => with_args(args, env, input, |v, a| join(v, &a[0])),
+        ("tostring", 0) => one(Ok(JsonValue::Str(formats::to_text(&input)))),
+        ("tojson", 0) => one(Ok(JsonValue::Str(input.to_string()))),
+        ("fromjson", 0) => one(from_json(&input)),
+        ("tonumber", 0) => one(to_number(&input)),
+        ("utf8bytelength", 0) => one(input
+            .as_str()
+            .map(|s| JsonValue::Num(s.len() as f64))
+            .ok_or_else(|| RuntimeError::invalid(&input, "only strings have UTF-8 byte length"))),
+        ("contains", 1) => with_args(args, env, input, |v, a| {
+            contains(v, &a[0]).map(JsonValue::Boolean)
+        }),
+        ("inside", 1) => with_args(args, env, input, |v, a| {
+            contains(&a[0], v).map(JsonValue::Boolean)
+        }),
+        ("indices", 1) => with_args(args, env, input, |v, a| indices(v, &a[0])),
+        ("index", 1) => with_args(args, env, input, |v, a| match indices(v, &a[0])? {
+            JsonValue::Array(found) => Ok(found.first().cloned().unwrap_or(JsonValue::Null)),
+            found => Ok(found),
+        }),
+        ("rindex", 1) => with_args(args, env, input, |v, a| match indices(v, &a[0])? {
+            JsonValue::Array(found) => Ok(found.last().cloned().unwrap_or(JsonValue::Null)),
+            found => Ok(found),
+        }),
+        // objects keep their keys sorted, so the unsorted keys are the sorted ones
+        ("keys" | "keys_unsorted", 0) => one(keys(&input)),
+        ("has", 1) => with_args(args, env, input, |v, a| has(v, &a[0])),
+        ("in", 1) => with_args(args, env, input, |v, a| has(&a[0], v)),
+        ("to_entries", 0) => one(to_entries(input)),
+        ("from_entries", 0) => one(from_entries(input)),
+        ("with_entries", 1) => one(to_entries(input).and_then(|entries| {
+            let mapped = path::iterate(entries)?
+                .into_iter()
+                .map(|e| outputs(&args[0], env, &e))
+                .collect::<Result<Vec<_>, _>>()?;
+            from_entries(mapped.into_iter().flatten().collect())
+        })),
+        ("add", 0) => one(path::iterate(input).and_then(add)),
+        ("add", 1) => one(outputs(&args[0], env, &input).and_then(add)),
+        ("flatten", 0) => one(flatten(input, 1e9)),
+        ("flatten", 1) => with_args(args, env, input, |v, a| match &a[0] {
+            JsonValue::Num(depth) => flatten(v.clone(), *depth),
+            _ => Err(RuntimeError::Type(
+                "flatten depth must be a number".to_string(),
+            )),
+        }),
+        ("reverse", 0) => one(reverse(input)),
+        ("min", 0) => one(sortable(input).map(|a| {
+            let keyed = a.into_iter().map(|e| (e.clone(), e)).collect();
+            extreme(keyed, Ordering::Less).unwrap_or(JsonValue::Null)
+        })),
+        ("max", 0) => one(sortable(input).map(|a| {
+            let keyed = a.into_iter().map(|e| (e.clone(), e)).collect();
+            extreme(keyed, Ordering::Greater).unwrap_or(JsonValue::Null)
+        })),
+        ("any", 0) => quantify(elements(input), None, env, true),
+        ("all", 0) => quantify(elements(input), None, env, false),
+        ("any", 1) => quantify(elements(input), Some(&args[0]), env, true),
+        ("all", 1) => quantify(elements(input), Some(&args[0]), env, false),
+        ("any", 2) => quantify(args[0].eval(env, input), Some(&args[1]), env, true),
+        ("all", 2) => quantify(args[0].eval(env, input), Some(&args[1]), env, false),
+        ("tostream", 0) => {
+            let mut events = vec![];
+            to_stream(vec![], input, &mut events);
+            Box::new(events.into_iter().map(Ok))
+        }
+        ("fromstream", 1) => from_stream(args[0].eval(env, input)),
+        // like jq, the events are generated from null rather than the depth
+        ("truncate_stream", 1) => truncate_stream(input, args[0].eval(env, JsonValue::Null)),
+        ("sort", 0) => one(sortable(input).map(|mut a| {
+            a.sort_by(|x, y| x.compare(y));
+            JsonValue::Array(a.into())
+        })),
+        ("sort_by", 1) => one(sorted_by(input, &args[0], env)
+            .map(|sorted| sorted.into_iter().map(|(_, e)| e).collect())),
+        ("group_by", 1) => one(sorted_by(input, &args[0], env)
+            .map(|sorted| groups(sorted).into_iter().map(JsonValue::from).collect())),
+        ("unique", 0) => one(sortable(input).map(|mut a| {
+            a.sort_by(|x, y| x.compare(y));
+            a.dedup_by(|x, y| x.compare(y).is_eq());
+            JsonValue::Array(a.into())
+        })),
+        ("unique_by", 1) => one(sorted_by(input, &args[0], env)
+            .map(|sorted| groups(sorted).into_iter().map(|g| g[0].clone()).collect())),
+        ("min_by", 1) => one(extreme_by(input, &args[0], env, Ordering::Less)),
+        ("max_by", 1) => one(extreme_by(input, &args[0], env, Ordering::Greater)),
+        ("now", 0) => one(Ok(dates::now())),
+        ("mktime", 0) => one(dates::mktime(&input)),
+        ("gmtime", 0) => one(dates::split_time(&input, false)),
+        ("localtime", 0) => one(dates::split_time(&input, true)),
+        ("strftime", 1) => with_args(args, env, input, |v, a| dates::strftime(v, &a[0], false)),
+        ("strflocaltime", 1) => with_args(args, env, input, |v, a| dates::strftime(v, &a[0], true)),
+        ("strptime", 1) => with_args(args, env, input, |v, a| dates::strptime(v, &a[0])),
+        ("todate" | "todateiso8601" | "date", 0) => one(dates::strftime(
+            &input,
+            &JsonValue::Str(ISO8601.to_string()),
+            false,
+        )),
+        ("fromdate" | "fromdateiso8601", 0) => one(dates::strptime(
+            &input,
+            &JsonValue::Str(ISO8601.to_string()),
+        )
+        .and_then(|tm| dates::mktime(&tm))),
+        // the unit argument is ignored, as it is in jq
+        ("dateadd", 2) => with_args(args, env, input, |v, a| {
+            binop(Operator::Add, v.clone(), a[1].clone())
+        }),
+        ("datesub", 2) => with_args(args, env, input, |v, a| {
+            binop(Operator::Sub, v.clone(), a[1].clone())
+        }),
+        ("path", 1) => map_ok(args[0].eval_paths(env, (vec![], input)), |(p, _)| {
+            Ok(JsonValue::Array(p.into()))
+        }),
+        ("paths", 0) => map_ok(sub_paths(input), |(p, _)| Ok(JsonValue::Array(p.into()))),
+        ("paths", 1) => {
+            let env = env.clone();
+            flat_map_ok(sub_paths(input), move |(p, v)| {
+                Box::new(args[0].eval(&env, v).filter_map(move |r| match r {
+                    Ok(c) if c.is_truthy() => Some(Ok(JsonValue::Array(p.clone().into()))),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                }))
+            })
+        }
+        ("leaf_paths", 0) => Box::new(sub_paths(input).filter_map(|r| match r {
+            Ok((_, JsonValue::Array(_) | JsonValue::Object(_))) => None,
+            r => Some(r.map(|(p, _)| JsonValue::Array(p.into()))),
+        })),
+        ("getpath", 1) => with_args(args, env, input, |v, a| path::getpath(v, as_path(&a[0])?)),
+        ("setpath", 2) => with_args(args, env, input, |v, a| {
+            path::setpath(v.clone(), as_path(&a[0])?, a[1].clone())
+        }),
+        ("delpaths", 1) => with_args(args, env, input, |v, a| {
+            let paths = match &a[0] {
+                JsonValue::Array(ps) => ps
+                    .iter()
+                    .map(|p| as_path(p).cloned())
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => {
+                    return Err(RuntimeError::Path(
+                        "Paths must be specified as an array".to_string(),
+                    ))
+                }
+            };
+            path::delpaths(v.clone(), paths)
+        }),
+        ("del", 1) => one(args[0]
+            .eval_paths(env, (vec![], input.clone()))
+            .map(|r| r.map(|(p, _)| p))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|paths| path::delpaths(input, paths))),
+        ("pick", 1) => one(args[0].eval_paths(env, (vec![], input.clone())).try_fold(
+            JsonValue::Null,
+            |acc, r| {
+                let (p, _) = r?;
+                let picked = path::getpath(&input, &p)?;
+                path::setpath(acc, &p, picked)
+            },
+        )),
+        ("infinite", 0) => one(Ok(JsonValue::Num(f64::INFINITY))),
+        ("nan", 0) => one(Ok(JsonValue::Num(f64::NAN))),
+        ("isinfinite", 0) => one(math::number(&input).map(|n| JsonValue::Boolean(n.is_infinite()))),
+        ("isnan", 0) => one(math::number(&input).map(|n| JsonValue::Boolean(n.is_nan()))),
+        ("isnormal", 0) => one(math::number(&input).map(|n| JsonValue::Boolean(n.is_normal()))),
+        (name, arity) => match math::function(name, arity) {
+            Some(f) if arity == 0 => one(math::apply(f, &[input])),
+            Some(f) => with_args(args, env, input, move |_, a| math::apply(f, a)),
+            None => undefined(name, arity),
+        },
+    }
+}
+
+/// the builtins that can appear in a path expression, e.g. `path(.a | select(.b))`
+pub(crate) fn call_paths<'a>(
+    name: &'a str,
+    args: &'a [Pipeline],
+    cache: &'a RegexCache,
+    env: &Env<'a>,
+    input: (Path, JsonValue),
+) -> PathIter<'a> {
+    match (name, args.len()) {
+        ("empty", 0) => Box::new(empty()),
+        ("error", 0) => one(Err(RuntimeError::Custom(input.1))),
+        ("error", 1) => flat_map_ok(args[0].eval(env, input.1), |v| {
+            one(Err(RuntimeError::Custom(v)))
+        }),
+        ("select", 1) => Box::new(args[0].eval(env, input.1.clone()).filter_map(
+            move |r| match r {
+                Ok(c) if c.is_truthy() => Some(Ok(input.clone())),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            },
+        )),
+        (
+            "arrays" | "objects" | "strings" | "numbers" | "booleans" | "nulls" | "iterables"
+            | "scalars" | "values",
+            0,
+        ) => {
+            if selected(name, &input.1) {
+                one(Ok(input))
+            } else {
+                Box::new(empty())
+            }
+        }
+        ("recurse", 0) => recurse_paths(input),
+        ("recurse", 1) => recurse_paths_with(&args[0], env, input),
+        ("getpath", 1) => {
+            let (path, value) = input;
+            map_ok(args[0].eval(env, value.clone()), move |p| {
+                let p = as_path(&p)?;
+                let mut full = path.clone();
+                full.extend(p.iter().cloned());
+                Ok((full, path::getpath(&value, p)?))
+            })
+        }
+        _ => map_ok(call(name, args, cache, env, input.1), |v| {
+            Err(RuntimeError::Path(format!(
+                "Invalid path expression with result {}",
+                v
+            )))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jq_parser::parse_filter;
+    use crate::test_util::run;
+
+    /// runs each of `cases`, (input, filter, expected), where the input and
+    /// expected outputs are filters too
+    fn check(cases: &[(&str, &str, &str)]) {
+        for (input, filter, expected) in cases {
+            let filter = format!("{} | {}", input, filter);
+            assert_eq!(run(&filter), run(expected), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn it_follows_paths() {
+        let doc = r#"{"a": [1, {"b": 2}], "c": null}"#;
+        check(&[
+            ("null", "path(.a[0].b)", r#"["a", 0, "b"]"#),
+            ("null", "[path(..)]", "[[]]"),
+            (
+                doc,
+                "[path(..)]",
+                r#"[[], ["a"], ["a", 0], ["a", 1], ["a", 1, "b"], ["c"]]"#,
+            ),
+            (
+                doc,
+                "[paths]",
+                r#"[["a"], ["a", 0], ["a", 1], ["a", 1, "b"], ["c"]]"#,
+            ),
+            (doc, "[leaf_paths]", r#"[["a", 0], ["a", 1, "b"], ["c"]]"#),
+            (doc, "[paths(. == 2)]", r#"[["a", 1, "b"]]"#),
+            (doc, "path(.a[] | select(.b?))", r#"["a", 1]"#),
+            (doc, "path(.x // .a)", r#"["a"]"#),
+            (doc, r#"path(getpath(["a", 1]).b)"#, r#"["a", 1, "b"]"#),
+            ("[1, 2, 3]", "path(.[1:])", r#"[{"start": 1, "end": null}]"#),
+            (doc, r#"getpath(["a", 1, "b"])"#, "2"),
+            (doc, r#"getpath(["x", "y"])"#, "null"),
+            ("null", r#"setpath(["a", 1]; 5)"#, r#"{"a": [null, 5]}"#),
+            (
+                "[1, 2, 3]",
+                r#"setpath([{"start": 1}]; ["x"])"#,
+                r#"[1, "x"]"#,
+            ),
+            (
+                doc,
+                r#"delpaths([["a", 0], ["c"]])"#,
+                r#"{"a": [{"b": 2}]}"#,
+            ),
+            ("[1, 2, 3, 4]", "delpaths([[0], [-1], [1]])", "[3]"),
+            (
+                doc,
+                "pick(.a[1].b, .c)",
+                r#"{"a": [null, {"b": 2}], "c": null}"#,
+            ),
+            (doc, "del(.c, .a[0])", r#"{"a": [{"b": 2}]}"#),
+            (
+                doc,
+                "del(.a[] | select(. == 1))",
+                r#"{"a": [{"b": 2}], "c": null}"#,
+            ),
+            (doc, "del(.)", "null"),
+            ("[1, [2]]", "[recurse] | length", "4"),
+        ]);
+    }
+
+    #[test]
+    fn it_works_on_strings() {
+        check(&[
+            (r#""a,b,c""#, r#"split(",")"#, r#"["a", "b", "c"]"#),
+            (
+                r#""foobar""#,
+                r#"ltrimstr("foo"), rtrimstr("bar")"#,
+                r#""bar", "foo""#,
+            ),
+            ("1", r#"ltrimstr("foo")"#, "1"),
+            (
+                r#"" \t a b \n""#,
+                "trim, ltrim, rtrim",
+                r#""a b", "a b \n", " \t a b""#,
+            ),
+            (
+                r#""foobar""#,
+                r#"startswith("foo"), endswith("foo")"#,
+                "true, false",
+            ),
+            (
+                r#""aBc""#,
+                "ascii_downcase, ascii_upcase",
+                r#""abc", "ABC""#,
+            ),
+            (r#""aé😀""#, "explode", "[97, 233, 128512]"),
+            ("[97, 233, 128512]", "implode", r#""aé😀""#),
+            ("65", "ascii", r#""A""#),
+            (r#"["a", 1, null, true]"#, r#"join("-")"#, r#""a-1--true""#),
+            ("[]", r#"join("-")"#, r#""""#),
+            (r#""aé""#, "utf8bytelength", "3"),
+        ]);
+    }
+
+    #[test]
+    fn it_converts_to_and_from_json() {
+        check(&[
+            (
+                r#"[1, "1", [1]]"#,
+                "[.[] | tostring]",
+                r#"["1", "1", "[1]"]"#,
+            ),
+            (r#"{"a": [1, "x"]}"#, "tojson", r#""{\"a\":[1,\"x\"]}""#),
+            (
+                r#""{\"a\": [1, \"\\u00e9\"]}""#,
+                "fromjson",
+                r#"{"a": [1, "é"]}"#,
+            ),
+            (r#""12.5""#, "tonumber", "12.5"),
+        ]);
+    }
+
+    #[test]
+    fn it_searches() {
+        check(&[
+            (r#""foobar""#, r#"contains("bar")"#, "true"),
+            (
+                r#"{"a": [1, 2, "xyz"], "b": 1}"#,
+                r#"contains({"a": [2, "y"]})"#,
+                "true",
+            ),
+            (r#"["a"]"#, r#"inside(["a", "b"])"#, "true"),
+            (r#""a,b, cd, efg""#, r#"indices(", ")"#, "[3, 7]"),
+            ("[0, 1, 2, 1, 3, 1, 2]", "indices(1)", "[1, 3, 5]"),
+            ("[0, 1, 2, 1, 2]", "indices([1, 2])", "[1, 3]"),
+            (
+                r#""aaa""#,
+                r#"index("aa"), rindex("aa"), index("b")"#,
+                "0, 1, null",
+            ),
+        ]);
+    }
+
+    #[test]
+    fn it_matches_regexes() {
+        check(&[
+            (r#""a, b,c""#, r#"split(", *"; null)"#, r#"["a", "b", "c"]"#),
+            (r#""aXbxc""#, r#"[splits("x"; "i")]"#, r#"["a", "b", "c"]"#),
+            (
+                r#""abc""#,
+                r#"test("B"; "i"), test(["B", "i"]), test("B")"#,
+                "true, true, false",
+            ),
+            (r#""ab""#, r#"[test("a", "z")]"#, "[true, false]"),
+            (
+                r#""foo bar foo""#,
+                r#"[match("foo"; "g").offset]"#,
+                "[0, 8]",
+            ),
+            (
+                r#""xabc""#,
+                r#"match("(?<x>a)(b)?(z)?")"#,
+                concat!(
+                    r#"{"offset": 1, "length": 2, "string": "ab", "captures": ["#,
+                    r#"{"offset": 1, "length": 1, "string": "a", "name": "x"}, "#,
+                    r#"{"offset": 2, "length": 1, "string": "b", "name": null}, "#,
+                    r#"{"offset": -1, "length": 0, "string": null, "name": null}]}"#,
+                ),
+            ),
+            (r#""éa""#, r#"match("a") | [.offset, .length]"#, "[1, 1]"),
+            (r#""ab""#, r#"[match(""; "g").offset]"#, "[0, 1, 2]"),
+            (
+                r#""xyzzy-14""#,
+                r#"capture("(?<a>[a-z]+)-(?<n>[0-9]+)")"#,
+                r#"{"a": "xyzzy", "n": "14"}"#,
+            ),
+            (
+                r#""abcabc""#,
+                r#"[scan("c")], [scan("(a)(b)")]"#,
+                r#"["c", "c"], [["a", "b"], ["a", "b"]]"#,
+            ),
+            (
+                r#""abab""#,
+                r#"sub("a"; "X"), gsub("a"; "X"), sub("a"; "X"; "g")"#,
+                r#""Xbab", "XbXb", "XbXb""#,
+            ),
+            (r#""abc""#, r#"sub("(?<x>b)"; "[\(.x)]")"#, r#""a[b]c""#),
+            (r#""abc""#, r#"[sub("b"; "1", "2")]"#, r#"["a1c", "a2c"]"#),
+            (r#""ab""#, r#"gsub(""; "-")"#, r#""-a-b-""#),
+        ]);
+    }
+
+    #[test]
+    fn it_works_on_collections() {
+        check(&[
+            (
+                r#"{"b": 1, "a": 2}"#,
+                "keys, keys_unsorted",
+                r#"["a", "b"], ["a", "b"]"#,
+            ),
+            ("[4, 5]", "keys", "[0, 1]"),
+            (r#"{"a": 1}"#, r#"has("a"), has("b")"#, "true, false"),
+            ("[1, 2]", "has(1), has(2)", "true, false"),
+            (r#""a""#, r#"in({"a": 1})"#, "true"),
+            (
+                r#"{"a": 1, "b": 2}"#,
+                "to_entries",
+                r#"[{"key": "a", "value": 1}, {"key": "b", "value": 2}]"#,
+            ),
+            (
+                r#"[{"k": "a", "v": 1}, {"name": "b", "value": 2}, {"key": 1, "value": null}]"#,
+                "from_entries",
+                r#"{"a": 1, "b": 2, "1": null}"#,
+            ),
+            (
+                r#"[{"key": false, "name": "x", "value": 1}, {"key": false, "v": 2}]"#,
+                "from_entries",
+                r#"{"x": 1, "null": 2}"#,
+            ),
+            (
+                r#"{"a": 1, "b": 2}"#,
+                r#"with_entries(.value += 1), with_entries(select(.key == "a"))"#,
+                r#"{"a": 2, "b": 3}, {"a": 1}"#,
+            ),
+            ("[1, 2, 3]", "add", "6"),
+            (r#"["a", "b"]"#, "add", r#""ab""#),
+            ("[]", "add", "null"),
+            (r#"{"a": [1], "b": [2]}"#, "add(.[])", "[1, 2]"),
+            (
+                "[1, [2, [3, [4]]]]",
+                "flatten, flatten(1)",
+                "[1, 2, 3, 4], [1, 2, [3, [4]]]",
+            ),
+            ("[1, 2, 3]", "reverse", "[3, 2, 1]"),
+            (r#""abc""#, "reverse", r#""cba""#),
+            (r#"[3, "a", null, 1]"#, "min, max", r#"null, "a""#),
+            ("[]", "min", "null"),
+            ("[true, false]", "any, all", "true, false"),
+            ("[]", "any, all", "false, true"),
+            ("[1, 2]", "any(. > 1), all(. > 1)", "true, false"),
+            ("null", "any(1, error; . == 1)", "true"),
+        ]);
+    }
+
+    #[test]
+    fn it_streams() {
+        check(&[
+            (
+                r#"{"a": [1, {"b": 2}]}"#,
+                "[tostream]",
+                r#"[[["a", 0], 1], [["a", 1, "b"], 2], [["a", 1, "b"]], [["a", 1]], [["a"]]]"#,
+            ),
+            ("3", "[tostream]", "[[[], 3]]"),
+            ("[[], {}]", "[tostream]", "[[[0], []], [[1], {}], [[1]]]"),
+            (
+                r#"{"a": [1, {"b": 2}], "c": []}"#,
+                "fromstream(tostream)",
+                r#"{"a": [1, {"b": 2}], "c": []}"#,
+            ),
+            ("null", "[fromstream(1, 2 | [[], .])]", "[1, 2]"),
+            (
+                "1",
+                "[truncate_stream([[0], 1], [[1, 0], 2], [[1, 0]], [[1]])]",
+                "[[[0], 2], [[0]]]",
+            ),
+        ]);
+    }
+
+    #[test]
+    fn it_sorts() {
+        check(&[
+            (
+                r#"[3, "b", null, [1], {"a": 1}, true, false, 1, "a", {}]"#,
+                "sort",
+                r#"[null, false, true, 1, 3, "a", "b", [1], {}, {"a": 1}]"#,
+            ),
+            (
+                r#"[{"a": 2, "b": 1}, {"a": 1, "b": 2}, {"a": 1, "b": 1}]"#,
+                "sort_by(.a), sort_by(.a, .b)",
+                concat!(
+                    r#"[{"a": 1, "b": 2}, {"a": 1, "b": 1}, {"a": 2, "b": 1}], "#,
+                    r#"[{"a": 1, "b": 1}, {"a": 1, "b": 2}, {"a": 2, "b": 1}]"#,
+                ),
+            ),
+            (
+                r#"[{"a": 1, "b": 1}, {"a": 2}, {"a": 1, "b": 2}]"#,
+                "group_by(.a)",
+                r#"[[{"a": 1, "b": 1}, {"a": 1, "b": 2}], [{"a": 2}]]"#,
+            ),
+            ("[1, 2, 5, 3, 5, 3, 1, 3]", "unique", "[1, 2, 3, 5]"),
+            (
+                r#"["chunky", "bacon", "kitten", "cicada", "asparagus"]"#,
+                "unique_by(length)",
+                r#"["bacon", "chunky", "asparagus"]"#,
+            ),
+            (
+                r#"[{"a": 1, "n": 1}, {"a": 3}, {"a": 1, "n": 2}, {"a": 3, "n": 2}]"#,
+                "min_by(.a), max_by(.a)",
+                r#"{"a": 1, "n": 1}, {"a": 3, "n": 2}"#,
+            ),
+            ("[]", "min_by(.a), sort_by(.a)", "null, []"),
+        ]);
+    }
+
+    #[test]
+    fn it_works_with_dates() {
+        check(&[
+            ("1425599621", "todate", r#""2015-03-05T23:53:41Z""#),
+            (r#""2015-03-05T23:51:47Z""#, "fromdate", "1425599507"),
+            (
+                r#""2015-03-05T23:51:47Z""#,
+                r#"strptime("%Y-%m-%dT%H:%M:%SZ")"#,
+                "[2015, 2, 5, 23, 51, 47, 4, 63]",
+            ),
+            (
+                "1425599621.25",
+                "gmtime",
+                "[2015, 2, 5, 23, 53, 41.25, 4, 63]",
+            ),
+            ("1425599621", "gmtime | mktime", "1425599621"),
+            (
+                "[2015, 2, 5, 23, 51, 47, 4, 63]",
+                "mktime, todate",
+                r#"1425599507, "2015-03-05T23:51:47Z""#,
+            ),
+            (
+                "1425599621",
+                r#"strftime("%A, %B %d, %Y"), (gmtime | strftime("%j %H"))"#,
+                r#""Thursday, March 05, 2015", "064 23""#,
+            ),
+            (
+                "1425599507",
+                r#"dateadd("seconds"; 13) | todate"#,
+                r#""2015-03-05T23:52:00Z""#,
+            ),
+            ("10", r#"datesub("seconds"; 3)"#, "7"),
+        ]);
+    }
+
+    #[test]
+    fn it_selects_by_type() {
+        check(&[
+            (
+                r#"[null, true, 1, "a", [], {}]"#,
+                "[.[] | type]",
+                r#"["null", "boolean", "number", "string", "array", "object"]"#,
+            ),
+            (
+                r#"[null, true, 1, "a", [2], {"b": 3}]"#,
+                "[.[] | arrays], [.[] | objects], [.[] | strings], [.[] | numbers]",
+                r#"[[2]], [{"b": 3}], ["a"], [1]"#,
+            ),
+            (
+                r#"[null, true, 1, "a", [2], {"b": 3}]"#,
+                "[.[] | booleans], [.[] | nulls], [.[] | iterables], [.[] | scalars]",
+                r#"[true], [null], [[2], {"b": 3}], [null, true, 1, "a"]"#,
+            ),
+            ("[null, false, 0]", "[.[] | values]", "[false, 0]"),
+            (
+                r#"{"a": [1, "x", {"b": 2}]}"#,
+                "[.. | numbers], [paths(numbers)]",
+                r#"[1, 2], [["a", 0], ["a", 2, "b"]]"#,
+            ),
+            (
+                r#"{"a": [1, "x"]}"#,
+                r#"[path(.. | strings)], [.. | select(type == "array")]"#,
+                r#"[["a", 1]], [[1, "x"]]"#,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn it_reads_the_environment() {
+        check(&[(
+            "null",
+            r#"env | type, ($ENV | type), ($ENV == env)"#,
+            r#""object", "object", true"#,
+        )]);
+    }
+
+    #[test]
+    fn it_lists_every_builtin() {
+        for (name, arity) in BUILTINS {
+            let args = vec!["."; *arity].join("; ");
+            let filter = match arity {
+                0 => name.to_string(),
+                _ => format!("{}({})", name, args),
+            };
+            let filter = parse_filter(&filter).expect("filter parses");
+            let first = filter.apply(JsonValue::Null).next();
+            assert!(
+                !matches!(first, Some(Err(RuntimeError::Undefined(_)))),
+                "{}/{} is listed but not defined",
+                name,
+                arity
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_paths() {
+        let cases = [
+            ("path(1)", "Invalid path expression with result 1"),
+            ("path(.a | length)", "Invalid path expression with result 0"),
+            (
+                "[] | setpath([-1]; 1)",
+                "Out of bounds negative array index",
+            ),
+            ("getpath(1)", "Path must be specified as an array"),
+            ("1 | trim", "trim input must be a string"),
+            (
+                r#"1 | startswith("a")"#,
+                "startswith() requires string inputs",
+            ),
+            ("{} | implode", "implode input must be an array"),
+            (r#""x" | fromjson"#, "Invalid JSON text (while parsing 'x')"),
+            (r#""x" | tonumber"#, "Cannot parse 'x' as JSON"),
+            (
+                r#"1 | contains("a")"#,
+                r#"number (1) and string ("a") cannot have their containment checked"#,
+            ),
+            (
+                "[[1]] | join(\",\")",
+                r#"string ("") and array ([1]) cannot be added"#,
+            ),
+            (
+                r#""a" | split("("; null)"#,
+                "( (at offset 0) is not a valid regex: end pattern with unmatched parenthesis",
+            ),
+            (
+                r#"1 | test("a")"#,
+                "number (1) cannot be matched, as it is not a string",
+            ),
+            (
+                r#""a" | test("a"; "q")"#,
+                "q is not a valid modifier string",
+            ),
+            (r#""a" | test(1)"#, "number not a string or array"),
+            (
+                r#""a" | sub("a"; 1)"#,
+                r#"string ("") and number (1) cannot be added"#,
+            ),
+            (
+                "true | utf8bytelength",
+                "boolean (true) only strings have UTF-8 byte length",
+            ),
+            (
+                r#""yesterday" | fromdate"#,
+                r#"date "yesterday" does not match format "%Y-%m-%dT%H:%M:%SZ""#,
+            ),
+            ("[1, 2] | mktime", "mktime requires array of 6 numbers"),
+            (r#""a" | gmtime"#, "gmtime() requires a number"),
+            ("[[1]] | flatten(-1)", "flatten depth must not be negative"),
+            (r#"[[1]] | flatten("a")"#, "flatten depth must be a number"),
+            (
+                r#""a" | truncate_stream([[0], 1])"#,
+                "truncate_stream depth must be a number",
+            ),
+        ];
+
+        for (filter, expected) in cases {
+            let err = run(filter).expect_err(filter);
+            assert_eq!(err.to_string(), expected, "{}", filter);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! jq's date builtins. like jq these go through the C library's time functions,
+//! so local time follows the TZ environment variable and the system's zoneinfo.
+//! broken down times are jq's arrays of
+//! `[year, month (0-11), day of month, hours, minutes, seconds, day of week, day of year]`
+
+use std::ffi::{CStr, CString};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::interpreter::RuntimeError;
+use crate::json_parser::JsonValue;
+
+// the libc crate binds strftime and strptime, but not tzset
+mod c {
+    extern "C" {
+        pub fn tzset();
+    }
+}
+
+pub(crate) fn now() -> JsonValue {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    JsonValue::Num(since_epoch.as_secs_f64())
+}
+
+fn empty_tm() -> libc::tm {
+    // an all-zero tm is valid: every field is an integer or a nullable pointer
+    unsafe { std::mem::zeroed() }
+}
+
+fn broken_down(tm: &libc::tm, fractional_secs: f64) -> JsonValue {
+    JsonValue::from(vec![
+        JsonValue::Num(tm.tm_year as f64 + 1900.0),
+        JsonValue::Num(tm.tm_mon as f64),
+        JsonValue::Num(tm.tm_mday as f64),
+        JsonValue::Num(tm.tm_hour as f64),
+        JsonValue::Num(tm.tm_min as f64),
+        JsonValue::Num(tm.tm_sec as f64 + fractional_secs),
+        JsonValue::Num(tm.tm_wday as f64),
+        JsonValue::Num(tm.tm_yday as f64),
+    ])
+}
+
+/// the `tm` a broken down time describes. `what` names the builtin for errors
+fn to_tm(v: &JsonValue, what: &str) -> Result<libc::tm, RuntimeError> {
+    let fields = match v {
+        JsonValue::Array(a) if a.len() >= 6 => a,
+        _ => {
+            return Err(RuntimeError::Type(format!(
+                "{} requires array of 6 numbers",
+                what
+            )))
+        }
+    };
+    let nums = fields
+        .iter()
+        .map(|f| f.as_num().map(|n| *n as libc::c_int))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| RuntimeError::Type(format!("{} requires parsed datetime inputs", what)))?;
+
+    let mut tm = empty_tm();
+    tm.tm_year = nums[0] - 1900;
+    tm.tm_mon = nums[1];
+    tm.tm_mday = nums[2];
+    tm.tm_hour = nums[3];
+    tm.tm_min = nums[4];
+    tm.tm_sec = nums[5];
+    tm.tm_wday = nums.get(6).copied().unwrap_or(0);
+    tm.tm_yday = nums.get(7).copied().unwrap_or(0);
+    Ok(tm)
+}
+
+fn seconds(v: &JsonValue, what: &str) -> Result<f64, RuntimeError> {
+    v.as_num()
+        .copied()
+        .ok_or_else(|| RuntimeError::Type(format!("{} requires a number", what)))
+}
+
+/// `gmtime` and `localtime`
+pub(crate) fn split_time(v: &JsonValue, local: bool) -> Result<JsonValue, RuntimeError> {
+    let what = if local { "localtime()" } else { "gmtime()" };
+    let secs = seconds(v, what)?;
+    let t = secs.floor() as libc::time_t;
+    let mut tm = empty_tm();
+    let converted = unsafe {
+        if local {
+            c::tzset();
+            libc::localtime_r(&t, &mut tm)
+        } else {
+            libc::gmtime_r(&t, &mut tm)
+        }
+    };
+    if converted.is_null() {
+        return Err(RuntimeError::Type(format!(
+            "error converting number of seconds since epoch to datetime: {}",
+            secs
+        )));
+    }
+    Ok(broken_down(&tm, secs - secs.floor()))
+}
+
+/// `mktime`: a broken down UTC time to seconds since the epoch
+pub(crate) fn mktime(v: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    let mut tm = to_tm(v, "mktime")?;
+    let t = unsafe { libc::timegm(&mut tm) };
+    Ok(JsonValue::Num(t as f64))
+}
+
+/// `strftime` and `strflocaltime`. numbers are converted to a broken down time
+/// first, in UTC or local time respectively
+pub(crate) fn strftime(
+    v: &JsonValue,
+    format: &JsonValue,
+    local: bool,
+) -> Result<JsonValue, RuntimeError> {
+    let what = if local {
+        "strflocaltime/1"
+    } else {
+        "strftime/1"
+    };
+    let format = match format {
+        JsonValue::Str(f) => f,
+        _ => {
+            return Err(RuntimeError::Type(format!(
+                "{} requires a string format",
+                what
+            )))
+        }
+    };
+    let v = match v {
+        JsonValue::Num(_) => split_time(v, local)?,
+        v => v.clone(),
+    };
+    let mut tm = to_tm(&v, what)
+        .map_err(|_| RuntimeError::Type(format!("{} requires parsed datetime inputs", what)))?;
+    if local {
+        // fills in the time zone and daylight saving time for %Z and %z
+        unsafe {
+            c::tzset();
+            libc::mktime(&mut tm);
+        }
+    }
+
+    let c_format = CString::new(format.as_str())
+        .map_err(|_| RuntimeError::Type(format!("{} requires a string format", what)))?;
+    // strftime gives no way to tell a full buffer from an empty result, so grow
+    // the buffer until the output clearly fits
+    let mut buf = vec![0u8; 128 + format.len() * 4];
+    loop {
+        let len = unsafe {
+            libc::strftime(
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+                c_format.as_ptr(),
+                &tm,
+            )
+        };
+        if len > 0 || buf.len() > 64 * 1024 {
+            buf.truncate(len);
+            return Ok(JsonValue::Str(String::from_utf8_lossy(&buf).into_owned()));
+        }
+        buf.resize(buf.len() * 4, 0);
+    }
+}
+
+/// `strptime`: parses a date into a broken down time
+pub(crate) fn strptime(v: &JsonValue, format: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    let (s, f) = match (v, format) {
+        (JsonValue::Str(s), JsonValue::Str(f)) => (s, f),
+        _ => {
+            return Err(RuntimeError::Type(
+                "strptime/1 requires string inputs and arguments".to_string(),
+            ))
+        }
+    };
+    let no_match = || RuntimeError::Type(format!("date \"{}\" does not match format \"{}\"", s, f));
+    let c_s = CString::new(s.as_str()).map_err(|_| no_match())?;
+    let c_f = CString::new(f.as_str()).map_err(|_| no_match())?;
+
+    let mut tm = empty_tm();
+    let rest = unsafe { libc::strptime(c_s.as_ptr(), c_f.as_ptr(), &mut tm) };
+    if rest.is_null() {
+        return Err(no_match());
+    }
+    let rest = unsafe { CStr::from_ptr(rest) }.to_bytes();
+    if !rest.iter().all(u8::is_ascii_whitespace) {
+        return Err(no_match());
+    }
+
+    // strptime doesn't always work out the day of the week and year, so take
+    // them from a round trip through timegm
+    let mut normalized = tm;
+    let t = unsafe { libc::timegm(&mut normalized) };
+    let mut full = empty_tm();
+    if !unsafe { libc::gmtime_r(&t, &mut full) }.is_null() {
+        tm.tm_wday = full.tm_wday;
+        tm.tm_yday = full.tm_yday;
+    }
+    Ok(broken_down(&tm, 0.0))
+}
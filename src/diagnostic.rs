@@ -0,0 +1,311 @@
+//! compiler-style errors for filters that don't compile: where it went wrong,
+//! the line with a caret under it, what was expected, and for unknown functions
+//! the builtins that were probably meant
+
+use std::fmt::Write;
+
+use nom::error::{VerboseError, VerboseErrorKind};
+
+use crate::builtins::BUILTINS;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    message: String,
+    /// from 1
+    line: usize,
+    /// from 1, in characters
+    column: usize,
+    /// how many characters to underline
+    width: usize,
+    source_line: String,
+    /// extra hints shown under the source line
+    notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// an error about the text at byte `offset` of `source`
+    fn at(source: &str, offset: usize, width: usize, message: String) -> Self {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+        let line_end = source[offset..]
+            .find('\n')
+            .map_or(source.len(), |n| offset + n);
+        Self {
+            message,
+            line: before.matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            width: width.max(1),
+            source_line: source[line_start..line_end].to_string(),
+            notes: vec![],
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// the error, with `file` naming where the filter came from
+    pub fn render(&self, file: &str) -> String {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let mut out = format!(
+            "{}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+            self.message,
+            gutter,
+            file,
+            self.line,
+            self.column,
+            gutter,
+            number,
+            self.source_line,
+            gutter,
+            " ".repeat(self.column - 1),
+            "^".repeat(self.width),
+        );
+        for note in &self.notes {
+            let _ = write!(out, "\n{} = {}", gutter, note);
+        }
+        out
+    }
+}
+
+/// a filter that doesn't parse. nom's innermost error says where, and the
+/// contexts around it what was being parsed
+pub(crate) fn syntax_error(source: &str, err: &VerboseError<&str>) -> Diagnostic {
+    let (rest, kind) = match err.errors.first() {
+        Some((rest, kind)) => (*rest, kind),
+        None => (source, &VerboseErrorKind::Context("filter")),
+    };
+    // a context that starts where the error is was only being tried, so the
+    // innermost one that started earlier is what the error is inside of
+    let context = err.errors.iter().find_map(|(at, kind)| match kind {
+        VerboseErrorKind::Context(c) if at.len() > rest.len() => describe(c),
+        _ => None,
+    });
+    let in_variable = err
+        .errors
+        .iter()
+        .any(|(_, kind)| matches!(kind, VerboseErrorKind::Context("variable")));
+
+    let at_end = rest.trim().is_empty();
+    // point just past the last of the filter, rather than at trailing whitespace
+    let offset = if at_end {
+        source.trim_end().len()
+    } else {
+        source.len() - rest.len()
+    };
+    let token = token(rest);
+
+    let (message, width) = match kind {
+        VerboseErrorKind::Char('"') if rest.starts_with('\\') => {
+            let escape: String = rest.chars().take(2).collect();
+            (format!("invalid escape `{}`", escape), 2)
+        }
+        VerboseErrorKind::Char(c) => (format!("expected `{}`", c), 1),
+        _ if in_variable => {
+            return Diagnostic::at(
+                source,
+                offset,
+                token.chars().count(),
+                "expected a variable name, like `$x`".to_string(),
+            )
+        }
+        _ if at_end => ("unexpected end of filter".to_string(), 1),
+        _ => (format!("unexpected `{}`", token), token.chars().count()),
+    };
+    let message = match context {
+        Some(context) => format!("{} in {}", message, context),
+        None => message,
+    };
+    Diagnostic::at(source, offset, width, message)
+}
+
+/// what a parser context is, for the message
+fn describe(context: &str) -> Option<&'static str> {
+    Some(match context {
+        "array" => "an array",
+        "object" => "an object",
+        "string" => "a string",
+        "format" => "a format",
+        "function_call" => "a function call",
+        "variable" => "a variable",
+        "if" => "an if",
+        "try" => "a try",
+        "fold" => "a reduce or foreach",
+        "directive" => "an import or include",
+        _ => return None,
+    })
+}
+
+/// the word or symbol at the start of `rest`
+fn token(rest: &str) -> &str {
+    let word = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    match word {
+        0 => rest.chars().next().map_or("", |c| &rest[..c.len_utf8()]),
+        n => &rest[..n],
+    }
+}
+
+/// a call to a function that isn't defined, with the builtins it might have meant
+pub(crate) fn undefined_function(source: &str, name: &str, arity: usize) -> Diagnostic {
+    let offset = call_site(source, name, arity).unwrap_or(0);
+    let mut d = Diagnostic::at(
+        source,
+        offset,
+        name.chars().count(),
+        format!("{}/{} is not defined", name, arity),
+    );
+
+    let arities: Vec<_> = BUILTINS
+        .iter()
+        .filter(|(n, _)| *n == name)
+        .map(|(_, a)| a.to_string())
+        .collect();
+    if !arities.is_empty() {
+        let plural = if arities == ["1"] { "" } else { "s" };
+        d.notes.push(format!(
+            "note: `{}` takes {} argument{}",
+            name,
+            arities.join(" or "),
+            plural
+        ));
+        return d;
+    }
+
+    let mut near: Vec<_> = BUILTINS
+        .iter()
+        .map(|(n, _)| *n)
+        .filter(|n| n.len() > 1)
+        .map(|n| (distance(name, n), n))
+        .filter(|(d, _)| *d <= (name.len() / 3).max(1))
+        .collect();
+    near.sort();
+    near.dedup();
+    if !near.is_empty() {
+        let names: Vec<_> = near
+            .iter()
+            .take(3)
+            .map(|(_, n)| format!("`{}`", n))
+            .collect();
+        d.notes
+            .push(format!("help: did you mean {}?", names.join(" or ")));
+    }
+    d
+}
+
+/// a format that doesn't exist, like `@foo`
+pub(crate) fn invalid_format(source: &str, name: &str) -> Diagnostic {
+    let at = format!("@{}", name);
+    let offset = source
+        .match_indices(&at)
+        .map(|(i, _)| i)
+        .find(|&i| !source[i + at.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+        .unwrap_or(0);
+    Diagnostic::at(
+        source,
+        offset,
+        at.chars().count(),
+        format!("{} is not a valid format", name),
+    )
+}
+
+/// where `name` is called in `source`, going by the text: a whole word, not a
+/// field, variable or format, with arguments if it has any
+fn call_site(source: &str, name: &str, arity: usize) -> Option<usize> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    source.match_indices(name).map(|(i, _)| i).find(|&i| {
+        let before = source[..i].chars().next_back();
+        let after = source[i + name.len()..].trim_start();
+        !before.is_some_and(|c| is_word(c) || "$.@".contains(c))
+            && !after.starts_with(is_word)
+            && after.starts_with('(') == (arity > 0)
+    })
+}
+
+/// the edit distance between `a` and `b`
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev + usize::from(ca != *cb);
+            prev = row[j + 1];
+            row[j + 1] = substitute.min(prev + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jq_parser::compile;
+
+    fn error(filter: &str) -> String {
+        compile(filter)
+            .expect_err("the filter is bad")
+            .render("<filter>")
+    }
+
+    #[test]
+    fn it_works() {
+        assert_eq!(
+            error(".a | [1, 2"),
+            "\
+expected `]` in an array
+ --> <filter>:1:11
+  |
+1 | .a | [1, 2
+  |           ^"
+        );
+        assert_eq!(
+            error(".a\n| .b ]\n| .c"),
+            "\
+unexpected `]`
+ --> <filter>:2:6
+  |
+2 | | .b ]
+  |      ^"
+        );
+        assert_eq!(
+            error(".a | lenght"),
+            "\
+lenght/0 is not defined
+ --> <filter>:1:6
+  |
+1 | .a | lenght
+  |      ^^^^^^
+  = help: did you mean `length`?"
+        );
+
+        let cases = [
+            ("1 +", "unexpected end of filter"),
+            ("map(.", "expected `)` in a function call"),
+            ("\"a\\qb\"", "invalid escape `\\q` in a string"),
+            (
+                "reduce .[] as x (0; .)",
+                "expected a variable name, like `$x`",
+            ),
+            ("{(.a) 1}", "expected `:` in an object"),
+            ("split", "split/0 is not defined"),
+            ("def f: g; f", "g/0 is not defined"),
+            ("def f(g): g; g", "g/0 is not defined"),
+            ("def f($x): x; f(1) | x", "x/0 is not defined"),
+            ("def f: 1; f(2)", "f/1 is not defined"),
+            ("@foo", "foo is not a valid format"),
+            (r#"@foo "\(1)""#, "foo is not a valid format"),
+            (r#"@foo "x""#, "foo is not a valid format"),
+            (r#"@base64 "\(.)" | @bar"#, "bar is not a valid format"),
+        ];
+        for (filter, message) in cases {
+            let d = compile(filter).expect_err("the filter is bad");
+            assert_eq!(d.message(), message, "{}", filter);
+        }
+        assert!(error("@csv, @cs").ends_with("1 | @csv, @cs\n  |       ^^^"));
+        assert!(error("split").ends_with("= note: `split` takes 1 or 2 arguments"));
+        assert!(error(".a | tostrng | ascii_upcase").contains("1 | .a | tostrng"));
+    }
+}
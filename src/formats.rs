@@ -0,0 +1,215 @@
+//! the `@name` format strings: `@csv`, `@base64` and friends. each one turns a
+//! value into a string, either on its own (`@csv`) or applied to every value
+//! interpolated into a string (`@csv "row: \(.)"`)
+
+use crate::interpreter::RuntimeError;
+use crate::json_parser::{format_number, JsonValue};
+
+/// every format there is, without the `@`
+pub(crate) const FORMATS: &[&str] = &[
+    "text", "json", "html", "uri", "csv", "tsv", "sh", "base64", "base64d",
+];
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// what `tostring` gives: strings as they are, anything else as JSON
+pub(crate) fn to_text(v: &JsonValue) -> String {
+    match v {
+        JsonValue::Str(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn row<'a>(v: &'a JsonValue, format: &str) -> Result<&'a Vec<JsonValue>, RuntimeError> {
+    v.as_array().ok_or_else(|| {
+        RuntimeError::invalid(
+            v,
+            &format!("cannot be {}-formatted, only an array can be", format),
+        )
+    })
+}
+
+fn csv(v: &JsonValue) -> Result<String, RuntimeError> {
+    let fields = row(v, "csv")?
+        .iter()
+        .map(|e| match e {
+            JsonValue::Null => Ok(String::new()),
+            JsonValue::Boolean(b) => Ok(b.to_string()),
+            JsonValue::Num(n) => Ok(format_number(*n)),
+            JsonValue::Str(s) => Ok(format!("\"{}\"", s.replace('"', "\"\""))),
+            e => Err(RuntimeError::invalid(e, "is not valid in a csv row")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(fields.join(","))
+}
+
+fn tsv(v: &JsonValue) -> Result<String, RuntimeError> {
+    let fields = row(v, "tsv")?
+        .iter()
+        .map(|e| match e {
+            JsonValue::Null => Ok(String::new()),
+            JsonValue::Boolean(b) => Ok(b.to_string()),
+            JsonValue::Num(n) => Ok(format_number(*n)),
+            JsonValue::Str(s) => Ok(s
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")),
+            e => Err(RuntimeError::invalid(e, "is not valid in a tsv row")),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(fields.join("\t"))
+}
+
+fn sh(v: &JsonValue) -> Result<String, RuntimeError> {
+    let quote = |e: &JsonValue| match e {
+        JsonValue::Str(s) => Ok(format!("'{}'", s.replace('\'', "'\\''"))),
+        JsonValue::Array(_) | JsonValue::Object(_) => {
+            Err(RuntimeError::invalid(e, "can not be escaped for shell"))
+        }
+        e => Ok(e.to_string()),
+    };
+    match v {
+        JsonValue::Array(a) => Ok(a
+            .iter()
+            .map(quote)
+            .collect::<Result<Vec<_>, _>>()?
+            .join(" ")),
+        v => quote(v),
+    }
+}
+
+fn html(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '&' => "&amp;".to_string(),
+            '\'' => "&#39;".to_string(),
+            '"' => "&quot;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+fn uri(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// decodes standard base64. like jq, trailing padding is optional
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let digits = s
+        .trim_end_matches('=')
+        .bytes()
+        .map(|c| {
+            BASE64_ALPHABET
+                .iter()
+                .position(|a| *a == c)
+                .map(|p| p as u32)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if digits.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(digits.len() * 3 / 4);
+    for chunk in digits.chunks(4) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, d)| n | d << (18 - 6 * i));
+        out.extend(n.to_be_bytes()[1..chunk.len()].iter());
+    }
+    Some(out)
+}
+
+/// formats a single value with the format called `name` (without its `@`)
+pub(crate) fn apply(name: &str, v: &JsonValue) -> Result<String, RuntimeError> {
+    match name {
+        "text" => Ok(to_text(v)),
+        "json" => Ok(v.to_string()),
+        "html" => Ok(html(&to_text(v))),
+        "uri" => Ok(uri(&to_text(v))),
+        "csv" => csv(v),
+        "tsv" => tsv(v),
+        "sh" => sh(v),
+        "base64" => Ok(base64_encode(to_text(v).as_bytes())),
+        "base64d" => {
+            let text = to_text(v);
+            base64_decode(&text)
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .ok_or_else(|| RuntimeError::invalid(v, "is not valid base64 data"))
+        }
+        _ => Err(RuntimeError::Undefined(format!(
+            "{} is not a valid format",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::run;
+
+    #[test]
+    fn it_works() {
+        let cases = [
+            (r#"[1, "a", null] | @text"#, r#""[1,\"a\",null]""#),
+            (r#""x" | @json"#, r#""\"x\"""#),
+            (
+                r#""<a href='x'>&</a>" | @html"#,
+                r#""&lt;a href=&#39;x&#39;&gt;&amp;&lt;/a&gt;""#,
+            ),
+            (r#""a b/ü" | @uri"#, r#""a%20b%2F%C3%BC""#),
+            (
+                r#"[1, "a\"b", null, true] | @csv"#,
+                r#""1,\"a\"\"b\",,true""#,
+            ),
+            (r#"["a\tb", 2] | @tsv"#, r#""a\\tb\t2""#),
+            (r#""it's" | @sh"#, r#""'it'\\''s'""#),
+            (r#"["a b", 1] | @sh"#, r#""'a b' 1""#),
+            (r#""hello world" | @base64"#, r#""aGVsbG8gd29ybGQ=""#),
+            (r#""aGVsbG8gd29ybGQ=" | @base64d"#, r#""hello world""#),
+            (r#""aGk" | @base64d"#, r#""hi""#),
+            (
+                r#"{"name": "bob", "ip": 1} | "user \(.name) from \(.ip)""#,
+                r#""user bob from 1""#,
+            ),
+            (r#""\(1, 2)-\(3, 4)""#, r#""1-3", "2-3", "1-4", "2-4""#),
+            (r#"[1, "x"] | @csv "row: \(.)""#, r#""row: 1,\"x\"""#),
+            (r#""<b>" | @html "safe: \(.)""#, r#""safe: &lt;b&gt;""#),
+            (r#"{"k": "a"} | {"\(.k)1": 2}"#, r#"{"a1": 2}"#),
+        ];
+
+        for (filter, expected) in cases {
+            assert_eq!(run(filter), run(expected), "{}", filter);
+        }
+    }
+}
@@ -0,0 +1,504 @@
+//! the stream of input values. the main loop and the `input` builtins share it,
+//! so a filter can pull in records the main loop then never sees. several
+//! files are read one after the other as a single stream
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use anyhow::{anyhow, Result};
+
+use crate::json_parser::{JsonParseError, JsonValue, JsonValueRef};
+use crate::mapped::Mapped;
+use crate::projection::Projection;
+use crate::streamer::{Streamer, DEFAULT_CAPACITY};
+
+/// what to do about input that isn't valid JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// report it and stop reading that source
+    Fail,
+    /// report it on stderr, then carry on from the next line
+    Warn,
+    /// carry on from the next line without a word
+    Skip,
+}
+
+impl std::str::FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OnError::Fail),
+            "warn" => Ok(OnError::Warn),
+            "skip" => Ok(OnError::Skip),
+            _ => Err(format!("expected fail, warn or skip, not {}", s)),
+        }
+    }
+}
+
+/// somewhere to read input from, opened when the inputs before it run out
+enum Source {
+    Reader(Box<dyn Read>, Option<String>),
+    /// a file name, or `-` for stdin
+    Path(String),
+}
+
+enum Reader {
+    /// a sequence of JSON values
+    Json(Streamer<Box<dyn Read>>),
+    /// a sequence of JSON values in a regular file, mapped into memory
+    Mapped(Mapped),
+    /// `-R`: each line of text is a string
+    Raw {
+        lines: BufReader<Box<dyn Read>>,
+        count: usize,
+    },
+}
+
+impl Reader {
+    fn new(reader: Box<dyn Read>, raw: bool, projection: &Projection, capacity: usize) -> Reader {
+        if raw {
+            return Reader::Raw {
+                lines: BufReader::new(reader),
+                count: 0,
+            };
+        }
+        Reader::Json(Streamer::with_capacity(reader, capacity).project(projection.clone()))
+    }
+
+    fn line_number(&self) -> usize {
+        match self {
+            Reader::Json(streamer) => streamer.line_number(),
+            Reader::Mapped(mapped) => mapped.line_number(),
+            Reader::Raw { count, .. } => *count,
+        }
+    }
+
+    /// after an error, moves on to the next line, returning the bad one and its number
+    fn skip_line(&mut self) -> Result<(usize, Vec<u8>)> {
+        match self {
+            Reader::Json(streamer) => streamer.skip_line(),
+            Reader::Mapped(mapped) => Ok(mapped.skip_line()),
+            Reader::Raw { count, .. } => Ok((*count, vec![])),
+        }
+    }
+
+    /// passes the next value to `f` without copying it out of the input first
+    fn next_with<T>(&mut self, f: impl FnOnce(JsonValueRef<'_>) -> T) -> Option<Result<T>> {
+        match self {
+            Reader::Json(streamer) => streamer.next_with(f),
+            Reader::Mapped(mapped) => mapped.next_with(f),
+            Reader::Raw { lines, count } => {
+                read_line(lines, count).map(|line| line.map(|s| f(JsonValueRef::Str(s.into()))))
+            }
+        }
+    }
+}
+
+impl Iterator for Reader {
+    type Item = Result<JsonValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Reader::Json(streamer) => streamer.next(),
+            Reader::Mapped(mapped) => mapped.next(),
+            Reader::Raw { lines, count } => {
+                read_line(lines, count).map(|line| line.map(JsonValue::Str))
+            }
+        }
+    }
+}
+
+/// the next line without its newline, counting it in `count`
+fn read_line(lines: &mut BufReader<Box<dyn Read>>, count: &mut usize) -> Option<Result<String>> {
+    let mut line = String::new();
+    match lines.read_line(&mut line) {
+        Ok(0) => None,
+        Ok(_) => {
+            if line.ends_with('\n') {
+                line.pop();
+                *count += 1;
+            }
+            Some(Ok(line))
+        }
+        Err(e) => Some(Err(e.into())),
+    }
+}
+
+pub struct Inputs {
+    sources: VecDeque<Source>,
+    /// `-R`: read lines of text rather than JSON
+    raw: bool,
+    /// what is being read now, if anything
+    reader: Option<Reader>,
+    /// the file being read, or `None` for stdin
+    filename: Option<String>,
+    /// `-s`: everything is read up front into a single value
+    slurp: bool,
+    /// with `-s`, whatever is left to hand out: any errors, then the value
+    slurped: Option<VecDeque<Result<JsonValue>>>,
+    on_error: OnError,
+    /// where lines that weren't valid JSON go, when they're skipped
+    rejects: Option<Box<dyn Write>>,
+    /// the parts of each JSON value to build
+    projection: Projection,
+    /// whether JSON in regular files is mapped into memory rather than read
+    map_files: bool,
+    /// how much JSON is read at a time
+    capacity: usize,
+}
+
+impl Inputs {
+    /// reads `reader` as JSON values
+    pub fn new(reader: Box<dyn Read>, filename: Option<String>) -> Self {
+        Self::with_sources(vec![Source::Reader(reader, filename)], false)
+    }
+
+    /// reads `reader` as lines of text
+    pub fn raw(reader: Box<dyn Read>, filename: Option<String>) -> Self {
+        Self::with_sources(vec![Source::Reader(reader, filename)], true)
+    }
+
+    /// reads each of `paths` in turn, where `-` is stdin. with `raw`, as lines
+    /// of text rather than JSON
+    pub fn files(paths: &[String], raw: bool) -> Self {
+        let sources = paths.iter().cloned().map(Source::Path).collect();
+        Self::with_sources(sources, raw)
+    }
+
+    fn with_sources(sources: Vec<Source>, raw: bool) -> Self {
+        Self {
+            sources: sources.into(),
+            raw,
+            reader: None,
+            filename: None,
+            slurp: false,
+            slurped: None,
+            on_error: OnError::Fail,
+            rejects: None,
+            projection: Projection::All,
+            map_files: false,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+
+    /// reads everything into one value: an array of the JSON values, or the
+    /// whole text as a single string
+    pub fn slurp(mut self) -> Self {
+        self.slurp = true;
+        self
+    }
+
+    /// what to do about input that isn't valid JSON. by default a source stops
+    /// at its first error
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// writes each line skipped for not being valid JSON to `rejects`
+    pub fn reject_to(mut self, rejects: Box<dyn Write>) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+
+    /// only builds the parts of each JSON value in `projection`. the rest is
+    /// skipped over as it's parsed, and left out of the values
+    pub fn project(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    /// reads JSON `capacity` bytes at a time. a value bigger than that still
+    /// gets read whole, with the buffer growing for it while it's parsed
+    pub fn buffer_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// maps JSON in regular files into memory and parses each whole, rather
+    /// than reading it through a buffer
+    ///
+    /// # Safety
+    ///
+    /// none of the files may change while they're read, as with [`Mapped::new`]
+    pub unsafe fn map_files(mut self) -> Self {
+        self.map_files = true;
+        self
+    }
+
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// the line reached in the current file
+    pub fn line_number(&self) -> usize {
+        self.reader.as_ref().map_or(0, Reader::line_number)
+    }
+
+    fn open(&mut self, source: Source) -> Result<Box<dyn Read>> {
+        let (reader, filename): (Box<dyn Read>, _) = match source {
+            Source::Reader(reader, filename) => (reader, filename),
+            Source::Path(p) if p == "-" => (Box::new(std::io::stdin()), None),
+            Source::Path(p) => match std::fs::File::open(&p) {
+                Ok(file) => (Box::new(file), Some(p)),
+                Err(e) => return Err(anyhow!("Could not open {}: {}", p, e)),
+            },
+        };
+        self.filename = filename;
+        Ok(reader)
+    }
+
+    /// opens `source` to read values from. with `map_files`, JSON in a regular
+    /// file is mapped into memory and parsed all at once; anything else is
+    /// streamed
+    fn open_reader(&mut self, source: Source) -> Result<Reader> {
+        match source {
+            Source::Path(p) if self.map_files && !self.raw && is_mappable(&p) => {
+                let file =
+                    std::fs::File::open(&p).map_err(|e| anyhow!("Could not open {}: {}", p, e))?;
+                // SAFETY: map_files was only called by someone who promised
+                // that the files won't change while they're read
+                let reader = match unsafe { Mapped::new(&file) } {
+                    Ok(mapped) => Reader::Mapped(mapped.project(self.projection.clone())),
+                    // not every filesystem can map files, so read it instead
+                    Err(_) => Reader::new(Box::new(file), false, &self.projection, self.capacity),
+                };
+                self.filename = Some(p);
+                Ok(reader)
+            }
+            source => {
+                let reader = self.open(source)?;
+                Ok(Reader::new(
+                    reader,
+                    self.raw,
+                    &self.projection,
+                    self.capacity,
+                ))
+            }
+        }
+    }
+
+    /// the next value from whichever source is current. a source that can't be
+    /// opened or read is reported, and reading carries on with the next one
+    fn next_value(&mut self) -> Option<Result<JsonValue>> {
+        self.next_from(Reader::next)
+    }
+
+    /// the next value as it is in the input, passed to `f`. with `-s` there's
+    /// only the one value that's already been read, so `f` borrows that
+    pub fn next_with<T>(&mut self, mut f: impl FnMut(JsonValueRef<'_>) -> T) -> Option<Result<T>> {
+        if self.slurp {
+            return self.next().map(|v| v.map(|v| f(JsonValueRef::from(&v))));
+        }
+        self.next_from(|reader| reader.next_with(&mut f))
+    }
+
+    /// the next thing `read` gets from whichever reader is current, getting
+    /// past invalid input as `on_error` says
+    fn next_from<T>(
+        &mut self,
+        mut read: impl FnMut(&mut Reader) -> Option<Result<T>>,
+    ) -> Option<Result<T>> {
+        loop {
+            if let Some(reader) = &mut self.reader {
+                match read(reader) {
+                    Some(Ok(v)) => return Some(Ok(v)),
+                    Some(Err(e)) if self.on_error != OnError::Fail && e.is::<JsonParseError>() => {
+                        match self.skip(e) {
+                            Ok(()) => continue,
+                            Err(e) => {
+                                self.reader = None;
+                                return Some(Err(e));
+                            }
+                        }
+                    }
+                    Some(Err(e)) => {
+                        self.reader = None;
+                        return Some(Err(e));
+                    }
+                    None => self.reader = None,
+                }
+            }
+            let source = self.sources.pop_front()?;
+            match self.open_reader(source) {
+                Ok(reader) => self.reader = Some(reader),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// gets past the line with the parse error `e` on it
+    fn skip(&mut self, e: anyhow::Error) -> Result<()> {
+        let (number, line) = match &mut self.reader {
+            Some(reader) => reader.skip_line()?,
+            None => return Ok(()),
+        };
+        if self.on_error == OnError::Warn {
+            let file = self.filename.as_deref().unwrap_or("<stdin>");
+            eprintln!("jqr: warning: skipped line {} of {}: {}", number, file, e);
+        }
+        if let Some(rejects) = &mut self.rejects {
+            rejects.write_all(&line)?;
+            rejects.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// the whole text of the next source
+    fn next_text(&mut self) -> Option<Result<String>> {
+        let source = self.sources.pop_front()?;
+        Some(self.open(source).and_then(|mut reader| {
+            let mut text = String::new();
+            reader.read_to_string(&mut text)?;
+            Ok(text)
+        }))
+    }
+
+    /// everything left, as one value, after any errors met along the way
+    fn read_all(&mut self) -> VecDeque<Result<JsonValue>> {
+        let mut results = VecDeque::new();
+        let all = if self.raw {
+            let mut all = String::new();
+            while let Some(text) = self.next_text() {
+                match text {
+                    Ok(text) => all.push_str(&text),
+                    Err(e) => results.push_back(Err(e)),
+                }
+            }
+            JsonValue::Str(all)
+        } else {
+            let mut values = vec![];
+            while let Some(v) = self.next_value() {
+                match v {
+                    Ok(v) => values.push(v),
+                    Err(e) => results.push_back(Err(e)),
+                }
+            }
+            JsonValue::Array(values.into())
+        };
+        results.push_back(Ok(all));
+        results
+    }
+}
+
+/// whether `path` is a regular file with something in it, rather than stdin, a
+/// pipe or a device, which can only be read as a stream
+fn is_mappable(path: &str) -> bool {
+    path != "-" && std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() > 0)
+}
+
+impl Iterator for Inputs {
+    type Item = Result<JsonValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.slurp {
+            if self.slurped.is_none() {
+                self.slurped = Some(self.read_all());
+            }
+            return self.slurped.as_mut()?.pop_front();
+        }
+        self.next_value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(inputs: Inputs) -> Vec<JsonValue> {
+        inputs.collect::<Result<_>>().expect("inputs are valid")
+    }
+
+    fn text(s: &'static str) -> Box<dyn Read> {
+        Box::new(s.as_bytes())
+    }
+
+    #[test]
+    fn it_works() {
+        let values = read(Inputs::new(text("[1] {\"a\": 2}\nnull"), None));
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[1]["a"], JsonValue::Num(2.0));
+
+        let slurped = read(Inputs::new(text("[1]\n[2]\n"), None).slurp());
+        assert_eq!(slurped.len(), 1);
+        assert_eq!(slurped[0][1][0], JsonValue::Num(2.0));
+
+        let slurped = read(Inputs::new(text(""), None).slurp());
+        assert_eq!(slurped, vec![JsonValue::Array(vec![].into())]);
+    }
+
+    #[test]
+    fn it_reads_files_in_turn() {
+        let dir = std::env::temp_dir().join(format!("jqr-inputs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.json");
+        let b = dir.join("b.json");
+        std::fs::write(&a, "[1]\n").unwrap();
+        std::fs::write(&b, "[2]\n[3]\n").unwrap();
+        let paths = [&a, &dir.join("missing.json"), &b].map(|p| p.display().to_string());
+
+        let mut inputs = Inputs::files(&paths, false);
+        assert_eq!(inputs.next().unwrap().unwrap()[0], JsonValue::Num(1.0));
+        assert_eq!(inputs.filename(), Some(paths[0].as_str()));
+        let err = inputs.next().unwrap().expect_err("the file is missing");
+        assert!(
+            err.to_string()
+                .starts_with(&format!("Could not open {}: ", paths[1])),
+            "{}",
+            err
+        );
+        assert_eq!(inputs.next().unwrap().unwrap()[0], JsonValue::Num(2.0));
+        assert_eq!(inputs.filename(), Some(paths[2].as_str()));
+        assert_eq!(
+            read(inputs),
+            vec![JsonValue::Array(vec![JsonValue::Num(3.0)].into())]
+        );
+
+        let slurped = read(Inputs::files(&[paths[0].clone(), paths[2].clone()], true).slurp());
+        assert_eq!(slurped, vec![JsonValue::Str("[1]\n[2]\n[3]\n".into())]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_skips_invalid_lines() {
+        let ndjson = "{\"a\": 1}\n{\"a\": \n[2]\n{\"a\": x}\n{\"a\": 3}\n";
+        let values = read(Inputs::new(text(ndjson), None).on_error(OnError::Skip));
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[2]["a"], JsonValue::Num(3.0));
+
+        let mut failing = Inputs::new(text(ndjson), None);
+        assert!(failing.next().unwrap().is_ok());
+        assert!(failing.next().unwrap().is_err());
+        assert!(failing.next().is_none());
+
+        let rejects = std::env::temp_dir().join(format!("jqr-rejects-{}", std::process::id()));
+        let file = std::fs::File::create(&rejects).unwrap();
+        let inputs = Inputs::new(text(ndjson), None)
+            .on_error(OnError::Skip)
+            .reject_to(Box::new(file));
+        assert_eq!(read(inputs).len(), 3);
+        let rejected = std::fs::read_to_string(&rejects).unwrap();
+        assert_eq!(rejected, "{\"a\": \n{\"a\": x}\n");
+        std::fs::remove_file(&rejects).unwrap();
+    }
+
+    #[test]
+    fn it_reads_raw_text() {
+        let mut inputs = Inputs::raw(text("a,b\n\nc"), None);
+        assert_eq!(
+            inputs.next().unwrap().unwrap(),
+            JsonValue::Str("a,b".into())
+        );
+        assert_eq!(inputs.line_number(), 1);
+        let rest = read(inputs);
+        assert_eq!(
+            rest,
+            vec![JsonValue::Str("".into()), JsonValue::Str("c".into())]
+        );
+
+        let slurped = read(Inputs::raw(text("a\nb\n"), None).slurp());
+        assert_eq!(slurped, vec![JsonValue::Str("a\nb\n".into())]);
+    }
+}
@@ -0,0 +1,1201 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::iter::once;
+use std::rc::Rc;
+
+use tracing::debug;
+
+use crate::builtins;
+use crate::formats;
+use crate::inputs::Inputs;
+use crate::jq_parser::{AssignOp, Filter, FunctionDef, ObjectKey, Operator, Pipeline, StringPart};
+use crate::json_parser::{JsonValue, JsonValueRef};
+use crate::modules::Modules;
+use crate::path::{self, Path};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    /// raised by `error`, carrying whatever value it was given
+    Custom(JsonValue),
+    /// an operator or builtin was given a value of the wrong type
+    Type(String),
+    /// a path expression produced something that isn't a path, or pointed out of bounds
+    Path(String),
+    /// a call to a function that doesn't exist
+    Undefined(String),
+    /// `input` or `inputs` read something that isn't valid JSON
+    Input(String),
+}
+
+impl RuntimeError {
+    /// a type error about `v`, in jq's `type (value) complaint` form
+    pub(crate) fn invalid(v: &JsonValue, what: &str) -> RuntimeError {
+        RuntimeError::Type(format!("{} ({}) {}", v.type_name(), v, what))
+    }
+
+    /// the value `try ... catch` hands to its handler
+    pub fn value(&self) -> JsonValue {
+        match self {
+            RuntimeError::Custom(v) => v.clone(),
+            _ => JsonValue::Str(self.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::Custom(JsonValue::Str(s)) => write!(f, "{}", s),
+            RuntimeError::Custom(v) => write!(f, "{} (not a string)", v),
+            RuntimeError::Type(s)
+            | RuntimeError::Path(s)
+            | RuntimeError::Undefined(s)
+            | RuntimeError::Input(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// what a filter can see besides its input: the variables and functions in
+/// scope, the stream that `input` and `inputs` read from, and the modules that
+/// were loaded. `'a` is how long the definitions of the functions live
+#[derive(Clone, Default)]
+pub struct Env<'a> {
+    vars: Option<Rc<Var>>,
+    funcs: Option<Rc<Function<'a>>>,
+    inputs: Option<Rc<RefCell<Inputs>>>,
+    modules: Option<&'a Modules>,
+}
+
+/// one link of the chain of bindings, innermost first
+struct Var {
+    name: String,
+    value: JsonValue,
+    outer: Option<Rc<Var>>,
+}
+
+/// one link of the chain of functions, innermost first
+struct Function<'a> {
+    name: Cow<'a, str>,
+    arity: usize,
+    body: Callable<'a>,
+    outer: Option<Rc<Function<'a>>>,
+}
+
+/// what calling a function runs
+enum Callable<'a> {
+    /// a `def`. it runs with the variables that were in scope where it was
+    /// defined, and the functions there along with itself
+    Def {
+        def: &'a FunctionDef,
+        vars: Option<Rc<Var>>,
+    },
+    /// a filter passed as an argument, which runs where it was passed
+    Arg { body: &'a Pipeline, env: Env<'a> },
+    /// `x` for a `$x` parameter
+    Value(JsonValue),
+    /// a module's function under the name it was imported as
+    Alias(Rc<Function<'a>>),
+}
+
+impl<'a> Env<'a> {
+    /// an environment whose `input` builtins read from `inputs`
+    pub fn with_inputs(inputs: Inputs) -> Env<'a> {
+        Env {
+            inputs: Some(Rc::new(RefCell::new(inputs))),
+            ..Env::default()
+        }
+    }
+
+    /// `self` with `$name` bound to `value`, hiding any outer `$name`
+    pub fn bind(&self, name: &str, value: JsonValue) -> Env<'a> {
+        Env {
+            vars: Some(Rc::new(Var {
+                name: name.to_string(),
+                value,
+                outer: self.vars.clone(),
+            })),
+            ..self.clone()
+        }
+    }
+
+    /// `self` with `name` callable, hiding any outer function of the same
+    /// name and arity
+    fn with_function(&self, name: Cow<'a, str>, arity: usize, body: Callable<'a>) -> Env<'a> {
+        Env {
+            funcs: Some(Rc::new(Function {
+                name,
+                arity,
+                body,
+                outer: self.funcs.clone(),
+            })),
+            ..self.clone()
+        }
+    }
+
+    /// `self` with the function `def` defined
+    pub(crate) fn define(&self, def: &'a FunctionDef) -> Env<'a> {
+        let body = Callable::Def {
+            def,
+            vars: self.vars.clone(),
+        };
+        self.with_function(Cow::Borrowed(&def.name), def.params.len(), body)
+    }
+
+    /// `self` with the functions defined in `module`, besides the ones it
+    /// imported under a prefix, callable here too, as `prefix::name` if
+    /// there's a prefix
+    pub(crate) fn import(&self, module: &Env<'a>, prefix: Option<&str>) -> Env<'a> {
+        let mut funcs = vec![];
+        let mut f = module.funcs.as_ref();
+        while let Some(func) = f {
+            if !func.name.contains("::") {
+                funcs.push(func.clone());
+            }
+            f = func.outer.as_ref();
+        }
+        // the oldest first, so later definitions still hide earlier ones
+        funcs.into_iter().rev().fold(self.clone(), |env, func| {
+            let name = match prefix {
+                Some(prefix) => Cow::Owned(format!("{}::{}", prefix, func.name)),
+                None => func.name.clone(),
+            };
+            env.with_function(name, func.arity, Callable::Alias(func))
+        })
+    }
+
+    /// an environment reading the same inputs, but with nothing in scope, for
+    /// a module's functions to be defined in
+    pub(crate) fn top_level(&self) -> Env<'a> {
+        Env {
+            inputs: self.inputs.clone(),
+            modules: self.modules,
+            ..Env::default()
+        }
+    }
+
+    /// `self` with `modules` to look up for `modulemeta`
+    pub(crate) fn with_modules(&self, modules: &'a Modules) -> Env<'a> {
+        Env {
+            modules: Some(modules),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn modules(&self) -> Option<&'a Modules> {
+        self.modules
+    }
+
+    fn function(&self, name: &str, arity: usize) -> Option<Rc<Function<'a>>> {
+        let mut f = self.funcs.as_ref();
+        while let Some(func) = f {
+            if func.name == name && func.arity == arity {
+                return Some(func.clone());
+            }
+            f = func.outer.as_ref();
+        }
+        None
+    }
+
+    pub(crate) fn var(&self, name: &str) -> Result<JsonValue, RuntimeError> {
+        let mut var = self.vars.as_deref();
+        while let Some(v) = var {
+            if v.name == name {
+                return Ok(v.value.clone());
+            }
+            var = v.outer.as_deref();
+        }
+        match name {
+            "ENV" => Ok(builtins::environment()),
+            _ => Err(RuntimeError::Undefined(format!("${} is not defined", name))),
+        }
+    }
+
+    /// the next value of the input stream, or `None` once it has run out
+    pub fn next_input(&self) -> Option<Result<JsonValue, RuntimeError>> {
+        let next = self.inputs.as_ref()?.borrow_mut().next()?;
+        Some(next.map_err(|e| RuntimeError::Input(e.to_string())))
+    }
+
+    /// the next input as it is in the input, passed to `f`, so `f` can pick out
+    /// what it needs without the rest being copied. `input` and `inputs` don't
+    /// use this: the values they give the filter are always copied whole
+    pub fn next_input_with<T>(
+        &self,
+        f: impl FnMut(JsonValueRef<'_>) -> T,
+    ) -> Option<Result<T, RuntimeError>> {
+        let next = self.inputs.as_ref()?.borrow_mut().next_with(f)?;
+        Some(next.map_err(|e| RuntimeError::Input(e.to_string())))
+    }
+
+    /// `input_filename`: null when reading stdin
+    pub(crate) fn input_filename(&self) -> JsonValue {
+        self.inputs
+            .as_ref()
+            .and_then(|i| i.borrow().filename().map(str::to_string))
+            .map_or(JsonValue::Null, JsonValue::Str)
+    }
+
+    pub(crate) fn input_line_number(&self) -> JsonValue {
+        let lines = self.inputs.as_ref().map_or(0, |i| i.borrow().line_number());
+        JsonValue::Num(lines as f64)
+    }
+}
+
+pub type ValueIter<'a> = Box<dyn Iterator<Item = Result<JsonValue, RuntimeError>> + 'a>;
+
+/// in path-tracking mode every output is the path it was found at, plus the value there
+pub(crate) type PathIter<'a> =
+    Box<dyn Iterator<Item = Result<(Path, JsonValue), RuntimeError>> + 'a>;
+
+type Results<'a, T> = Box<dyn Iterator<Item = Result<T, RuntimeError>> + 'a>;
+
+pub(crate) fn one<'a, T: 'a>(r: Result<T, RuntimeError>) -> Results<'a, T> {
+    Box::new(once(r))
+}
+
+/// feeds every successful output of `it` to `f`, passing errors through untouched
+pub(crate) fn flat_map_ok<'a, T: 'a, U: 'a>(
+    it: Results<'a, T>,
+    mut f: impl FnMut(T) -> Results<'a, U> + 'a,
+) -> Results<'a, U> {
+    Box::new(it.flat_map(move |r| match r {
+        Ok(v) => f(v),
+        Err(e) => one(Err(e)),
+    }))
+}
+
+pub(crate) fn map_ok<'a, T: 'a, U: 'a>(
+    it: Results<'a, T>,
+    mut f: impl FnMut(T) -> Result<U, RuntimeError> + 'a,
+) -> Results<'a, U> {
+    Box::new(it.map(move |r| r.and_then(&mut f)))
+}
+
+/// yields the outputs of `body` until it raises an error, then switches to whatever
+/// `handler` produces for that error
+fn try_catch<'a, T: 'a>(
+    body: Results<'a, T>,
+    handler: impl FnOnce(RuntimeError) -> Results<'a, T> + 'a,
+) -> Results<'a, T> {
+    let mut body = Some(body);
+    let mut handler = Some(handler);
+    let mut caught: Option<Results<'a, T>> = None;
+    Box::new(std::iter::from_fn(move || {
+        if let Some(c) = caught.as_mut() {
+            return c.next();
+        }
+        match body.as_mut()?.next() {
+            Some(Err(e)) => {
+                body = None;
+                let mut c = handler.take()?(e);
+                let next = c.next();
+                caught = Some(c);
+                next
+            }
+            other => other,
+        }
+    }))
+}
+
+/// yields the truthy outputs of `lhs`, or the outputs of `rhs` if there weren't any.
+/// errors raised by `lhs` are swallowed, like jq does
+fn alternative<'a, T: 'a>(
+    lhs: Results<'a, T>,
+    rhs: impl FnOnce() -> Results<'a, T> + 'a,
+    truthy: fn(&T) -> bool,
+) -> Results<'a, T> {
+    let mut lhs = Some(lhs);
+    let mut rhs = Some(rhs);
+    let mut found = false;
+    let mut fallback: Option<Results<'a, T>> = None;
+    Box::new(std::iter::from_fn(move || loop {
+        if let Some(f) = fallback.as_mut() {
+            return f.next();
+        }
+        match lhs.as_mut()?.next() {
+            Some(Ok(v)) if truthy(&v) => {
+                found = true;
+                return Some(Ok(v));
+            }
+            Some(_) => continue,
+            None if found => return None,
+            None => {
+                lhs = None;
+                fallback = Some(rhs.take()?());
+            }
+        }
+    }))
+}
+
+fn invalid_path(v: &JsonValue) -> RuntimeError {
+    RuntimeError::Path(format!("Invalid path expression with result {}", v))
+}
+
+/// `..`: the value itself, then everything below it, depth first
+pub(crate) fn recurse_values<'a>(v: JsonValue) -> ValueIter<'a> {
+    let children = match &v {
+        JsonValue::Array(_) | JsonValue::Object(_) => path::iterate(v.clone()).unwrap_or_default(),
+        _ => vec![],
+    };
+    Box::new(once(Ok(v)).chain(children.into_iter().flat_map(recurse_values)))
+}
+
+pub(crate) fn recurse_paths<'a>((path, v): (Path, JsonValue)) -> PathIter<'a> {
+    let children = match &v {
+        JsonValue::Array(_) | JsonValue::Object(_) => path::entries(v.clone()).unwrap_or_default(),
+        _ => vec![],
+    };
+    let here = path.clone();
+    Box::new(
+        once(Ok((here, v))).chain(children.into_iter().flat_map(move |(k, child)| {
+            let mut p = path.clone();
+            p.push(k);
+            recurse_paths((p, child))
+        })),
+    )
+}
+
+/// `recurse(f)`: the value, then `f` applied to it recursively
+pub(crate) fn recurse_with<'a>(f: &'a Pipeline, env: &Env<'a>, v: JsonValue) -> ValueIter<'a> {
+    let next = f.eval(env, v.clone());
+    let env = env.clone();
+    Box::new(once(Ok(v)).chain(flat_map_ok(next, move |c| recurse_with(f, &env, c))))
+}
+
+pub(crate) fn recurse_paths_with<'a>(
+    f: &'a Pipeline,
+    env: &Env<'a>,
+    input: (Path, JsonValue),
+) -> PathIter<'a> {
+    let next = f.eval_paths(env, input.clone());
+    let env = env.clone();
+    Box::new(once(Ok(input)).chain(flat_map_ok(next, move |c| recurse_paths_with(f, &env, c))))
+}
+
+pub(crate) fn binop(
+    op: Operator,
+    lhs: JsonValue,
+    rhs: JsonValue,
+) -> Result<JsonValue, RuntimeError> {
+    use JsonValue::*;
+
+    let fail = |verb: &str, lhs: &JsonValue, rhs: &JsonValue| {
+        Err(RuntimeError::Type(format!(
+            "{} ({}) and {} ({}) cannot be {}",
+            lhs.type_name(),
+            lhs,
+            rhs.type_name(),
+            rhs,
+            verb
+        )))
+    };
+
+    match op {
+        Operator::Eq => Ok(Boolean(lhs.compare(&rhs).is_eq())),
+        Operator::Ne => Ok(Boolean(lhs.compare(&rhs).is_ne())),
+        Operator::Lt => Ok(Boolean(lhs.compare(&rhs).is_lt())),
+        Operator::Le => Ok(Boolean(lhs.compare(&rhs).is_le())),
+        Operator::Gt => Ok(Boolean(lhs.compare(&rhs).is_gt())),
+        Operator::Ge => Ok(Boolean(lhs.compare(&rhs).is_ge())),
+        Operator::Add => match (lhs, rhs) {
+            (Null, v) | (v, Null) => Ok(v),
+            (Num(a), Num(b)) => Ok(Num(a + b)),
+            (Str(a), Str(b)) => Ok(Str(a + &b)),
+            (Array(a), Array(b)) => {
+                let mut a = Rc::unwrap_or_clone(a);
+                a.extend(Rc::unwrap_or_clone(b));
+                Ok(Array(a.into()))
+            }
+            (Object(a), Object(b)) => {
+                let mut a = Rc::unwrap_or_clone(a);
+                a.extend(Rc::unwrap_or_clone(b));
+                Ok(Object(a.into()))
+            }
+            (a, b) => fail("added", &a, &b),
+        },
+        Operator::Sub => match (lhs, rhs) {
+            (Num(a), Num(b)) => Ok(Num(a - b)),
+            (Array(a), Array(b)) => Ok(Rc::unwrap_or_clone(a)
+                .into_iter()
+                .filter(|e| !b.iter().any(|x| x.compare(e).is_eq()))
+                .collect()),
+            (a, b) => fail("subtracted", &a, &b),
+        },
+        Operator::Mul => match (lhs, rhs) {
+            (Num(a), Num(b)) => Ok(Num(a * b)),
+            (Str(s), Num(n)) | (Num(n), Str(s)) => {
+                if n <= 0.0 {
+                    Ok(Null)
+                } else {
+                    Ok(Str(s.repeat(n.ceil() as usize)))
+                }
+            }
+            (Object(a), Object(b)) => Ok(Object(
+                deep_merge(Rc::unwrap_or_clone(a), Rc::unwrap_or_clone(b)).into(),
+            )),
+            (a, b) => fail("multiplied", &a, &b),
+        },
+        Operator::Div => match (lhs, rhs) {
+            (Num(a), Num(b)) if b == 0.0 => {
+                fail("divided because the divisor is zero", &Num(a), &Num(b))
+            }
+            (Num(a), Num(b)) => Ok(Num(a / b)),
+            (Str(a), Str(b)) => Ok(builtins::split(&a, &b)),
+            (a, b) => fail("divided", &a, &b),
+        },
+        Operator::Mod => match (lhs, rhs) {
+            (Num(a), Num(b)) if b as i64 == 0 => {
+                fail("divided because the divisor is zero", &Num(a), &Num(b))
+            }
+            // a divisor of -1 would overflow for the smallest dividend, and is always 0 anyway
+            (Num(_), Num(b)) if b as i64 == -1 => Ok(Num(0.0)),
+            (Num(a), Num(b)) => Ok(Num(((a as i64) % (b as i64)) as f64)),
+            (a, b) => fail("divided", &a, &b),
+        },
+    }
+}
+
+fn deep_merge(
+    mut a: BTreeMap<String, JsonValue>,
+    b: BTreeMap<String, JsonValue>,
+) -> BTreeMap<String, JsonValue> {
+    for (k, v) in b {
+        let merged = match (a.remove(&k), v) {
+            (Some(JsonValue::Object(x)), JsonValue::Object(y)) => {
+                JsonValue::Object(deep_merge(Rc::unwrap_or_clone(x), Rc::unwrap_or_clone(y)).into())
+            }
+            (_, v) => v,
+        };
+        a.insert(k, merged);
+    }
+    a
+}
+
+/// every path `f` points at in `input`
+fn collect_paths(f: &Filter, env: &Env, input: JsonValue) -> Result<Vec<Path>, RuntimeError> {
+    f.eval_paths(env, (vec![], input))
+        .map(|r| r.map(|(p, _)| p))
+        .collect()
+}
+
+/// applies `update` to the value at each of the paths `lhs` points at in `input`.
+/// this is what all of the assignment operators boil down to
+fn modify(
+    lhs: &Filter,
+    env: &Env,
+    input: JsonValue,
+    mut update: impl FnMut(JsonValue) -> Result<Option<JsonValue>, RuntimeError>,
+) -> Result<JsonValue, RuntimeError> {
+    collect_paths(lhs, env, input.clone())?
+        .iter()
+        .try_fold(input, |acc, p| path::update(acc, p, &mut update))
+}
+
+fn assign<'a>(
+    op: AssignOp,
+    lhs: &'a Filter,
+    rhs: &'a Filter,
+    env: &Env<'a>,
+    input: JsonValue,
+) -> ValueIter<'a> {
+    let rhs_outputs = rhs.eval(env, input.clone());
+    let env = env.clone();
+    match op {
+        // the first output of the rhs replaces each value, or deletes it if there isn't one
+        AssignOp::Update => one(modify(lhs, &env, input, |v| {
+            rhs.eval(&env, v).next().transpose()
+        })),
+        // the others evaluate the rhs against `.` and produce one result per output
+        AssignOp::Set => map_ok(rhs_outputs, move |val| {
+            modify(lhs, &env, input.clone(), |_| Ok(Some(val.clone())))
+        }),
+        AssignOp::Arithmetic(op) => map_ok(rhs_outputs, move |val| {
+            modify(lhs, &env, input.clone(), |v| {
+                binop(op, v, val.clone()).map(Some)
+            })
+        }),
+        AssignOp::Alternative => map_ok(rhs_outputs, move |val| {
+            modify(lhs, &env, input.clone(), |v| {
+                Ok(Some(if v.is_truthy() { v } else { val.clone() }))
+            })
+        }),
+    }
+}
+
+/// builds every string `parts` can produce. like binary operators, later
+/// interpolations are the outer loops
+fn interpolate<'a>(
+    format: Option<&'a str>,
+    parts: &'a [StringPart],
+    env: &Env<'a>,
+    input: JsonValue,
+) -> Results<'a, String> {
+    parts
+        .iter()
+        .rev()
+        .fold(one(Ok(String::new())), move |acc, part| match part {
+            StringPart::Literal(s) => map_ok(acc, move |suffix| Ok(s.clone() + &suffix)),
+            StringPart::Interpolation(p) => {
+                let input = input.clone();
+                let env = env.clone();
+                flat_map_ok(acc, move |suffix| {
+                    map_ok(p.eval(&env, input.clone()), move |v| {
+                        let text = match format {
+                            Some(name) => formats::apply(name, &v)?,
+                            None => formats::to_text(&v),
+                        };
+                        Ok(text + &suffix)
+                    })
+                })
+            }
+        })
+}
+
+fn construct_object(
+    entries: &[(ObjectKey, Option<Pipeline>)],
+    env: &Env,
+    input: &JsonValue,
+) -> Result<Vec<JsonValue>, RuntimeError> {
+    let mut objects = vec![BTreeMap::new()];
+    for (key, val) in entries {
+        let keys = match key {
+            ObjectKey::Literal(k) => vec![k.clone()],
+            ObjectKey::Expr(p) => p
+                .eval(env, input.clone())
+                .map(|k| match k? {
+                    JsonValue::Str(s) => Ok(s),
+                    k => Err(RuntimeError::Type(format!(
+                        "Object keys must be strings, not {}",
+                        k.type_name()
+                    ))),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        let mut next = vec![];
+        for k in keys {
+            let vals = match val {
+                Some(p) => p.eval(env, input.clone()).collect::<Result<Vec<_>, _>>()?,
+                None => vec![path::get_field(input.clone(), &k)?],
+            };
+            for o in &objects {
+                for v in &vals {
+                    let mut o = o.clone();
+                    o.insert(k.clone(), v.clone());
+                    next.push(o);
+                }
+            }
+        }
+        objects = next;
+    }
+    Ok(objects.into_iter().map(JsonValue::from).collect())
+}
+
+impl Pipeline {
+    /// runs the pipeline against one input value, yielding each of its outputs
+    pub fn apply(&self, val: JsonValue) -> ValueIter<'_> {
+        self.apply_with(&Env::default(), val)
+    }
+
+    /// like `apply`, but with variables and an input stream to draw on
+    pub fn apply_with<'a>(&'a self, env: &Env<'a>, val: JsonValue) -> ValueIter<'a> {
+        self.eval(env, val)
+    }
+
+    pub(crate) fn eval<'a>(&'a self, env: &Env<'a>, input: JsonValue) -> ValueIter<'a> {
+        self.filters.iter().fold(one(Ok(input)), |acc, f| {
+            let env = env.clone();
+            flat_map_ok(acc, move |v| f.eval(&env, v))
+        })
+    }
+
+    /// evaluates the pipeline in path-tracking mode. `input` is the path of the
+    /// current value relative to the root, and the value itself
+    pub(crate) fn eval_paths<'a>(
+        &'a self,
+        env: &Env<'a>,
+        input: (Path, JsonValue),
+    ) -> PathIter<'a> {
+        self.filters.iter().fold(one(Ok(input)), |acc, f| {
+            let env = env.clone();
+            flat_map_ok(acc, move |v| f.eval_paths(&env, v))
+        })
+    }
+}
+
+/// `reduce source as $name (init; update)`: folds every output of the source
+/// into the state, starting from each output of init in turn
+fn reduce<'a>(
+    source: &'a Filter,
+    name: &'a str,
+    init: &'a Pipeline,
+    update: &'a Pipeline,
+    env: &Env<'a>,
+    input: JsonValue,
+) -> ValueIter<'a> {
+    let env = env.clone();
+    flat_map_ok(init.eval(&env, input.clone()), move |state| {
+        let reduced = source
+            .eval(&env, input.clone())
+            .try_fold(state, |state, item| {
+                // the last output of the update is the new state, or null if there isn't one
+                let outputs = update.eval(&env.bind(name, item?), state);
+                outputs.last().unwrap_or(Ok(JsonValue::Null))
+            });
+        one(reduced)
+    })
+}
+
+/// `foreach source as $name (init; update; extract)`: like `reduce`, but yields
+/// the extract of every intermediate state as it goes
+fn foreach<'a>(
+    source: &'a Filter,
+    name: &'a str,
+    init: &'a Pipeline,
+    update: &'a Pipeline,
+    extract: Option<&'a Pipeline>,
+    env: &Env<'a>,
+    input: JsonValue,
+) -> ValueIter<'a> {
+    let env = env.clone();
+    flat_map_ok(init.eval(&env, input.clone()), move |init| {
+        let env = env.clone();
+        let mut state = init;
+        flat_map_ok(source.eval(&env, input.clone()), move |item| {
+            let env = env.bind(name, item);
+            let states = match update
+                .eval(&env, state.clone())
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(states) => states,
+                Err(e) => return one(Err(e)),
+            };
+            if let Some(last) = states.last() {
+                state = last.clone();
+            }
+            match extract {
+                Some(extract) => {
+                    Box::new(states.into_iter().flat_map(move |s| extract.eval(&env, s)))
+                }
+                None => Box::new(states.into_iter().map(Ok)),
+            }
+        })
+    })
+}
+
+impl Filter {
+    pub(crate) fn eval<'a>(&'a self, env: &Env<'a>, input: JsonValue) -> ValueIter<'a> {
+        debug!("applying {:?} to {:?}", self, input);
+        match self {
+            Filter::FieldAccessor { fields } => one(fields
+                .iter()
+                .try_fold(input, |cur, field| path::get_field(cur, field))),
+            Filter::Recurse => recurse_values(input),
+            Filter::Literal(v) => one(Ok(v.clone())),
+            Filter::StringInterpolation { format, parts } => {
+                map_ok(interpolate(format.as_deref(), parts, env, input), |s| {
+                    Ok(JsonValue::Str(s))
+                })
+            }
+            Filter::Format(name) => one(formats::apply(name, &input).map(JsonValue::Str)),
+            Filter::Index { target, index } => {
+                let env = env.clone();
+                flat_map_ok(index.eval(&env, input.clone()), move |idx| {
+                    map_ok(target.eval(&env, input.clone()), move |t| {
+                        path::get(&t, &idx)
+                    })
+                })
+            }
+            Filter::Slice { target, from, to } => {
+                let env = env.clone();
+                flat_map_ok(slice_keys(from, to, &env, &input), move |key| {
+                    map_ok(target.eval(&env, input.clone()), move |t| {
+                        path::get(&t, &key)
+                    })
+                })
+            }
+            Filter::Iterate { target } => {
+                flat_map_ok(target.eval(env, input), |t| match path::iterate(t) {
+                    Ok(vs) => Box::new(vs.into_iter().map(Ok)),
+                    Err(e) => one(Err(e)),
+                })
+            }
+            Filter::Try { body, handler } => {
+                let env = env.clone();
+                try_catch(body.eval(&env, input), move |e| match handler {
+                    Some(h) => h.eval(&env, e.value()),
+                    None => Box::new(std::iter::empty()),
+                })
+            }
+            Filter::Parens(p) => p.eval(env, input),
+            Filter::ArrayConstruction(None) => one(Ok(JsonValue::Array(vec![].into()))),
+            Filter::ArrayConstruction(Some(p)) => one(p
+                .eval(env, input)
+                .collect::<Result<Vec<_>, _>>()
+                .map(JsonValue::from)),
+            Filter::ObjectConstruction(entries) => match construct_object(entries, env, &input) {
+                Ok(objects) => Box::new(objects.into_iter().map(Ok)),
+                Err(e) => one(Err(e)),
+            },
+            Filter::Comma(filters) => {
+                let env = env.clone();
+                Box::new(
+                    filters
+                        .iter()
+                        .flat_map(move |f| f.eval(&env, input.clone())),
+                )
+            }
+            Filter::Alternative(lhs, rhs) => {
+                let env = env.clone();
+                alternative(
+                    lhs.eval(&env, input.clone()),
+                    move || rhs.eval(&env, input),
+                    JsonValue::is_truthy,
+                )
+            }
+            Filter::And(lhs, rhs) => {
+                let env = env.clone();
+                flat_map_ok(lhs.eval(&env, input.clone()), move |l| {
+                    if !l.is_truthy() {
+                        return one(Ok(JsonValue::Boolean(false)));
+                    }
+                    map_ok(rhs.eval(&env, input.clone()), |r| {
+                        Ok(JsonValue::Boolean(r.is_truthy()))
+                    })
+                })
+            }
+            Filter::Or(lhs, rhs) => {
+                let env = env.clone();
+                flat_map_ok(lhs.eval(&env, input.clone()), move |l| {
+                    if l.is_truthy() {
+                        return one(Ok(JsonValue::Boolean(true)));
+                    }
+                    map_ok(rhs.eval(&env, input.clone()), |r| {
+                        Ok(JsonValue::Boolean(r.is_truthy()))
+                    })
+                })
+            }
+            Filter::Negate(f) => map_ok(f.eval(env, input), |v| match v {
+                JsonValue::Num(n) => Ok(JsonValue::Num(-n)),
+                v => Err(RuntimeError::Type(format!(
+                    "{} ({}) cannot be negated",
+                    v.type_name(),
+                    v
+                ))),
+            }),
+            // like jq, the right hand side is the outer loop
+            Filter::Operation { op, lhs, rhs } => {
+                let env = env.clone();
+                flat_map_ok(rhs.eval(&env, input.clone()), move |r| {
+                    map_ok(lhs.eval(&env, input.clone()), move |l| {
+                        binop(*op, l, r.clone())
+                    })
+                })
+            }
+            Filter::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let env = env.clone();
+                flat_map_ok(cond.eval(&env, input.clone()), move |c| {
+                    match (c.is_truthy(), otherwise) {
+                        (true, _) => then.eval(&env, input.clone()),
+                        (false, Some(otherwise)) => otherwise.eval(&env, input.clone()),
+                        (false, None) => one(Ok(input.clone())),
+                    }
+                })
+            }
+            Filter::Assign { op, lhs, rhs } => assign(*op, lhs, rhs, env, input),
+            Filter::Variable(name) => one(env.var(name)),
+            Filter::Bind { source, name, body } => {
+                let env = env.clone();
+                flat_map_ok(source.eval(&env, input.clone()), move |v| {
+                    body.eval(&env.bind(name, v), input.clone())
+                })
+            }
+            Filter::Reduce {
+                source,
+                name,
+                init,
+                update,
+            } => reduce(source, name, init, update, env, input),
+            Filter::Foreach {
+                source,
+                name,
+                init,
+                update,
+                extract,
+            } => foreach(source, name, init, update, extract.as_deref(), env, input),
+            Filter::Define { def, rest } => rest.eval(&env.define(def), input),
+            Filter::FunctionCall { name, args, cache } => match env.function(name, args.len()) {
+                Some(func) => match call(func, args, env, &input) {
+                    Call::Run(body, envs) => {
+                        flat_map_ok(envs, move |env| body.eval(&env, input.clone()))
+                    }
+                    Call::Value(v) => one(Ok(v)),
+                },
+                None => builtins::call(name, args, cache, env, input),
+            },
+        }
+    }
+
+    pub(crate) fn eval_paths<'a>(
+        &'a self,
+        env: &Env<'a>,
+        input: (Path, JsonValue),
+    ) -> PathIter<'a> {
+        let (path, value) = input;
+        match self {
+            Filter::FieldAccessor { fields } => {
+                one(fields
+                    .iter()
+                    .try_fold((path, value), |(mut path, cur), field| {
+                        let next = path::get_field(cur, field)?;
+                        path.push(JsonValue::Str(field.clone()));
+                        Ok((path, next))
+                    }))
+            }
+            Filter::Recurse => recurse_paths((path, value)),
+            Filter::Index { target, index } => {
+                let env = env.clone();
+                flat_map_ok(index.eval(&env, value.clone()), move |idx| {
+                    map_ok(
+                        target.eval_paths(&env, (path.clone(), value.clone())),
+                        move |(mut p, t)| {
+                            let next = path::get(&t, &idx)?;
+                            p.push(idx.clone());
+                            Ok((p, next))
+                        },
+                    )
+                })
+            }
+            Filter::Slice { target, from, to } => {
+                let env = env.clone();
+                flat_map_ok(slice_keys(from, to, &env, &value), move |key| {
+                    map_ok(
+                        target.eval_paths(&env, (path.clone(), value.clone())),
+                        move |(mut p, t)| {
+                            let next = path::get(&t, &key)?;
+                            p.push(key.clone());
+                            Ok((p, next))
+                        },
+                    )
+                })
+            }
+            Filter::Iterate { target } => flat_map_ok(
+                target.eval_paths(env, (path, value)),
+                |(p, t)| match path::entries(t) {
+                    Ok(children) => Box::new(children.into_iter().map(move |(k, child)| {
+                        let mut p = p.clone();
+                        p.push(k);
+                        Ok((p, child))
+                    })),
+                    Err(e) => one(Err(e)),
+                },
+            ),
+            Filter::Try { body, handler } => {
+                let env = env.clone();
+                try_catch(
+                    body.eval_paths(&env, (path, value)),
+                    move |e| match handler {
+                        Some(h) => map_ok(h.eval(&env, e.value()), |v| Err(invalid_path(&v))),
+                        None => Box::new(std::iter::empty()),
+                    },
+                )
+            }
+            Filter::Parens(p) => p.eval_paths(env, (path, value)),
+            Filter::Comma(filters) => {
+                let env = env.clone();
+                Box::new(
+                    filters
+                        .iter()
+                        .flat_map(move |f| f.eval_paths(&env, (path.clone(), value.clone()))),
+                )
+            }
+            Filter::Alternative(lhs, rhs) => {
+                let env = env.clone();
+                alternative(
+                    lhs.eval_paths(&env, (path.clone(), value.clone())),
+                    move || rhs.eval_paths(&env, (path, value)),
+                    |(_, v)| v.is_truthy(),
+                )
+            }
+            Filter::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let env = env.clone();
+                flat_map_ok(cond.eval(&env, value.clone()), move |c| {
+                    let input = (path.clone(), value.clone());
+                    match (c.is_truthy(), otherwise) {
+                        (true, _) => then.eval_paths(&env, input),
+                        (false, Some(otherwise)) => otherwise.eval_paths(&env, input),
+                        (false, None) => one(Ok(input)),
+                    }
+                })
+            }
+            Filter::Bind { source, name, body } => {
+                let env = env.clone();
+                flat_map_ok(source.eval(&env, value.clone()), move |v| {
+                    body.eval_paths(&env.bind(name, v), (path.clone(), value.clone()))
+                })
+            }
+            Filter::Define { def, rest } => rest.eval_paths(&env.define(def), (path, value)),
+            Filter::FunctionCall { name, args, cache } => match env.function(name, args.len()) {
+                Some(func) => match call(func, args, env, &value) {
+                    Call::Run(body, envs) => flat_map_ok(envs, move |env| {
+                        body.eval_paths(&env, (path.clone(), value.clone()))
+                    }),
+                    Call::Value(v) => one(Err(invalid_path(&v))),
+                },
+                None => builtins::call_paths(name, args, cache, env, (path, value)),
+            },
+            _ => map_ok(self.eval(env, value), |v| Err(invalid_path(&v))),
+        }
+    }
+}
+
+/// what calling a function comes down to
+enum Call<'a> {
+    /// running `body` in each of the environments, one per combination of the
+    /// values of the `$` parameters
+    Run(&'a Pipeline, Results<'a, Env<'a>>),
+    /// the value of a `$` parameter
+    Value(JsonValue),
+}
+
+/// calls `func` with `args`, passed from `env` with `input` as `.`
+fn call<'a>(
+    func: Rc<Function<'a>>,
+    args: &'a [Pipeline],
+    env: &Env<'a>,
+    input: &JsonValue,
+) -> Call<'a> {
+    let (def, vars) = match &func.body {
+        Callable::Def { def, vars } => (*def, vars.clone()),
+        Callable::Arg { body, env } => return Call::Run(body, one(Ok(env.clone()))),
+        Callable::Value(v) => return Call::Value(v.clone()),
+        Callable::Alias(f) => return call(f.clone(), args, env, input),
+    };
+    // the body sees the variables and functions from where it was defined,
+    // itself included so it can recurse
+    let callee = Env {
+        vars,
+        funcs: Some(func.clone()),
+        ..env.clone()
+    };
+    // like `a as $a | b as $b | ...`, the first `$` parameter is the outer loop
+    let envs = def
+        .params
+        .iter()
+        .zip(args)
+        .fold(one(Ok(callee)), |envs, (param, arg)| {
+            let caller = env.clone();
+            match param.strip_prefix('$') {
+                Some(name) => {
+                    let input = input.clone();
+                    flat_map_ok(envs, move |callee| {
+                        map_ok(arg.eval(&caller, input.clone()), move |v| {
+                            let value = Callable::Value(v.clone());
+                            Ok(callee
+                                .bind(name, v)
+                                .with_function(Cow::Borrowed(name), 0, value))
+                        })
+                    })
+                }
+                None => map_ok(envs, move |callee| {
+                    let body = Callable::Arg {
+                        body: arg,
+                        env: caller.clone(),
+                    };
+                    Ok(callee.with_function(Cow::Borrowed(param), 0, body))
+                }),
+            }
+        });
+    Call::Run(&def.body, envs)
+}
+
+/// evaluates the bounds of `.[from:to]` into the slice keys used as path components
+fn slice_keys<'a>(
+    from: &'a Option<Box<Pipeline>>,
+    to: &'a Option<Box<Pipeline>>,
+    env: &Env<'a>,
+    input: &JsonValue,
+) -> ValueIter<'a> {
+    let bound = |b: &'a Option<Box<Pipeline>>, env: &Env<'a>, input: JsonValue| match b {
+        Some(p) => p.eval(env, input),
+        None => one(Ok(JsonValue::Null)),
+    };
+    let input = input.clone();
+    let env = env.clone();
+    flat_map_ok(bound(to, &env, input.clone()), move |t| {
+        map_ok(bound(from, &env, input.clone()), move |f| {
+            Ok(path::slice_key(f, t.clone()))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jq_parser::parse_filter;
+    use crate::test_util::run;
+
+    #[test]
+    fn it_works() {
+        // each filter runs against null and is compared with the outputs of the
+        // expected filter, so the inputs can be built up with jq itself
+        let cases = [
+            (r#"{"a": {"b": 1}} | .a.b"#, "1"),
+            (r#"{"a": 1} | .b"#, "null"),
+            ("[1, 2, 3] | .[1]", "2"),
+            ("[1, 2, 3] | .[-1]", "3"),
+            ("[1, 2, 3] | .[1:]", "[2, 3]"),
+            (r#""abcdef" | .[2:4]"#, r#""cd""#),
+            ("[1, 2, 3] | .[]", "1, 2, 3"),
+            (r#"{"a": 1, "b": 2} | [.[]]"#, "[1, 2]"),
+            (
+                r#"{"a": [1, {"b": 2}]} | [..]"#,
+                r#"[{"a": [1, {"b": 2}]}, [1, {"b": 2}], 1, {"b": 2}, 2]"#,
+            ),
+            ("1, 2 | . * 10", "10, 20"),
+            ("(1, 2) + (10, 20)", "11, 12, 21, 22"),
+            (r#"{"a": (1, 2)}"#, r#"{"a": 1}, {"a": 2}"#),
+            (
+                r#"{"k": "x", "v": 1} | {(.k): .v, v}"#,
+                r#"{"x": 1, "v": 1}"#,
+            ),
+            ("null // 1", "1"),
+            ("(false, 2, null, 3) // 1", "2, 3"),
+            ("true and (true, false)", "true, false"),
+            ("false or false", "false"),
+            ("1 < 2, [] > {}, null == false", "true, false, false"),
+            ("if . then 1 elif 1 == 1 then 2 else 3 end", "2"),
+            ("try error(\"x\") catch .", r#""x""#),
+            ("[.[]?]", "[]"),
+            ("[1, error(\"x\"), 3]?", "empty"),
+            ("-(1 + 2) * 2 % 4", "-2"),
+            (r#""a,b" / ",""#, r#"["a", "b"]"#),
+            (
+                r#"{"a": {"b": 1}} * {"a": {"c": 2}}"#,
+                r#"{"a": {"b": 1, "c": 2}}"#,
+            ),
+            ("[1, 2, 3, 1] - [1]", "[2, 3]"),
+            (
+                r#"{"meta": {"seen": 1}} | .meta.seen |= . + 1"#,
+                r#"{"meta": {"seen": 2}}"#,
+            ),
+            (
+                r#"{"tags": ["a"]} | .tags += ["x"]"#,
+                r#"{"tags": ["a", "x"]}"#,
+            ),
+            ("[1, 2, 3] | .[] *= 2", "[2, 4, 6]"),
+            ("[1, 2, 3] | .[1:] -= [3]", "[1, 2]"),
+            (r#"{"a": 6} | .a /= 2, .a %= 4"#, r#"{"a": 3}, {"a": 2}"#),
+            (
+                r#"{"a": null, "b": 1} | .a //= 5 | .b //= 5"#,
+                r#"{"a": 5, "b": 1}"#,
+            ),
+            (r#"{"a": 1, "b": 2} | .a = .b"#, r#"{"a": 2, "b": 2}"#),
+            (r#"{} | .a = (1, 2)"#, r#"{"a": 1}, {"a": 2}"#),
+            (r#"{} | .a.b.c = 1"#, r#"{"a": {"b": {"c": 1}}}"#),
+            ("[1, 2, 3] | (.[] | select(. > 1)) |= empty", "[1, 3]"),
+            ("[1, 2, 3, 4, 5] | .[] |= empty", "[2, 4]"),
+            ("[1, 2] | .[5] |= empty", "[1, 2]"),
+            (r#"{"a": [1, 2]} | .a[0] |= (., 10)"#, r#"{"a": [1, 2]}"#),
+            ("1 as $x | 2 as $y | [$x, $y, .]", "[1, 2, null]"),
+            ("(1, 2) as $x | $x * 10", "10, 20"),
+            ("1 as $x | (2 as $x | $x), $x", "2, 1"),
+            ("[1, 2] as $a | $a[1], $a.[0]", "2, 1"),
+            ("1 + 2 as $x | $x * 10", "21"),
+            (r#"{"a": 1} | path(.a as $x | .a)"#, r#"["a"]"#),
+            ("reduce (1, 2, 3) as $x (0; . + $x)", "6"),
+            ("reduce empty as $x (0; . + 1)", "0"),
+            ("reduce (1, 2) as $x (0, 10; . + $x)", "3, 13"),
+            ("reduce (1, 2) as $x (0; empty)", "null"),
+            ("[foreach (1, 2, 3) as $x (0; . + $x)]", "[1, 3, 6]"),
+            (
+                "[foreach (1, 2, 3) as $x (0; . + $x; [$x, .])]",
+                "[[1, 1], [2, 3], [3, 6]]",
+            ),
+            ("def f: 1; def g(x): [x, f]; g(2)", "[2, 1]"),
+            (
+                "def fac: if . <= 1 then 1 else . * (. - 1 | fac) end; 5 | fac",
+                "120",
+            ),
+            (
+                "[def f(a; $b): a + $b; f(1, 2; 10, 20)]",
+                "[11, 12, 21, 22]",
+            ),
+            (
+                "def f($a; $b): [$a, b]; f(1, 2; 3, 4)",
+                "[1, 3], [1, 4], [2, 3], [2, 4]",
+            ),
+            ("1 as $x | def f: $x; 2 as $x | [f, $x]", "[1, 2]"),
+            ("def f(g): def h: g; [h]; 5 as $v | f($v)", "[5]"),
+            ("def f: 1; def g: f; def f: 2; [g, f]", "[1, 2]"),
+            ("def f: def f: 2; f; f", "2"),
+            (
+                r#"{"a": 1} | def f: .a; [path(f)], (f |= 3)"#,
+                r#"[["a"]], {"a": 3}"#,
+            ),
+        ];
+
+        for (filter, expected) in cases {
+            assert_eq!(run(filter), run(expected), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn it_reads_inputs() {
+        let text = "[1]\n{\"a\": 2}\n[3]\n";
+        let env = Env::with_inputs(Inputs::new(
+            Box::new(text.as_bytes()),
+            Some("in.json".to_string()),
+        ));
+        let run_with = |filter: &str, input: JsonValue| {
+            parse_filter(filter)
+                .expect("filter parses")
+                .apply_with(&env, input)
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let first = env.next_input().unwrap().unwrap();
+        assert_eq!(
+            run_with("[., input, input_line_number, input_filename]", first),
+            run(r#"[[1], {"a": 2}, 2, "in.json"]"#)
+        );
+        assert_eq!(run_with("[inputs]", JsonValue::Null), run("[[3]]"));
+        assert!(env.next_input().is_none());
+    }
+
+    #[test]
+    fn it_reports_errors() {
+        let cases = [
+            ("1 | .a", r#"Cannot index number with "a""#),
+            ("{} | .[0]", "Cannot index object with number"),
+            ("1 | .[]", "Cannot iterate over number"),
+            (
+                r#"1 + "a""#,
+                r#"number (1) and string ("a") cannot be added"#,
+            ),
+            ("error(\"boom\")", "boom"),
+            ("nosuchfunction", "nosuchfunction/0 is not defined"),
+            ("$nope", "$nope is not defined"),
+            ("1 as $x | input", "No more inputs"),
+            (
+                "def f($x): $x; path(f(1))",
+                "Invalid path expression with result 1",
+            ),
+        ];
+
+        for (filter, expected) in cases {
+            let err = run(filter).expect_err(filter);
+            assert_eq!(err.to_string(), expected, "{}", filter);
+        }
+    }
+}
@@ -1,87 +1,688 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while},
-    character::complete::alphanumeric1,
-    combinator::{all_consuming, map, opt},
+    bytes::complete::{is_not, tag, take_while, take_while1, take_while_m_n},
+    character::complete::{alpha1, alphanumeric1, char, digit0, digit1, one_of},
+    combinator::{all_consuming, cut, map, map_opt, not, opt, peek, recognize, value, verify},
     error::{context, ContextError, ParseError, VerboseError},
-    multi::{many1, separated_list0, separated_list1},
-    sequence::{delimited, preceded, tuple},
+    multi::{fold_many0, many0, many0_count, many1, separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     Finish, IResult,
 };
 
-use tracing::debug;
-
+use crate::builtins;
+use crate::diagnostic::{self, Diagnostic};
+use crate::formats;
 use crate::json_parser::JsonValue;
+use crate::regex::RegexCache;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Pipeline {
-    filters: Vec<Filter>,
+    pub(crate) filters: Vec<Filter>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Filter {
+    /// `.`, `.a`, `.a.b`
+    FieldAccessor {
+        fields: Vec<String>,
+    },
+    /// `..`
+    Recurse,
+    /// `1`, `"hi"`, `true`, `null`
+    Literal(JsonValue),
+    /// `"a \(.b) c"`, or `@csv "a \(.b) c"` to format each interpolated value
+    StringInterpolation {
+        format: Option<String>,
+        parts: Vec<StringPart>,
+    },
+    /// `@csv`, `@base64`, ... on their own
+    Format(String),
+    /// `t[i]`, `t.a` where `t` isn't itself a field accessor, `t."a"`
+    Index {
+        target: Box<Filter>,
+        index: Box<Pipeline>,
+    },
+    /// `t[from:to]`, either end optional
+    Slice {
+        target: Box<Filter>,
+        from: Option<Box<Pipeline>>,
+        to: Option<Box<Pipeline>>,
+    },
+    /// `t[]`
+    Iterate {
+        target: Box<Filter>,
+    },
+    /// `try body catch handler`. `body?` is a try with no handler
+    Try {
+        body: Box<Filter>,
+        handler: Option<Box<Filter>>,
+    },
+    /// `(...)`
+    Parens(Box<Pipeline>),
+    /// `[...]`, or `[]`
+    ArrayConstruction(Option<Box<Pipeline>>),
+    /// `{a, "b": 1, (.c): .d}`. a missing value means "look the key up in the input"
+    ObjectConstruction(Vec<(ObjectKey, Option<Pipeline>)>),
+    /// `a, b`
+    Comma(Vec<Filter>),
+    /// `a // b`
+    Alternative(Box<Filter>, Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    /// `-a`
+    Negate(Box<Filter>),
+    /// `a + b`, `a == b`, ...
+    Operation {
+        op: Operator,
+        lhs: Box<Filter>,
+        rhs: Box<Filter>,
+    },
+    /// `a = b`, `a |= b`, `a += b`, ...
+    Assign {
+        op: AssignOp,
+        lhs: Box<Filter>,
+        rhs: Box<Filter>,
+    },
+    /// `if cond then a elif cond2 then b else c end`. each `elif` nests in `otherwise`
+    If {
+        cond: Box<Pipeline>,
+        then: Box<Pipeline>,
+        otherwise: Option<Box<Pipeline>>,
+    },
+    /// `$name`
+    Variable(String),
+    /// `source as $name | body`. the body runs once per output of the source
+    Bind {
+        source: Box<Filter>,
+        name: String,
+        body: Box<Pipeline>,
+    },
+    /// `reduce source as $name (init; update)`
+    Reduce {
+        source: Box<Filter>,
+        name: String,
+        init: Box<Pipeline>,
+        update: Box<Pipeline>,
+    },
+    /// `foreach source as $name (init; update; extract)`, extract optional
+    Foreach {
+        source: Box<Filter>,
+        name: String,
+        init: Box<Pipeline>,
+        update: Box<Pipeline>,
+        extract: Option<Box<Pipeline>>,
+    },
+    FunctionCall {
+        name: String,
+        args: Vec<Pipeline>,
+        /// where the regex builtins keep the regex they compiled at this call site
+        cache: RegexCache,
+    },
+    /// `def name(params): body; rest`. the function can be called in its own
+    /// body and in the rest of the pipeline, which is what this filter outputs
+    Define {
+        def: Box<FunctionDef>,
+        rest: Box<Pipeline>,
+    },
+}
+
+/// `def name: body;` or `def name(f; $x): body;`
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionDef {
+    pub name: String,
+    /// `f` for a filter argument, `$x` for one whose outputs are bound to `$x`
+    pub params: Vec<String>,
+    pub body: Pipeline,
+}
+
+/// a whole program: the modules it pulls in, then its filter
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    pub directives: Vec<Directive>,
+    pub pipeline: Pipeline,
+}
+
+/// a module file: its metadata, the modules it pulls in, then its functions
+#[derive(Debug, PartialEq, Clone)]
+pub struct Module {
+    /// from `module {...};`, a constant object
+    pub meta: Option<Pipeline>,
+    pub directives: Vec<Directive>,
+    pub defs: Vec<FunctionDef>,
+}
+
+/// `import "path" as name;`, `import "path" as $name;` or `include "path";`,
+/// each with optional metadata, like `{search: "./lib"}`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Directive {
+    Import {
+        path: String,
+        name: String,
+        /// `as $name`: the module is a JSON file whose values are bound to `$name`
+        data: bool,
+        meta: Option<Pipeline>,
+    },
+    Include {
+        path: String,
+        meta: Option<Pipeline>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StringPart {
+    Literal(String),
+    Interpolation(Pipeline),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ObjectKey {
+    Literal(String),
+    Expr(Pipeline),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AssignOp {
+    /// `=`
+    Set,
+    /// `|=`
+    Update,
+    /// `+=`, `-=`, `*=`, `/=`, `%=`
+    Arithmetic(Operator),
+    /// `//=`
+    Alternative,
 }
 
 impl Pipeline {
-    pub fn apply<'a>(&self, val: &'a JsonValue) -> Option<JsonValue> {
+    /// every function the pipeline calls, as `(name, arity)`, in the order they appear
+    pub(crate) fn calls(&self) -> Vec<(&str, usize)> {
+        let mut calls = vec![];
+        self.visit(&mut |f| {
+            if let Filter::FunctionCall { name, args, .. } = f {
+                calls.push((name.as_str(), args.len()));
+            }
+        });
+        calls
+    }
+
+    /// every `@format` the pipeline uses, without the `@`, in the order they appear
+    pub(crate) fn formats(&self) -> Vec<&str> {
+        let mut formats = vec![];
+        self.visit(&mut |f| match f {
+            Filter::Format(name)
+            | Filter::StringInterpolation {
+                format: Some(name), ..
+            } => formats.push(name.as_str()),
+            _ => {}
+        });
+        formats
+    }
+
+    /// the first function the pipeline calls that isn't defined in it or by
+    /// `defined`, as `(name, arity)`
+    pub(crate) fn undefined_call(
+        &self,
+        defined: &dyn Fn(&str, usize) -> bool,
+    ) -> Option<(&str, usize)> {
+        self.find_undefined(&mut vec![], defined)
+    }
+
+    fn find_undefined<'a>(
+        &'a self,
+        scope: &mut Vec<(&'a str, usize)>,
+        defined: &dyn Fn(&str, usize) -> bool,
+    ) -> Option<(&'a str, usize)> {
         self.filters
             .iter()
-            .fold(Some(val.clone()), |acc, f| f.apply(&acc?))
+            .find_map(|f| f.find_undefined(scope, defined))
     }
-}
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum Filter {
-    FieldAccessor { fields: Vec<String> },
-    FunctionCall { name: String, args: Vec<String> },
-    // Spread {  }
+    /// calls `f` on every filter in the pipeline, outer ones before the ones inside them
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Filter)) {
+        for filter in &self.filters {
+            filter.visit(f);
+        }
+    }
+
+    /// the path the pipeline looks up, if that's all it does: fields and
+    /// indices that don't depend on the input, like `.a.b[0]` or `.a | .["b"]`.
+    /// such a pipeline has exactly one output, what `getpath` would give
+    pub fn constant_path(&self) -> Option<Vec<JsonValue>> {
+        let mut path = vec![];
+        for f in &self.filters {
+            f.collect_path(&mut path)?;
+        }
+        Some(path)
+    }
+
+    /// the key, if the pipeline is one that can index with, like `"a"` or `-1`
+    pub(crate) fn constant_key(&self) -> Option<JsonValue> {
+        match self.filters.as_slice() {
+            [Filter::Literal(k @ (JsonValue::Str(_) | JsonValue::Num(_)))] => Some(k.clone()),
+            [Filter::Negate(n)] => match n.as_ref() {
+                Filter::Literal(JsonValue::Num(n)) => Some(JsonValue::Num(-n)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 impl Filter {
-    // TODO: made this clone everything because the Cows were killing me
-    // the FieldAccessor branch can return a reference (how?), but the function call branch creates data
-
-    pub fn apply<'a>(&self, val: &'a JsonValue) -> Option<JsonValue> {
-        debug!("applying {:?} to {:?}", self, val);
+    fn collect_path(&self, path: &mut Vec<JsonValue>) -> Option<()> {
         match self {
             Filter::FieldAccessor { fields } => {
-                let mut cur = val;
-                for field in fields.iter() {
-                    cur = cur.as_object()?.get(field)?
-                }
-                Some(cur.clone())
+                path.extend(fields.iter().cloned().map(JsonValue::Str));
             }
-            // TODO: improve this
-            Filter::FunctionCall { name, args } => match name.as_str() {
-                "length" => match val {
-                    // TODO: unsafe "as"
-                    JsonValue::Array(a) => Some(JsonValue::Num(a.len() as f64)),
-                    JsonValue::Object(o) => Some(JsonValue::Num(o.len() as f64)),
-                    _ => panic!("cannot take length of non-array/object"),
-                },
-                "split" => {
-                    let delim = &args[0];
-                    match val {
-                        JsonValue::Str(s) => Some(JsonValue::Array(
-                            s.split(delim)
-                                .map(|e| JsonValue::Str(e.to_string()))
-                                .collect::<Vec<_>>(),
-                        )),
-                        _ => panic!("cannot split non-string"),
-                    }
-                }
-                _ => panic!("unknown function {}", name),
+            Filter::Index { target, index } => {
+                target.collect_path(path)?;
+                path.push(index.constant_key()?);
+            }
+            Filter::Parens(p) => path.extend(p.constant_path()?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn visit<'a>(&'a self, f: &mut impl FnMut(&'a Filter)) {
+        f(self);
+        for child in self.children() {
+            match child {
+                Node::Filter(filter) => filter.visit(f),
+                Node::Pipeline(p) => p.visit(f),
+            }
+        }
+    }
+
+    /// the first function the filter calls that isn't in `scope` or `defined`
+    fn find_undefined<'a>(
+        &'a self,
+        scope: &mut Vec<(&'a str, usize)>,
+        defined: &dyn Fn(&str, usize) -> bool,
+    ) -> Option<(&'a str, usize)> {
+        match self {
+            Filter::Define { def, rest } => {
+                let found = def.find_undefined(scope, defined);
+                let found = found.or_else(|| rest.find_undefined(scope, defined));
+                scope.pop();
+                found
+            }
+            Filter::FunctionCall { name, args, .. }
+                if !scope.contains(&(name.as_str(), args.len())) && !defined(name, args.len()) =>
+            {
+                Some((name, args.len()))
+            }
+            _ => self.children().into_iter().find_map(|child| match child {
+                Node::Filter(f) => f.find_undefined(scope, defined),
+                Node::Pipeline(p) => p.find_undefined(scope, defined),
+            }),
+        }
+    }
+
+    /// the filters and pipelines directly inside this one
+    fn children<'a>(&'a self) -> Vec<Node<'a>> {
+        let filter = |f: &'a Filter| Node::Filter(f);
+        let pipeline = |p: &'a Pipeline| Node::Pipeline(p);
+        match self {
+            Filter::FieldAccessor { .. }
+            | Filter::Recurse
+            | Filter::Literal(_)
+            | Filter::Format(_)
+            | Filter::Variable(_) => vec![],
+            Filter::StringInterpolation { parts, .. } => parts
+                .iter()
+                .filter_map(|part| match part {
+                    StringPart::Interpolation(p) => Some(pipeline(p)),
+                    StringPart::Literal(_) => None,
+                })
+                .collect(),
+            Filter::Index { target, index } => vec![filter(target), pipeline(index)],
+            Filter::Slice { target, from, to } => [from, to]
+                .into_iter()
+                .flatten()
+                .map(|p| pipeline(p))
+                .fold(vec![filter(target)], |mut v, p| {
+                    v.push(p);
+                    v
+                }),
+            Filter::Iterate { target } | Filter::Negate(target) => vec![filter(target)],
+            Filter::Try { body, handler } => {
+                let mut v = vec![filter(body)];
+                v.extend(handler.as_deref().map(filter));
+                v
+            }
+            Filter::Parens(p) => vec![pipeline(p)],
+            Filter::ArrayConstruction(p) => p.as_deref().map(pipeline).into_iter().collect(),
+            Filter::ObjectConstruction(entries) => entries
+                .iter()
+                .flat_map(|(key, value)| {
+                    let key = match key {
+                        ObjectKey::Expr(p) => Some(pipeline(p)),
+                        ObjectKey::Literal(_) => None,
+                    };
+                    key.into_iter().chain(value.as_ref().map(pipeline))
+                })
+                .collect(),
+            Filter::Comma(filters) => filters.iter().map(filter).collect(),
+            Filter::Alternative(lhs, rhs)
+            | Filter::And(lhs, rhs)
+            | Filter::Or(lhs, rhs)
+            | Filter::Operation { lhs, rhs, .. }
+            | Filter::Assign { lhs, rhs, .. } => vec![filter(lhs), filter(rhs)],
+            Filter::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let mut v = vec![pipeline(cond), pipeline(then)];
+                v.extend(otherwise.as_deref().map(pipeline));
+                v
+            }
+            Filter::Bind { source, body, .. } => vec![filter(source), pipeline(body)],
+            Filter::Reduce {
+                source,
+                init,
+                update,
+                ..
+            } => vec![filter(source), pipeline(init), pipeline(update)],
+            Filter::Foreach {
+                source,
+                init,
+                update,
+                extract,
+                ..
+            } => {
+                let mut v = vec![filter(source), pipeline(init), pipeline(update)];
+                v.extend(extract.as_deref().map(pipeline));
+                v
+            }
+            Filter::FunctionCall { args, .. } => args.iter().map(pipeline).collect(),
+            Filter::Define { def, rest } => vec![pipeline(&def.body), pipeline(rest)],
+        }
+    }
+}
+
+/// something inside a filter
+enum Node<'a> {
+    Filter(&'a Filter),
+    Pipeline(&'a Pipeline),
+}
+
+impl FunctionDef {
+    /// the first function the definition's body calls that isn't defined. the
+    /// function itself is left in `scope` for whatever comes after it
+    fn find_undefined<'a>(
+        &'a self,
+        scope: &mut Vec<(&'a str, usize)>,
+        defined: &dyn Fn(&str, usize) -> bool,
+    ) -> Option<(&'a str, usize)> {
+        scope.push((&self.name, self.params.len()));
+        let depth = scope.len();
+        // `$x` can be called as `x` too
+        scope.extend(self.params.iter().map(|p| (p.trim_start_matches('$'), 0)));
+        let found = self.body.find_undefined(scope, defined);
+        scope.truncate(depth);
+        found
+    }
+}
+
+impl Module {
+    /// the first function the module calls that isn't defined in it or by `defined`
+    pub(crate) fn undefined_call(
+        &self,
+        defined: &dyn Fn(&str, usize) -> bool,
+    ) -> Option<(&str, usize)> {
+        let mut scope = vec![];
+        self.defs
+            .iter()
+            .find_map(|def| def.find_undefined(&mut scope, defined))
+    }
+}
+
+#[derive(Clone)]
+enum Suffix {
+    Field(String),
+    Index(Pipeline),
+    Slice(Option<Pipeline>, Option<Pipeline>),
+    Iterate,
+    Optional,
+}
+
+impl Suffix {
+    fn apply(self, target: Filter) -> Filter {
+        match (self, target) {
+            (Suffix::Field(f), Filter::FieldAccessor { mut fields }) => {
+                fields.push(f);
+                Filter::FieldAccessor { fields }
+            }
+            (Suffix::Field(f), target) => Filter::Index {
+                target: Box::new(target),
+                index: Box::new(literal_pipeline(JsonValue::Str(f))),
+            },
+            (Suffix::Index(index), target) => Filter::Index {
+                target: Box::new(target),
+                index: Box::new(index),
+            },
+            (Suffix::Slice(from, to), target) => Filter::Slice {
+                target: Box::new(target),
+                from: from.map(Box::new),
+                to: to.map(Box::new),
+            },
+            (Suffix::Iterate, target) => Filter::Iterate {
+                target: Box::new(target),
+            },
+            (Suffix::Optional, body) => Filter::Try {
+                body: Box::new(body),
+                handler: None,
             },
         }
     }
 }
 
+fn literal_pipeline(v: JsonValue) -> Pipeline {
+    Pipeline {
+        filters: vec![Filter::Literal(v)],
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "and", "or", "if", "then", "elif", "else", "end", "try", "catch", "as", "reduce", "foreach",
+    "import", "include", "module", "def",
+];
+
+/// whitespace, including newlines, and `#` comments running to the end of the line
 fn sp<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, &'a str, E> {
-    take_while(move |c| "\t ".contains(c))(i)
+    recognize(many0_count(alt((
+        take_while1(|c| " \t\r\n".contains(c)),
+        recognize(pair(char('#'), take_while(|c| c != '\n'))),
+    ))))(i)
+}
+
+/// wraps a parser so it skips the whitespace around it
+fn ws<'a, O, E: ParseError<&'a str> + ContextError<&'a str>, F>(
+    inner: F,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O, E>,
+{
+    delimited(opt(sp), inner, opt(sp))
 }
 
 fn identifier<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, &'a str, E> {
-    context("identifier", alphanumeric1)(i)
+    context(
+        "identifier",
+        recognize(pair(
+            alt((alpha1, tag("_"))),
+            many0(alt((alphanumeric1, tag("_")))),
+        )),
+    )(i)
+}
+
+fn keyword<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    kw: &'static str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str, E> {
+    ws(verify(identifier, move |s: &str| s == kw))
+}
+
+fn number<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, f64, E> {
+    context(
+        "number",
+        map(
+            recognize(tuple((
+                digit1,
+                opt(pair(char('.'), digit0)),
+                opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+            ))),
+            // anything recognized above is a valid float
+            |s: &str| s.parse().unwrap(),
+        ),
+    )(i)
+}
+
+fn hex4<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, u32, E> {
+    map(
+        take_while_m_n(4, 4, |c: char| c.is_ascii_hexdigit()),
+        // four hex digits always fit
+        |h| u32::from_str_radix(h, 16).unwrap(),
+    )(i)
+}
+
+/// `\uXXXX`, including utf-16 surrogate pairs
+fn unicode_escape<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, char, E> {
+    map_opt(
+        pair(preceded(char('u'), hex4), opt(preceded(tag("\\u"), hex4))),
+        |(hi, lo)| match (hi, lo) {
+            (0xD800..=0xDBFF, Some(lo @ 0xDC00..=0xDFFF)) => {
+                char::from_u32(0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00))
+            }
+            (_, None) => char::from_u32(hi),
+            _ => None,
+        },
+    )(i)
+}
+
+fn escape<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, char, E> {
+    preceded(
+        char('\\'),
+        alt((
+            value('"', char('"')),
+            value('\\', char('\\')),
+            value('/', char('/')),
+            value('\n', char('n')),
+            value('\t', char('t')),
+            value('\r', char('r')),
+            value('\u{8}', char('b')),
+            value('\u{c}', char('f')),
+            unicode_escape,
+        )),
+    )(i)
+}
+
+fn string_body<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Vec<StringPart>, E> {
+    fold_many0(
+        alt((
+            map(is_not("\"\\"), |s: &str| StringPart::Literal(s.to_owned())),
+            map(
+                delimited(tag("\\("), cut(pipeline), cut(char(')'))),
+                StringPart::Interpolation,
+            ),
+            map(escape, |c| StringPart::Literal(c.to_string())),
+        )),
+        Vec::new,
+        |mut acc: Vec<StringPart>, part| {
+            match (acc.last_mut(), part) {
+                (Some(StringPart::Literal(s)), StringPart::Literal(more)) => s.push_str(&more),
+                (_, part) => acc.push(part),
+            }
+            acc
+        },
+    )(i)
+}
+
+fn string<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Vec<StringPart>, E> {
+    context(
+        "string",
+        preceded(char('"'), cut(terminated(string_body, char('"')))),
+    )(i)
+}
+
+/// the text of a string with nothing interpolated into it
+fn plain_string(parts: &[StringPart]) -> Option<String> {
+    parts
+        .iter()
+        .try_fold(String::new(), |mut acc, part| match part {
+            StringPart::Literal(s) => {
+                acc.push_str(s);
+                Some(acc)
+            }
+            StringPart::Interpolation(_) => None,
+        })
+}
+
+fn string_literal<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, String, E> {
+    map_opt(string, |parts| plain_string(&parts))(i)
+}
+
+/// a string with nothing interpolated is a literal, unless it has a format,
+/// which is kept to be checked even though there's nothing to apply it to
+fn string_filter(format: Option<&str>, parts: Vec<StringPart>) -> Filter {
+    match plain_string(&parts) {
+        Some(s) if format.is_none() => Filter::Literal(JsonValue::Str(s)),
+        _ => Filter::StringInterpolation {
+            format: format.map(str::to_owned),
+            parts,
+        },
+    }
+}
+
+fn format<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    context(
+        "format",
+        map(
+            pair(
+                preceded(tag("@"), cut(identifier)),
+                opt(preceded(sp, string)),
+            ),
+            |(name, parts)| match parts {
+                Some(parts) => string_filter(Some(name), parts),
+                None => Filter::Format(name.to_owned()),
+            },
+        ),
+    )(i)
 }
 
 fn field_accessor<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
@@ -96,82 +697,763 @@ fn field_accessor_chain<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     context("field_accessor_chain", many1(field_accessor))(i)
 }
 
+/// a function's name, or `lib::name` for one imported from `lib`
 fn function_name<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, &'a str, E> {
-    context("function_name", identifier)(i)
+    context(
+        "function_name",
+        verify(
+            recognize(pair(identifier, opt(pair(tag("::"), identifier)))),
+            |s: &str| !KEYWORDS.contains(&s),
+        ),
+    )(i)
 }
 
-// TODO: should be able to / have to put args in "quotes" unless numeric idk lol. maybe arg is a JsonValue?
-// but also the filter itself should be able to be a JsonValue so idk
-fn function_arg<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+fn variable<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, &'a str, E> {
-    context("function_arg", identifier)(i)
+    // `$name::name` is how jq 1.6 names the values of `import "path" as $name;`
+    context(
+        "variable",
+        preceded(
+            tag("$"),
+            cut(recognize(pair(
+                identifier,
+                opt(pair(tag("::"), identifier)),
+            ))),
+        ),
+    )(i)
+}
+
+fn function_arg<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Pipeline, E> {
+    context("function_arg", pipeline)(i)
 }
 
 fn function_args<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
-) -> IResult<&'a str, Vec<&'a str>, E> {
-    context(
-        "function_args",
-        separated_list0(delimited(opt(sp), tag(","), opt(sp)), function_arg),
-    )(i)
+) -> IResult<&'a str, Vec<Pipeline>, E> {
+    context("function_args", separated_list1(ws(tag(";")), function_arg))(i)
 }
 
-// TODO: "()" should be optional
 fn function_call<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
-) -> IResult<&'a str, (&'a str, Vec<&'a str>), E> {
+) -> IResult<&'a str, (&'a str, Vec<Pipeline>), E> {
     context(
         "function_call",
-        tuple((function_name, delimited(tag("("), function_args, tag(")")))),
+        pair(
+            function_name,
+            map(
+                opt(delimited(tag("("), cut(function_args), cut(char(')')))),
+                Option::unwrap_or_default,
+            ),
+        ),
     )(i)
 }
 
-fn filter<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+fn array_construction<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, Filter, E> {
+    context(
+        "array",
+        map(
+            delimited(tag("["), opt(pipeline), cut(ws(char(']')))),
+            |p| Filter::ArrayConstruction(p.map(Box::new)),
+        ),
+    )(i)
+}
+
+/// object values can be pipelines, but not comma-separated lists
+fn object_value<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Pipeline, E> {
+    map(separated_list1(pipe_separator, alternative), |filters| {
+        Pipeline { filters }
+    })(i)
+}
+
+fn object_entry<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, (ObjectKey, Option<Pipeline>), E> {
+    ws(alt((
+        separated_pair(
+            map(
+                delimited(tag("("), pipeline, cut(char(')'))),
+                ObjectKey::Expr,
+            ),
+            cut(ws(char(':'))),
+            cut(map(object_value, Some)),
+        ),
+        pair(
+            alt((
+                map(identifier, |s: &str| ObjectKey::Literal(s.to_owned())),
+                map(string, |parts| match string_filter(None, parts) {
+                    Filter::Literal(JsonValue::Str(s)) => ObjectKey::Literal(s),
+                    f => ObjectKey::Expr(Pipeline { filters: vec![f] }),
+                }),
+            )),
+            opt(preceded(ws(tag(":")), cut(object_value))),
+        ),
+    )))(i)
+}
+
+fn object_construction<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    context(
+        "object",
+        map(
+            delimited(
+                tag("{"),
+                separated_list0(tag(","), object_entry),
+                cut(ws(char('}'))),
+            ),
+            Filter::ObjectConstruction,
+        ),
+    )(i)
+}
+
+fn if_then_else<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    context(
+        "if",
+        map(
+            preceded(
+                keyword("if"),
+                cut(tuple((
+                    pipeline,
+                    preceded(keyword("then"), pipeline),
+                    many0(pair(
+                        preceded(keyword("elif"), pipeline),
+                        preceded(keyword("then"), pipeline),
+                    )),
+                    opt(preceded(keyword("else"), pipeline)),
+                    keyword("end"),
+                ))),
+            ),
+            |(cond, then, elifs, otherwise, _)| {
+                let otherwise =
+                    elifs
+                        .into_iter()
+                        .rev()
+                        .fold(otherwise, |otherwise, (cond, then)| {
+                            Some(Pipeline {
+                                filters: vec![Filter::If {
+                                    cond: Box::new(cond),
+                                    then: Box::new(then),
+                                    otherwise: otherwise.map(Box::new),
+                                }],
+                            })
+                        });
+                Filter::If {
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    otherwise: otherwise.map(Box::new),
+                }
+            },
+        ),
+    )(i)
+}
+
+fn try_catch<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    context(
+        "try",
+        map(
+            preceded(
+                keyword("try"),
+                cut(pair(postfix, opt(preceded(keyword("catch"), postfix)))),
+            ),
+            |(body, handler)| Filter::Try {
+                body: Box::new(body),
+                handler: handler.map(Box::new),
+            },
+        ),
+    )(i)
+}
+
+/// `reduce` and `foreach`, which only differ in what follows the update
+fn fold<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    let (i, kw) = alt((keyword("reduce"), keyword("foreach")))(i)?;
+    let (i, (source, name, _, init, update)) = cut(tuple((
+        postfix,
+        preceded(keyword("as"), ws(variable)),
+        tag("("),
+        pipeline,
+        preceded(tag(";"), pipeline),
+    )))(i)?;
+    let source = Box::new(source);
+    let name = name.to_owned();
+    let init = Box::new(init);
+    let update = Box::new(update);
+    if kw == "reduce" {
+        let (i, _) = cut(char(')'))(i)?;
+        return Ok((
+            i,
+            Filter::Reduce {
+                source,
+                name,
+                init,
+                update,
+            },
+        ));
+    }
+    let (i, extract) = cut(terminated(opt(preceded(tag(";"), pipeline)), char(')')))(i)?;
+    Ok((
+        i,
+        Filter::Foreach {
+            source,
+            name,
+            init,
+            update,
+            extract: extract.map(Box::new),
+        },
+    ))
+}
+
+fn term<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    ws(alt((
+        value(Filter::Recurse, tag("..")),
+        map(number, |n| Filter::Literal(JsonValue::Num(n))),
+        map(string, |parts| string_filter(None, parts)),
+        format,
+        map(field_accessor_chain, |v| Filter::FieldAccessor {
+            fields: v.into_iter().map(|s| s.to_owned()).collect(),
+        }),
+        map(preceded(tag("."), string_literal), |s| {
+            Filter::FieldAccessor { fields: vec![s] }
+        }),
+        value(Filter::FieldAccessor { fields: vec![] }, tag(".")),
+        map(delimited(tag("("), pipeline, cut(ws(char(')')))), |p| {
+            Filter::Parens(Box::new(p))
+        }),
+        array_construction,
+        object_construction,
+        if_then_else,
+        try_catch,
+        context("fold", fold),
+        map(variable, |name| Filter::Variable(name.to_owned())),
+        value(Filter::Literal(JsonValue::Null), keyword("null")),
+        value(Filter::Literal(JsonValue::Boolean(true)), keyword("true")),
+        value(Filter::Literal(JsonValue::Boolean(false)), keyword("false")),
+        map(function_call, |(name, args)| Filter::FunctionCall {
+            name: name.to_owned(),
+            args,
+            cache: RegexCache::default(),
+        }),
+    )))(i)
+}
+
+fn bracket_suffix<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Suffix, E> {
     delimited(
-        opt(sp),
+        tag("["),
         alt((
-            // special case: '.'
-            map(all_consuming(tag(".")), |_| Filter::FieldAccessor {
-                fields: vec![],
-            }),
-            map(field_accessor_chain, |v| Filter::FieldAccessor {
-                fields: v.into_iter().map(|s| s.to_owned()).collect(),
-            }),
-            map(function_call, |(name, args)| Filter::FunctionCall {
-                name: name.to_owned(),
-                args: args.into_iter().map(|s| s.to_owned()).collect(),
+            value(Suffix::Iterate, peek(ws(tag("]")))),
+            map(
+                separated_pair(opt(pipeline), ws(tag(":")), opt(pipeline)),
+                |(from, to)| Suffix::Slice(from, to),
+            ),
+            map(pipeline, Suffix::Index),
+        )),
+        cut(ws(char(']'))),
+    )(i)
+}
+
+fn suffix<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Suffix, E> {
+    terminated(
+        alt((
+            map(preceded(tag("."), identifier), |s| {
+                Suffix::Field(s.to_owned())
             }),
+            map(preceded(tag("."), string_literal), Suffix::Field),
+            preceded(opt(tag(".")), bracket_suffix),
+            value(Suffix::Optional, tag("?")),
         )),
         opt(sp),
     )(i)
 }
 
-fn pipeline<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+fn postfix<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    let (mut i, mut filter) = term(i)?;
+    loop {
+        match suffix::<E>(i) {
+            Ok((rest, s)) => {
+                filter = s.apply(filter);
+                i = rest;
+            }
+            Err(nom::Err::Error(_)) => return Ok((i, filter)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// `source as $name | body`. as in jq the body takes in the rest of the
+/// pipeline, so `1 + 2 as $x | $x` is `1 + (2 as $x | $x)`
+fn binding<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
-) -> IResult<&'a str, Vec<Filter>, E> {
+) -> IResult<&'a str, Filter, E> {
+    let (i, source) = postfix(i)?;
+    match keyword::<E>("as")(i) {
+        Ok((i, _)) => {
+            let (i, (name, body)) = cut(pair(ws(variable), preceded(pipe_separator, pipeline)))(i)?;
+            Ok((
+                i,
+                Filter::Bind {
+                    source: Box::new(source),
+                    name: name.to_owned(),
+                    body: Box::new(body),
+                },
+            ))
+        }
+        Err(nom::Err::Error(_)) => Ok((i, source)),
+        Err(e) => Err(e),
+    }
+}
+
+fn unary<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    alt((
+        map(preceded(ws(tag("-")), unary), |f| {
+            Filter::Negate(Box::new(f))
+        }),
+        binding,
+    ))(i)
+}
+
+fn operation(op: Operator, lhs: Filter, rhs: Filter) -> Filter {
+    Filter::Operation {
+        op,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+/// a left-associative chain of binary operators, all of the same precedence
+fn binary_chain<'a, E: ParseError<&'a str> + ContextError<&'a str>, P, O, T>(
+    mut operand: P,
+    mut op: O,
+    make: fn(T, Filter, Filter) -> Filter,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Filter, E>
+where
+    P: FnMut(&'a str) -> IResult<&'a str, Filter, E>,
+    O: FnMut(&'a str) -> IResult<&'a str, T, E>,
+{
+    move |i: &'a str| {
+        let (mut i, mut acc) = operand(i)?;
+        loop {
+            let (rest, op) = match op(i) {
+                Ok(r) => r,
+                Err(nom::Err::Error(_)) => return Ok((i, acc)),
+                Err(e) => return Err(e),
+            };
+            let (rest, rhs) = cut(&mut operand)(rest)?;
+            acc = make(op, acc, rhs);
+            i = rest;
+        }
+    }
+}
+
+fn multiplicative<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    binary_chain(
+        unary,
+        ws(alt((
+            value(Operator::Mul, terminated(tag("*"), not(tag("=")))),
+            value(Operator::Div, terminated(tag("/"), not(one_of("/=")))),
+            value(Operator::Mod, terminated(tag("%"), not(tag("=")))),
+        ))),
+        operation,
+    )(i)
+}
+
+fn additive<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    binary_chain(
+        multiplicative,
+        ws(alt((
+            value(Operator::Add, terminated(tag("+"), not(tag("=")))),
+            value(Operator::Sub, terminated(tag("-"), not(tag("=")))),
+        ))),
+        operation,
+    )(i)
+}
+
+fn comparison<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    map(
+        pair(
+            additive,
+            opt(pair(
+                ws(alt((
+                    value(Operator::Eq, tag("==")),
+                    value(Operator::Ne, tag("!=")),
+                    value(Operator::Le, tag("<=")),
+                    value(Operator::Ge, tag(">=")),
+                    value(Operator::Lt, tag("<")),
+                    value(Operator::Gt, tag(">")),
+                ))),
+                cut(additive),
+            )),
+        ),
+        |(lhs, rhs)| match rhs {
+            Some((op, rhs)) => operation(op, lhs, rhs),
+            None => lhs,
+        },
+    )(i)
+}
+
+fn and<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    binary_chain(comparison, keyword("and"), |_, lhs, rhs| {
+        Filter::And(Box::new(lhs), Box::new(rhs))
+    })(i)
+}
+
+fn or<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    binary_chain(and, keyword("or"), |_, lhs, rhs| {
+        Filter::Or(Box::new(lhs), Box::new(rhs))
+    })(i)
+}
+
+fn assign_op<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, AssignOp, E> {
+    ws(alt((
+        value(AssignOp::Update, tag("|=")),
+        value(AssignOp::Arithmetic(Operator::Add), tag("+=")),
+        value(AssignOp::Arithmetic(Operator::Sub), tag("-=")),
+        value(AssignOp::Arithmetic(Operator::Mul), tag("*=")),
+        value(AssignOp::Arithmetic(Operator::Div), tag("/=")),
+        value(AssignOp::Arithmetic(Operator::Mod), tag("%=")),
+        value(AssignOp::Alternative, tag("//=")),
+        value(AssignOp::Set, terminated(tag("="), not(tag("=")))),
+    )))(i)
+}
+
+/// assignments don't chain: `.a = .b = 1` is a syntax error, as in jq
+fn assignment<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    map(
+        pair(or, opt(pair(assign_op, cut(or)))),
+        |(lhs, rhs)| match rhs {
+            Some((op, rhs)) => Filter::Assign {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            },
+            None => lhs,
+        },
+    )(i)
+}
+
+fn alternative<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    map(
+        pair(
+            assignment,
+            opt(preceded(
+                ws(terminated(tag("//"), not(tag("=")))),
+                cut(alternative),
+            )),
+        ),
+        |(lhs, rhs)| match rhs {
+            Some(rhs) => Filter::Alternative(Box::new(lhs), Box::new(rhs)),
+            None => lhs,
+        },
+    )(i)
+}
+
+fn filter<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Filter, E> {
+    map(separated_list1(ws(tag(",")), alternative), |mut v| {
+        if v.len() == 1 {
+            v.remove(0)
+        } else {
+            Filter::Comma(v)
+        }
+    })(i)
+}
+
+fn pipe_separator<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, &'a str, E> {
+    ws(terminated(tag("|"), not(tag("="))))(i)
+}
+
+/// a parameter of a function definition: `f`, or `$x`
+fn param<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, String, E> {
+    ws(alt((
+        map(recognize(preceded(tag("$"), identifier)), str::to_owned),
+        map(function_name, str::to_owned),
+    )))(i)
+}
+
+fn function_def<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, FunctionDef, E> {
     context(
-        "pipeline",
-        separated_list1(delimited(opt(sp), tag("|"), opt(sp)), filter),
+        "def",
+        map(
+            preceded(
+                keyword("def"),
+                cut(tuple((
+                    ws(identifier),
+                    map(
+                        opt(delimited(
+                            tag("("),
+                            separated_list1(tag(";"), param),
+                            char(')'),
+                        )),
+                        Option::unwrap_or_default,
+                    ),
+                    preceded(ws(char(':')), pipeline),
+                    ws(char(';')),
+                ))),
+            ),
+            |(name, params, body, _)| FunctionDef {
+                name: name.to_owned(),
+                params,
+                body,
+            },
+        ),
     )(i)
 }
 
+/// filters separated by pipes. a function definition takes in the rest of the
+/// pipeline, like a binding does
+fn pipeline<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Pipeline, E> {
+    context("pipeline", |mut i| {
+        let mut filters = vec![];
+        // where the last filter ended, before any pipe after it
+        let mut end = i;
+        loop {
+            if let (rest, Some(def)) = opt(function_def)(i)? {
+                let (rest, body) = cut(pipeline)(rest)?;
+                filters.push(Filter::Define {
+                    def: Box::new(def),
+                    rest: Box::new(body),
+                });
+                return Ok((rest, Pipeline { filters }));
+            }
+            // like `separated_list1`, a pipe with nothing after it is left unparsed
+            let (rest, f) = match filter::<E>(i) {
+                Ok(parsed) => parsed,
+                Err(nom::Err::Error(_)) if !filters.is_empty() => {
+                    return Ok((end, Pipeline { filters }))
+                }
+                Err(e) => return Err(e),
+            };
+            filters.push(f);
+            end = rest;
+            match pipe_separator::<E>(rest) {
+                Ok((after, _)) => i = after,
+                Err(nom::Err::Error(_)) => return Ok((rest, Pipeline { filters })),
+                Err(e) => return Err(e),
+            }
+        }
+    })(i)
+}
+
 fn root<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     i: &'a str,
 ) -> IResult<&'a str, Pipeline, E> {
-    delimited(
-        opt(sp),
-        map(pipeline, |filters| Pipeline { filters }),
-        opt(sp),
+    delimited(opt(sp), pipeline, opt(sp))(i)
+}
+
+/// metadata for an import, which is a constant object
+fn module_meta<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Option<Pipeline>, E> {
+    opt(map(ws(object_construction), |f| Pipeline {
+        filters: vec![f],
+    }))(i)
+}
+
+fn directive<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Directive, E> {
+    let import = map(
+        tuple((
+            preceded(keyword("import"), cut(ws(string_literal))),
+            preceded(
+                cut(keyword("as")),
+                cut(ws(pair(opt(char('$')), identifier))),
+            ),
+            module_meta,
+        )),
+        |(path, (data, name), meta)| Directive::Import {
+            path,
+            name: name.to_owned(),
+            data: data.is_some(),
+            meta,
+        },
+    );
+    let include = map(
+        pair(
+            preceded(keyword("include"), cut(ws(string_literal))),
+            module_meta,
+        ),
+        |(path, meta)| Directive::Include { path, meta },
+    );
+    context(
+        "directive",
+        terminated(alt((import, include)), cut(ws(char(';')))),
+    )(i)
+}
+
+fn program<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Program, E> {
+    map(
+        delimited(opt(sp), pair(many0(directive), pipeline), opt(sp)),
+        |(directives, pipeline)| Program {
+            directives,
+            pipeline,
+        },
+    )(i)
+}
+
+fn module<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Module, E> {
+    let meta = context(
+        "module",
+        delimited(
+            keyword("module"),
+            cut(map(ws(object_construction), |f| Pipeline {
+                filters: vec![f],
+            })),
+            cut(ws(char(';'))),
+        ),
+    );
+    map(
+        delimited(
+            opt(sp),
+            tuple((opt(meta), many0(directive), many0(ws(function_def)))),
+            opt(sp),
+        ),
+        |(meta, directives, defs)| Module {
+            meta,
+            directives,
+            defs,
+        },
     )(i)
 }
 
-pub fn parse_filter<'a>(i: &'a str) -> Result<Pipeline, VerboseError<&'a str>> {
-    let filter = all_consuming::<_, _, VerboseError<&'a str>, _>(root)(i)
+/// parses a module file: `module {...};`, then its directives, then nothing
+/// but function definitions
+pub fn parse_module(i: &str) -> Result<Module, VerboseError<&str>> {
+    let module = all_consuming::<_, _, VerboseError<&str>, _>(module)(i)
+        .finish()?
+        .1;
+    Ok(module)
+}
+
+impl Program {
+    /// whether a module the program pulls in could define `name`. that isn't
+    /// known until the module is loaded
+    pub(crate) fn may_import(&self, name: &str) -> bool {
+        self.directives.iter().any(|d| match d {
+            Directive::Include { .. } => true,
+            Directive::Import {
+                name: prefix,
+                data: false,
+                ..
+            } => name
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.starts_with("::")),
+            Directive::Import { .. } => false,
+        })
+    }
+}
+
+/// parses a program that may start with `import` and `include` directives
+pub fn parse_program(i: &str) -> Result<Program, VerboseError<&str>> {
+    let program = all_consuming::<_, _, VerboseError<&str>, _>(program)(i)
+        .finish()?
+        .1;
+    Ok(program)
+}
+
+/// parses a program and checks that every function it calls is defined, and
+/// every format it uses exists
+pub fn compile(i: &str) -> Result<Program, Diagnostic> {
+    compile_with(i, &|_, _| false)
+}
+
+/// like `compile`, with the functions `defined` says exist callable as well as
+/// the builtins, like the ones in `~/.jq`
+pub fn compile_with(i: &str, defined: &dyn Fn(&str, usize) -> bool) -> Result<Program, Diagnostic> {
+    let program = parse_program(i).map_err(|e| diagnostic::syntax_error(i, &e))?;
+    // calls that could be to a module's functions are checked once it's loaded
+    let defined = |name: &str, arity| {
+        builtins::is_defined(name, arity) || defined(name, arity) || program.may_import(name)
+    };
+    if let Some((name, arity)) = program.pipeline.undefined_call(&defined) {
+        return Err(diagnostic::undefined_function(i, name, arity));
+    }
+    check_formats(i, program.pipeline.formats())?;
+    Ok(program)
+}
+
+/// parses a module, checking its calls against the builtins and `defined`,
+/// which are the functions its imports define
+pub(crate) fn compile_module(
+    i: &str,
+    defined: &dyn Fn(&str, usize) -> bool,
+) -> Result<Module, Diagnostic> {
+    let module = parse_module(i).map_err(|e| diagnostic::syntax_error(i, &e))?;
+    let defined = |name: &str, arity| builtins::is_defined(name, arity) || defined(name, arity);
+    if let Some((name, arity)) = module.undefined_call(&defined) {
+        return Err(diagnostic::undefined_function(i, name, arity));
+    }
+    check_formats(
+        i,
+        module.defs.iter().flat_map(|d| d.body.formats()).collect(),
+    )?;
+    Ok(module)
+}
+
+fn check_formats(i: &str, names: Vec<&str>) -> Result<(), Diagnostic> {
+    match names
+        .into_iter()
+        .find(|name| !formats::FORMATS.contains(name))
+    {
+        Some(name) => Err(diagnostic::invalid_format(i, name)),
+        None => Ok(()),
+    }
+}
+
+pub fn parse_filter(i: &str) -> Result<Pipeline, VerboseError<&str>> {
+    let filter = all_consuming::<_, _, VerboseError<&str>, _>(root)(i)
         .finish()?
         .1;
     Ok(filter)
@@ -226,7 +1508,8 @@ mod tests {
                 Pipeline {
                     filters: vec![Filter::FunctionCall {
                         name: "hello".into(),
-                        args: vec!["42".into()],
+                        args: vec![literal_pipeline(JsonValue::Num(42.0))],
+                        cache: RegexCache::default(),
                     }],
                 },
             ),
@@ -239,12 +1522,12 @@ mod tests {
                         },
                         Filter::FunctionCall {
                             name: "hello".into(),
-                            args: vec!["42".into()],
+                            args: vec![literal_pipeline(JsonValue::Num(42.0))],
+                            cache: RegexCache::default(),
                         },
                     ],
                 },
             ),
-            // TODO: why this one no worky
             (
                 ". | hello(42)",
                 Pipeline {
@@ -252,11 +1535,92 @@ mod tests {
                         Filter::FieldAccessor { fields: vec![] },
                         Filter::FunctionCall {
                             name: "hello".into(),
-                            args: vec!["42".into()],
+                            args: vec![literal_pipeline(JsonValue::Num(42.0))],
+                            cache: RegexCache::default(),
                         },
                     ],
                 },
             ),
+            (
+                ".a[0]?",
+                Pipeline {
+                    filters: vec![Filter::Try {
+                        body: Box::new(Filter::Index {
+                            target: Box::new(Filter::FieldAccessor {
+                                fields: vec!["a".into()],
+                            }),
+                            index: Box::new(literal_pipeline(JsonValue::Num(0.0))),
+                        }),
+                        handler: None,
+                    }],
+                },
+            ),
+            (
+                r#"setpath(["a"]; 1)"#,
+                Pipeline {
+                    filters: vec![Filter::FunctionCall {
+                        name: "setpath".into(),
+                        args: vec![
+                            Pipeline {
+                                filters: vec![Filter::ArrayConstruction(Some(Box::new(
+                                    literal_pipeline(JsonValue::Str("a".into())),
+                                )))],
+                            },
+                            literal_pipeline(JsonValue::Num(1.0)),
+                        ],
+                        cache: RegexCache::default(),
+                    }],
+                },
+            ),
+            (
+                "1, 2 // 3 + 4 * 5",
+                Pipeline {
+                    filters: vec![Filter::Comma(vec![
+                        Filter::Literal(JsonValue::Num(1.0)),
+                        Filter::Alternative(
+                            Box::new(Filter::Literal(JsonValue::Num(2.0))),
+                            Box::new(operation(
+                                Operator::Add,
+                                Filter::Literal(JsonValue::Num(3.0)),
+                                operation(
+                                    Operator::Mul,
+                                    Filter::Literal(JsonValue::Num(4.0)),
+                                    Filter::Literal(JsonValue::Num(5.0)),
+                                ),
+                            )),
+                        ),
+                    ])],
+                },
+            ),
+            (
+                ".a |= . | .b",
+                Pipeline {
+                    filters: vec![
+                        Filter::Assign {
+                            op: AssignOp::Update,
+                            lhs: Box::new(Filter::FieldAccessor {
+                                fields: vec!["a".into()],
+                            }),
+                            rhs: Box::new(Filter::FieldAccessor { fields: vec![] }),
+                        },
+                        Filter::FieldAccessor {
+                            fields: vec!["b".into()],
+                        },
+                    ],
+                },
+            ),
+            (
+                ". as $x | $x",
+                Pipeline {
+                    filters: vec![Filter::Bind {
+                        source: Box::new(Filter::FieldAccessor { fields: vec![] }),
+                        name: "x".into(),
+                        body: Box::new(Pipeline {
+                            filters: vec![Filter::Variable("x".into())],
+                        }),
+                    }],
+                },
+            ),
         ];
 
         for (input, output) in cases {
@@ -271,4 +1635,106 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn it_finds_constant_paths() {
+        let path = |filter: &str| parse_filter(filter).unwrap().constant_path();
+        assert_eq!(
+            path(r#".a.b[0] | .["c"][-1]"#),
+            Some(vec![
+                JsonValue::Str("a".into()),
+                JsonValue::Str("b".into()),
+                JsonValue::Num(0.0),
+                JsonValue::Str("c".into()),
+                JsonValue::Num(-1.0),
+            ])
+        );
+        assert_eq!(path("."), Some(vec![]));
+        assert_eq!(path("(.a).b"), path(".a.b"));
+        for filter in [".a[]", ".a[.i]", ".a?", ".a, .b", "$x.a", ".[1:2]"] {
+            assert_eq!(path(filter), None, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn it_parses_directives() {
+        let program = r#"import "lib/data" as $d; include "util" {search: "./x"};
+            $d::d"#;
+        let res = parse_program(program);
+        if let Err(e) = &res {
+            eprintln!("errors:\n{}", convert_error(program, e.clone()));
+        }
+        let res = res.expect("no error");
+        assert_eq!(
+            res.directives[0],
+            Directive::Import {
+                path: "lib/data".into(),
+                name: "d".into(),
+                data: true,
+                meta: None,
+            }
+        );
+        assert!(matches!(
+            &res.directives[1],
+            Directive::Include { path, meta: Some(_) } if path == "util"
+        ));
+        assert_eq!(res.pipeline.filters, vec![Filter::Variable("d::d".into())]);
+
+        assert!(parse_program(r#"import "a" as a .a"#).is_err());
+        assert!(parse_filter(r#"import "a" as a; ."#).is_err());
+    }
+
+    #[test]
+    fn it_parses_definitions() {
+        let call = |name: &str| Filter::FunctionCall {
+            name: name.into(),
+            args: vec![],
+            cache: Default::default(),
+        };
+        let res = parse_filter("def f(g; $x): g | $x; f(.; 1) | .a");
+        if let Err(e) = &res {
+            eprintln!(
+                "errors:\n{}",
+                convert_error("def f(g; $x): g | $x; ...", e.clone())
+            );
+        }
+        let filters = res.expect("no error").filters;
+        let (def, rest) = match filters.as_slice() {
+            [Filter::Define { def, rest }] => (def, rest),
+            other => panic!("not a definition: {:?}", other),
+        };
+        assert_eq!(def.name, "f");
+        assert_eq!(def.params, vec!["g".to_string(), "$x".to_string()]);
+        assert_eq!(
+            def.body.filters,
+            vec![call("g"), Filter::Variable("x".into())]
+        );
+        assert_eq!(rest.filters.len(), 2);
+        // a definition takes in the rest of the pipeline, even after a pipe
+        assert_eq!(
+            parse_filter(".a | def f: 1; f, f").unwrap().filters[1],
+            parse_filter("def f: 1; f, f").unwrap().filters[0]
+        );
+
+        let module = parse_module("module {a: 1};\nimport \"b\" as b;\ndef f: b::g;\ndef h: f;");
+        let module = module.expect("no error");
+        assert!(module.meta.is_some());
+        assert_eq!(module.directives.len(), 1);
+        assert_eq!(module.defs.len(), 2);
+        assert_eq!(module.defs[0].body.filters, vec![call("b::g")]);
+
+        assert!(parse_module("def f: 1; .").is_err());
+        assert!(parse_filter("def f: 1;").is_err());
+        assert!(parse_filter("def 1: 1; .").is_err());
+    }
+
+    #[test]
+    fn it_skips_comments_and_newlines() {
+        let program = "# the first field\n.a |\n  # then\n  .b # trailing\n";
+        let res = parse_filter(program);
+        if let Err(e) = &res {
+            eprintln!("errors:\n{}", convert_error(program, e.clone()));
+        }
+        assert_eq!(res.expect("no error"), parse_filter(".a | .b").unwrap());
+    }
 }
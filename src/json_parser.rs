@@ -1,34 +1,201 @@
 use nom::{
     branch::alt,
-    bytes::streaming::{escaped, tag, take_while},
-    character::complete::{alphanumeric1 as alphanumeric, char, one_of},
-    combinator::{cut, map, opt, value},
-    error::{context, ContextError, ParseError},
-    multi::separated_list0,
+    bytes::streaming::{is_not, tag, take, take_while},
+    character::streaming::{char, one_of},
+    combinator::{cut, map, map_opt, opt, value},
+    error::{context, ContextError, ErrorKind, ParseError},
+    multi::many0,
     number::streaming::double,
-    sequence::{delimited, preceded, separated_pair, terminated},
+    sequence::{preceded, separated_pair, terminated},
     IResult,
 };
 
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
+use std::rc::Rc;
 
+use crate::projection::Projection;
+
+/// a JSON value. arrays and objects are shared rather than copied when the
+/// value is cloned, and only copied when one of the sharers changes them
 #[derive(Debug, PartialEq, Clone)]
 pub enum JsonValue {
     Null,
     Str(String),
     Boolean(bool),
     Num(f64),
-    Array(Vec<JsonValue>),
-    Object(BTreeMap<String, JsonValue>),
+    Array(Rc<Vec<JsonValue>>),
+    Object(Rc<BTreeMap<String, JsonValue>>),
+}
+
+impl From<Vec<JsonValue>> for JsonValue {
+    fn from(a: Vec<JsonValue>) -> Self {
+        JsonValue::Array(Rc::new(a))
+    }
+}
+
+impl From<BTreeMap<String, JsonValue>> for JsonValue {
+    fn from(o: BTreeMap<String, JsonValue>) -> Self {
+        JsonValue::Object(Rc::new(o))
+    }
+}
+
+/// an array of the values
+impl FromIterator<JsonValue> for JsonValue {
+    fn from_iter<I: IntoIterator<Item = JsonValue>>(iter: I) -> Self {
+        JsonValue::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+/// an object of the members, the last of any duplicate keys counting
+impl FromIterator<(String, JsonValue)> for JsonValue {
+    fn from_iter<I: IntoIterator<Item = (String, JsonValue)>>(iter: I) -> Self {
+        JsonValue::from(iter.into_iter().collect::<BTreeMap<_, _>>())
+    }
+}
+
+/// a JSON value as parsed, pointing into the input where it can. strings with
+/// no escapes in them are borrowed, and objects keep their keys in input order,
+/// so nothing is copied or sorted until the value is turned into a `JsonValue`
+#[derive(Debug, PartialEq, Clone)]
+pub enum JsonValueRef<'a> {
+    Null,
+    Str(Cow<'a, str>),
+    Boolean(bool),
+    Num(f64),
+    Array(Vec<JsonValueRef<'a>>),
+    /// with any duplicate keys, the last one counts, as with `JsonValue`
+    Object(Members<'a>),
+}
+
+/// an object's keys and values, in the order they're in the input
+pub type Members<'a> = Vec<Member<'a>>;
+
+pub type Member<'a> = (Cow<'a, str>, JsonValueRef<'a>);
+
+static NULL: JsonValueRef<'static> = JsonValueRef::Null;
+
+impl<'a> JsonValueRef<'a> {
+    /// the owned value, copying whatever is still borrowed
+    pub fn into_value(self) -> JsonValue {
+        match self {
+            JsonValueRef::Null => JsonValue::Null,
+            JsonValueRef::Str(s) => JsonValue::Str(s.into_owned()),
+            JsonValueRef::Boolean(b) => JsonValue::Boolean(b),
+            JsonValueRef::Num(n) => JsonValue::Num(n),
+            JsonValueRef::Array(a) => a.into_iter().map(JsonValueRef::into_value).collect(),
+            JsonValueRef::Object(o) => o
+                .into_iter()
+                .map(|(k, v)| (k.into_owned(), v.into_value()))
+                .collect(),
+        }
+    }
+
+    /// an owned copy of the value
+    pub fn to_value(&self) -> JsonValue {
+        self.clone().into_value()
+    }
+
+    /// `.[key]` on an object
+    pub fn get(&self, key: &str) -> Option<&JsonValueRef<'a>> {
+        match self {
+            JsonValueRef::Object(o) => o.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// follows `path` down into the value the way `getpath` does, so a key or
+    /// index that isn't there, or anything in null, is null. `None` if a step
+    /// doesn't make sense for the value it's on, which is an error at runtime
+    pub fn lookup(&self, path: &[JsonValue]) -> Option<&JsonValueRef<'a>> {
+        path.iter().try_fold(self, |v, key| match (v, key) {
+            (JsonValueRef::Null, JsonValue::Str(_) | JsonValue::Num(_)) => Some(&NULL),
+            (JsonValueRef::Object(_), JsonValue::Str(k)) => Some(v.get(k).unwrap_or(&NULL)),
+            (JsonValueRef::Array(a), JsonValue::Num(n)) => {
+                let n = n.floor();
+                let i = if n < 0.0 { a.len() as f64 + n } else { n };
+                Some(if i < 0.0 {
+                    &NULL
+                } else {
+                    a.get(i as usize).unwrap_or(&NULL)
+                })
+            }
+            _ => None,
+        })
+    }
+}
+
+impl<'a> From<&'a JsonValue> for JsonValueRef<'a> {
+    /// borrows all of `v`
+    fn from(v: &'a JsonValue) -> Self {
+        match v {
+            JsonValue::Null => JsonValueRef::Null,
+            JsonValue::Str(s) => JsonValueRef::Str(Cow::Borrowed(s)),
+            JsonValue::Boolean(b) => JsonValueRef::Boolean(*b),
+            JsonValue::Num(n) => JsonValueRef::Num(*n),
+            JsonValue::Array(a) => JsonValueRef::Array(a.iter().map(Self::from).collect()),
+            JsonValue::Object(o) => JsonValueRef::Object(
+                o.iter()
+                    .map(|(k, v)| (Cow::Borrowed(k.as_str()), Self::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// formats a number the way jq does, with the fewest digits that read back as
+/// the same number. like jq, that's in full unless its decimal exponent is -5
+/// or less, or more than 15 past its last significant digit, and infinities
+/// are clamped to the largest finite double
+pub(crate) fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return "null".to_string();
+    }
+    let n = n.clamp(f64::MIN, f64::MAX);
+    if n.fract() == 0.0 && n.abs() < 1e17 {
+        return format!("{}", n as i64);
+    }
+    let s = format!("{:e}", n);
+    let (mantissa, exp) = match s.split_once('e') {
+        Some((mantissa, exp)) => (mantissa, exp.parse::<i32>().unwrap_or(0)),
+        None => return s,
+    };
+    let digits = mantissa.chars().filter(char::is_ascii_digit).count() as i32;
+    if exp <= -5 || exp > digits + 15 {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        format!("{}", n)
+    }
+}
+
+fn write_json_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            '\u{8}' => write!(f, "\\b")?,
+            '\u{c}' => write!(f, "\\f")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
 }
 
+/// compact JSON, as jq prints it with `-c`
 impl std::fmt::Display for JsonValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             JsonValue::Null => write!(f, "null"),
-            JsonValue::Str(s) => write!(f, "{}", s),
+            JsonValue::Str(s) => write_json_string(f, s),
             JsonValue::Boolean(b) => write!(f, "{}", b),
-            JsonValue::Num(n) => write!(f, "{}", n),
+            JsonValue::Num(n) => write!(f, "{}", format_number(*n)),
             JsonValue::Array(a) => {
                 write!(f, "[")?;
                 for (i, e) in a.iter().enumerate() {
@@ -42,7 +209,8 @@ impl std::fmt::Display for JsonValue {
             JsonValue::Object(o) => {
                 write!(f, "{{")?;
                 for (i, (k, v)) in o.iter().enumerate() {
-                    write!(f, r#""{}":{}"#, k, v)?;
+                    write_json_string(f, k)?;
+                    write!(f, ":{}", v)?;
                     if i != o.len() - 1 {
                         write!(f, ",")?;
                     }
@@ -87,6 +255,73 @@ impl JsonValue {
             _ => None,
         }
     }
+
+    /// false and null are falsy, everything else is truthy
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, JsonValue::Null | JsonValue::Boolean(false))
+    }
+
+    /// the name jq gives this value's type
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            JsonValue::Null => "null",
+            JsonValue::Boolean(_) => "boolean",
+            JsonValue::Num(_) => "number",
+            JsonValue::Str(_) => "string",
+            JsonValue::Array(_) => "array",
+            JsonValue::Object(_) => "object",
+        }
+    }
+
+    /// jq's total ordering: null < false < true < numbers < strings < arrays < objects.
+    /// objects compare their sorted key sets first, then their values key by key
+    pub fn compare(&self, other: &JsonValue) -> Ordering {
+        fn rank(v: &JsonValue) -> u8 {
+            match v {
+                JsonValue::Null => 0,
+                JsonValue::Boolean(false) => 1,
+                JsonValue::Boolean(true) => 2,
+                JsonValue::Num(_) => 3,
+                JsonValue::Str(_) => 4,
+                JsonValue::Array(_) => 5,
+                JsonValue::Object(_) => 6,
+            }
+        }
+
+        match (self, other) {
+            (JsonValue::Num(a), JsonValue::Num(b)) => {
+                // nan sorts below every number, including itself
+                if a < b || a.is_nan() {
+                    Ordering::Less
+                } else if a == b {
+                    Ordering::Equal
+                } else {
+                    Ordering::Greater
+                }
+            }
+            (JsonValue::Str(a), JsonValue::Str(b)) => a.cmp(b),
+            (JsonValue::Array(a), JsonValue::Array(b)) => compare_slices(a, b),
+            (JsonValue::Object(a), JsonValue::Object(b)) => {
+                a.keys().cmp(b.keys()).then_with(|| {
+                    a.values()
+                        .zip(b.values())
+                        .map(|(x, y)| x.compare(y))
+                        .find(|o| o.is_ne())
+                        .unwrap_or(Ordering::Equal)
+                })
+            }
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+}
+
+/// lexicographic comparison of two arrays of values under `JsonValue::compare`
+pub(crate) fn compare_slices(a: &[JsonValue], b: &[JsonValue]) -> Ordering {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| x.compare(y))
+        .find(|o| o.is_ne())
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
 }
 
 impl std::ops::Index<&str> for JsonValue {
@@ -105,6 +340,15 @@ impl std::ops::Index<usize> for JsonValue {
     }
 }
 
+impl std::str::FromStr for JsonValue {
+    type Err = String;
+
+    /// parses one JSON value of any kind, e.g. for `--argjson`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_value(s)
+    }
+}
+
 /// parser combinators are constructed from the bottom up:
 /// first we write parsers for the smallest elements (here a space character),
 /// then we'll combine them in larger parsers
@@ -131,9 +375,68 @@ fn sp<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E
 /// with the same lifetime tag. This means that the produced value is a subslice
 /// of the input data. and there is no allocation needed. This is the main idea
 /// behind nom's performance.
-fn parse_str<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
-    // TODO: strings should be allowed to have non alphanum chars in them eg spaces
-    escaped(alphanumeric, '\\', one_of("\"n\\"))(i)
+fn hex4<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], u32, E> {
+    map_opt(take(4usize), |h: &[u8]| {
+        u32::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok()
+    })(i)
+}
+
+/// `\u` escapes, where characters outside the BMP come as a surrogate pair
+fn unicode_escape<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], char, E> {
+    let (i, hi) = preceded(char('u'), hex4)(i)?;
+    if !(0xD800..0xDC00).contains(&hi) {
+        return Ok((i, std::char::from_u32(hi).unwrap_or('\u{FFFD}')));
+    }
+    match preceded(tag("\\u"), hex4::<E>)(i) {
+        Ok((rest, lo)) if (0xDC00..0xE000).contains(&lo) => {
+            let c = 0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00);
+            Ok((rest, std::char::from_u32(c).unwrap_or('\u{FFFD}')))
+        }
+        Err(nom::Err::Incomplete(n)) => Err(nom::Err::Incomplete(n)),
+        // a lone surrogate can't be represented, so it becomes the replacement character
+        _ => Ok((i, '\u{FFFD}')),
+    }
+}
+
+fn escape<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], char, E> {
+    alt((
+        value('"', char('"')),
+        value('\\', char('\\')),
+        value('/', char('/')),
+        value('\u{8}', char('b')),
+        value('\u{c}', char('f')),
+        value('\n', char('n')),
+        value('\r', char('r')),
+        value('\t', char('t')),
+        unicode_escape,
+    ))(i)
+}
+
+/// the inside of a string, up to and including its closing quote. it's
+/// borrowed from the input unless it has escapes to replace
+fn parse_str<'a, E: ParseError<&'a [u8]>>(mut i: &'a [u8]) -> IResult<&'a [u8], Cow<'a, str>, E> {
+    let mut s = Cow::Borrowed("");
+    loop {
+        let (rest, chunk) = opt(is_not("\"\\"))(i)?;
+        if let Some(chunk) = chunk {
+            let chunk = std::str::from_utf8(chunk)
+                .map_err(|_| nom::Err::Failure(E::from_error_kind(i, ErrorKind::Char)))?;
+            match &mut s {
+                Cow::Borrowed("") => s = Cow::Borrowed(chunk),
+                s => s.to_mut().push_str(chunk),
+            }
+        }
+        let (rest, c) = one_of("\"\\")(rest)?;
+        match c {
+            '"' => return Ok((rest, s)),
+            '\\' => {
+                let (rest, c) = escape(rest)?;
+                s.to_mut().push(c);
+                i = rest;
+            }
+            _ => unreachable!("is_not stops at quotes and backslashes"),
+        }
+    }
 }
 
 /// `tag(string)` generates a parser that recognizes the argument string.
@@ -168,18 +471,15 @@ fn null<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], (), E
 ///
 /// `context` and `cut` are related to error management:
 /// - `cut` transforms an `Err::Error(e)` in `Err::Failure(e)`, signaling to
-/// combinators like  `alt` that they should not try other parsers. We were in the
-/// right branch (since we found the `"` character) but encountered an error when
-/// parsing the string
+///   combinators like  `alt` that they should not try other parsers. We were in the
+///   right branch (since we found the `"` character) but encountered an error when
+///   parsing the string
 /// - `context` lets you add a static string to provide more information in the
-/// error chain (to indicate which parser had an error)
+///   error chain (to indicate which parser had an error)
 fn string<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
     i: &'a [u8],
-) -> IResult<&'a [u8], &'a [u8], E> {
-    context(
-        "string",
-        preceded(char('\"'), cut(terminated(parse_str, char('\"')))),
-    )(i)
+) -> IResult<&'a [u8], Cow<'a, str>, E> {
+    context("string", preceded(char('\"'), cut(parse_str)))(i)
 }
 
 /// some combinators, like `separated_list0` or `many0`, will call a parser repeatedly,
@@ -188,45 +488,133 @@ fn string<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
 /// combinator (cf `examples/iterator.rs`)
 fn array<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
     i: &'a [u8],
-) -> IResult<&'a [u8], Vec<JsonValue>, E> {
+    depth: usize,
+) -> IResult<&'a [u8], Vec<JsonValueRef<'a>>, E> {
     context(
         "array",
         preceded(
             char('['),
             cut(terminated(
-                separated_list0(preceded(sp, char(',')), json_value),
+                comma_separated(move |i| json_value(i, depth + 1)),
                 preceded(sp, char(']')),
             )),
         ),
     )(i)
 }
 
+/// `f`s separated by commas, or none at all. after a comma there has to be
+/// another `f`, so an error in it is reported there rather than at the comma
+fn comma_separated<'a, O, E: ParseError<&'a [u8]>, F>(
+    f: F,
+) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Vec<O>, E>
+where
+    F: Fn(&'a [u8]) -> IResult<&'a [u8], O, E> + Copy,
+{
+    move |i| {
+        let (i, first) = match opt(f)(i)? {
+            (i, Some(first)) => (i, first),
+            (i, None) => return Ok((i, vec![])),
+        };
+        let (i, mut rest) = many0(preceded(preceded(sp, char(',')), cut(f)))(i)?;
+        rest.insert(0, first);
+        Ok((i, rest))
+    }
+}
+
 fn key_value<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
     i: &'a [u8],
-) -> IResult<&'a [u8], (&'a [u8], JsonValue), E> {
+    depth: usize,
+) -> IResult<&'a [u8], Member<'a>, E> {
     separated_pair(
         preceded(sp, string),
         cut(preceded(sp, char(':'))),
-        json_value,
+        cut(move |i| json_value(i, depth)),
     )(i)
 }
 
 fn hash<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
     i: &'a [u8],
-) -> IResult<&'a [u8], BTreeMap<String, JsonValue>, E> {
+    depth: usize,
+) -> IResult<&'a [u8], Members<'a>, E> {
+    context(
+        "map",
+        preceded(
+            char('{'),
+            cut(terminated(
+                comma_separated(move |i| key_value(i, depth + 1)),
+                preceded(sp, char('}')),
+            )),
+        ),
+    )(i)
+}
+
+/// how deeply arrays and objects can be nested, as in jq. the parsers recurse
+/// for each level, so a deep enough value would otherwise overflow the stack
+const MAX_DEPTH: usize = 10000;
+
+/// the error for an array or object at `i` that's nested too deeply. it's a
+/// failure, so nothing tries to parse it some other way
+fn too_deep<'a, O, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], O, E> {
+    Err(nom::Err::Failure(E::from_error_kind(
+        i,
+        ErrorKind::TooLarge,
+    )))
+}
+
+/// here, we apply the space parser before trying to parse a value. the first
+/// byte says what kind of value it is, so only that parser is tried: with
+/// `alt`, every parser that didn't match would allocate an error first.
+/// `depth` is how many arrays and objects the value is inside of
+fn json_value<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    depth: usize,
+) -> IResult<&'a [u8], JsonValueRef<'a>, E> {
+    let (i, _) = opt(sp)(i)?;
+    match i.first() {
+        None => Err(nom::Err::Incomplete(nom::Needed::new(1))),
+        Some(b'{' | b'[') if depth >= MAX_DEPTH => too_deep(i),
+        Some(b'{') => map(|i| hash(i, depth), JsonValueRef::Object)(i),
+        Some(b'[') => map(|i| array(i, depth), JsonValueRef::Array)(i),
+        Some(b'"') => map(string, JsonValueRef::Str)(i),
+        Some(b't' | b'f') => map(boolean, JsonValueRef::Boolean)(i),
+        Some(b'n') => map(null, |_| JsonValueRef::Null)(i),
+        Some(_) => map(double, JsonValueRef::Num)(i),
+    }
+}
+
+/// a value cut down to `need`: objects keep only the members it names
+fn projected_value<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    need: &Projection,
+    depth: usize,
+) -> IResult<&'a [u8], JsonValueRef<'a>, E> {
+    let fields = match need {
+        Projection::All => return json_value(i, depth),
+        Projection::Fields(fields) => fields,
+    };
+    let (i, _) = opt(sp)(i)?;
+    match i.first() {
+        Some(b'{') if depth >= MAX_DEPTH => too_deep(i),
+        Some(b'{') => map(|i| projected_hash(i, fields, depth), JsonValueRef::Object)(i),
+        _ => json_value(i, depth),
+    }
+}
+
+/// an object with only the members in `fields`. the rest are skipped over
+/// without being built
+fn projected_hash<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    fields: &BTreeMap<String, Projection>,
+    depth: usize,
+) -> IResult<&'a [u8], Members<'a>, E> {
     context(
         "map",
         preceded(
             char('{'),
             cut(terminated(
                 map(
-                    separated_list0(preceded(sp, char(',')), key_value),
-                    |tuple_vec| {
-                        tuple_vec
-                            .into_iter()
-                            .map(|(k, v)| (String::from_utf8(k.to_vec()).unwrap(), v))
-                            .collect()
-                    },
+                    comma_separated(move |i| projected_member(i, fields, depth + 1)),
+                    |members| members.into_iter().flatten().collect(),
                 ),
                 preceded(sp, char('}')),
             )),
@@ -234,40 +622,224 @@ fn hash<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
     )(i)
 }
 
-/// here, we apply the space parser before trying to parse a value
-fn json_value<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+/// a key and its value, if the key is one of `fields`
+fn projected_member<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    fields: &BTreeMap<String, Projection>,
+    depth: usize,
+) -> IResult<&'a [u8], Option<Member<'a>>, E> {
+    let (i, key) = preceded(sp, string)(i)?;
+    let (i, _) = cut(preceded(sp, char(':')))(i)?;
+    match fields.get(key.as_ref()) {
+        Some(need) => {
+            let (i, v) = cut(|i| projected_value(i, need, depth))(i)?;
+            Ok((i, Some((key, v))))
+        }
+        None => {
+            let (i, _) = cut(|i| skip_value(i, depth))(i)?;
+            Ok((i, None))
+        }
+    }
+}
+
+/// gets past a value nothing needs without building it. it's checked the same
+/// way as a value that's built, so a mistake in it is reported just the same
+fn skip_value<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    depth: usize,
+) -> IResult<&'a [u8], (), E> {
+    let (i, _) = opt(sp)(i)?;
+    match i.first() {
+        None => Err(nom::Err::Incomplete(nom::Needed::new(1))),
+        Some(b'{' | b'[') if depth >= MAX_DEPTH => too_deep(i),
+        Some(b'{') => value((), |i| skip_hash(i, depth))(i),
+        Some(b'[') => value((), |i| skip_array(i, depth))(i),
+        Some(b'"') => skip_string(i),
+        Some(b't' | b'f') => value((), boolean)(i),
+        Some(b'n') => null(i),
+        Some(_) => value((), double)(i),
+    }
+}
+
+/// the inside of a string, up to and including its closing quote, checked
+/// like `parse_str` but not copied anywhere
+fn skip_str<'a, E: ParseError<&'a [u8]>>(mut i: &'a [u8]) -> IResult<&'a [u8], (), E> {
+    loop {
+        let (rest, chunk) = opt(is_not("\"\\"))(i)?;
+        if chunk.is_some_and(|c| std::str::from_utf8(c).is_err()) {
+            return Err(nom::Err::Failure(E::from_error_kind(i, ErrorKind::Char)));
+        }
+        let (rest, c) = one_of("\"\\")(rest)?;
+        match c {
+            '"' => return Ok((rest, ())),
+            '\\' => i = escape(rest)?.0,
+            _ => unreachable!("is_not stops at quotes and backslashes"),
+        }
+    }
+}
+
+fn skip_string<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
     i: &'a [u8],
-) -> IResult<&'a [u8], JsonValue, E> {
-    preceded(
-        opt(sp),
-        alt((
-            map(hash, JsonValue::Object),
-            map(array, JsonValue::Array),
-            map(string, |s| {
-                JsonValue::Str(String::from_utf8(s.to_vec()).unwrap())
-            }),
-            map(double, JsonValue::Num),
-            map(boolean, JsonValue::Boolean),
-            map(null, |_| JsonValue::Null),
-        )),
+) -> IResult<&'a [u8], (), E> {
+    context("string", preceded(char('\"'), cut(skip_str)))(i)
+}
+
+/// the elements are skipped too, so the `Vec` of them holds nothing
+fn skip_array<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    depth: usize,
+) -> IResult<&'a [u8], Vec<()>, E> {
+    context(
+        "array",
+        preceded(
+            char('['),
+            cut(terminated(
+                comma_separated(move |i| skip_value(i, depth + 1)),
+                preceded(sp, char(']')),
+            )),
+        ),
     )(i)
 }
 
-/// the root element of a JSON parser is either an object or an array
-pub(crate) fn root<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+fn skip_member<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
     i: &'a [u8],
-) -> IResult<&'a [u8], JsonValue, E> {
-    delimited(
-        opt(sp),
-        alt((
-            map(hash, JsonValue::Object),
-            map(array, JsonValue::Array),
-            map(null, |_| JsonValue::Null),
-        )),
-        opt(sp),
+    depth: usize,
+) -> IResult<&'a [u8], (), E> {
+    value(
+        (),
+        separated_pair(
+            preceded(sp, skip_string),
+            cut(preceded(sp, char(':'))),
+            cut(move |i| skip_value(i, depth)),
+        ),
     )(i)
 }
 
+fn skip_hash<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    depth: usize,
+) -> IResult<&'a [u8], Vec<()>, E> {
+    context(
+        "map",
+        preceded(
+            char('{'),
+            cut(terminated(
+                comma_separated(move |i| skip_member(i, depth + 1)),
+                preceded(sp, char('}')),
+            )),
+        ),
+    )(i)
+}
+
+/// the root element of a JSON parser, cut down to `need`. like jq, any kind of
+/// value can be one, not just an object or an array
+fn root_value<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    need: &Projection,
+) -> IResult<&'a [u8], JsonValueRef<'a>, E> {
+    projected_value(i, need, 0)
+}
+
+/// a root element and the whitespace after it, up to wherever the next one starts
+pub(crate) fn root<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    need: &Projection,
+) -> IResult<&'a [u8], JsonValueRef<'a>, E> {
+    let (i, v) = root_value(i, need)?;
+    let (i, _) = opt(sp)(i)?;
+    Ok((i, v))
+}
+
+/// the last root element in the input. there's nothing more to come, so the
+/// whitespace after it ends where the input does
+pub(crate) fn root_at_eof<'a, E: ParseError<&'a [u8]> + ContextError<&'a [u8]>>(
+    i: &'a [u8],
+    need: &Projection,
+) -> IResult<&'a [u8], JsonValueRef<'a>, E> {
+    let (i, _) = opt(sp)(i)?;
+    // a number running to the end of the input can't carry on any further
+    let (i, v) = match i.first() {
+        Some(c) if !b"{[\"tfn".contains(c) => {
+            map(nom::number::complete::double, JsonValueRef::Num)(i)?
+        }
+        _ => root_value(i, need)?,
+    };
+    let (i, _) = nom::bytes::complete::take_while(|c| b" \t\r\n".contains(&c))(i)?;
+    Ok((i, v))
+}
+
+/// where and why a stream of JSON input stopped being valid
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct JsonParseError {
+    pub message: String,
+    /// bytes from the start of the input
+    pub offset: usize,
+    /// from 1
+    pub line: usize,
+    /// from 1, in bytes
+    pub column: usize,
+    /// which value in the stream was being parsed, from 1
+    pub record: usize,
+    /// the text around the error
+    pub snippet: String,
+}
+
+impl std::fmt::Display for JsonParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {} (byte {}, record {}): {}",
+            self.message, self.line, self.column, self.offset, self.record, self.snippet
+        )
+    }
+}
+
+impl std::error::Error for JsonParseError {}
+
+/// what went wrong, going by nom's innermost error and the value it was in
+pub(crate) fn describe_error(err: &nom::error::VerboseError<&[u8]>) -> String {
+    use nom::error::VerboseErrorKind;
+
+    let (rest, kind) = match err.errors.first() {
+        Some((_, VerboseErrorKind::Nom(ErrorKind::TooLarge))) => {
+            return "Exceeds depth limit for parsing".to_string()
+        }
+        Some((rest, kind)) => (rest.len(), kind),
+        None => return "invalid JSON value".to_string(),
+    };
+    // a value that starts right where the error is was only being tried, so
+    // the innermost one that started earlier is what the error is inside of
+    let within = err.errors.iter().find_map(|(at, kind)| match kind {
+        VerboseErrorKind::Context(c) if at.len() > rest => Some(*c),
+        _ => None,
+    });
+    let what = match (within, kind) {
+        (Some("string"), _) => return "invalid string".to_string(),
+        (_, VerboseErrorKind::Char(c)) => format!("expected `{}`", c),
+        _ => "invalid JSON value".to_string(),
+    };
+    match within {
+        Some("array") => format!("{} in an array", what),
+        Some("map") => format!("{} in an object", what),
+        _ => what,
+    }
+}
+
+/// parses a single complete JSON value of any kind, as `fromjson` does
+pub(crate) fn parse_value(s: &str) -> Result<JsonValue, String> {
+    // the parsers are streaming, so a trailing space tells them a bare number has ended
+    let padded = format!("{} ", s);
+    match json_value::<nom::error::Error<&[u8]>>(padded.as_bytes(), 0) {
+        Ok((rest, v)) if rest.iter().all(u8::is_ascii_whitespace) => Ok(v.into_value()),
+        Ok(_) => Err("Unexpected extra JSON values".to_string()),
+        Err(nom::Err::Incomplete(_)) => Err("Unfinished JSON term at EOF".to_string()),
+        Err(nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge => {
+            Err("Exceeds depth limit for parsing".to_string())
+        }
+        Err(_) => Err("Invalid JSON text".to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -279,20 +851,136 @@ mod tests {
     fn it_works() -> Result<()> {
         let cases = [(
             r#" { "hi": 42 } "#.as_bytes(),
-            JsonValue::Object(
-                vec![("hi".into(), JsonValue::Num(42.0))]
-                    .into_iter()
-                    .collect(),
-            ),
+            vec![("hi".to_string(), JsonValue::Num(42.0))]
+                .into_iter()
+                .collect(),
         )];
 
         for (input, output) in cases {
-            let res = root::<VerboseError<&[u8]>>(input);
+            let res = root_at_eof::<VerboseError<&[u8]>>(input, &Projection::All);
             dbg!(&res);
-            let res = res.finish();
+            let res = res.finish().map(|(rest, v)| (rest, v.into_value()));
 
             assert_eq!(res, Ok(("".as_bytes(), output)));
         }
         Ok(())
     }
+
+    #[test]
+    fn it_formats_numbers_like_jq() {
+        let cases = [
+            (1e-5, "1e-05"),
+            (0.00005, "5e-05"),
+            (0.0001, "0.0001"),
+            (-1.5e-7, "-1.5e-07"),
+            (0.1, "0.1"),
+            (3.0, "3"),
+            (1e17, "1e+17"),
+            (1.5e300, "1.5e+300"),
+            (12345678901234567890.0, "12345678901234567000"),
+            (f64::INFINITY, "1.7976931348623157e+308"),
+            (f64::NAN, "null"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(format_number(n), expected, "{}", n);
+        }
+    }
+
+    #[test]
+    fn it_skips_what_isnt_needed() {
+        let need = Projection::Fields(BTreeMap::from([
+            ("id".to_string(), Projection::All),
+            (
+                "user".to_string(),
+                Projection::Fields(BTreeMap::from([("name".to_string(), Projection::All)])),
+            ),
+        ]));
+        let input =
+            br#"{"skip": "a \"}]\\", "id": 7, "user": {"tags": [{"x": "]"}, 1], "name": "n"},
+            "list": [[1, {"a": []}], "x"], "n": -1.5e3, "t": true, "user": 5} "#;
+        let (rest, v) = root_at_eof::<VerboseError<&[u8]>>(input, &need).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            v.to_value(),
+            parse_value(r#"{"id": 7, "user": 5}"#).unwrap()
+        );
+        assert_eq!(
+            v.lookup(&[JsonValue::Str("user".into())]),
+            Some(&JsonValueRef::Num(5.0))
+        );
+        let JsonValueRef::Object(members) = &v else {
+            panic!("not an object: {:?}", v)
+        };
+        assert_eq!(members[1].1.to_value()["name"], JsonValue::Str("n".into()));
+
+        // a value skipped part way through needs more input, same as one being built
+        for end in [12, 30, 60] {
+            let res = root::<VerboseError<&[u8]>>(&input[..end], &need);
+            assert!(
+                matches!(res, Err(nom::Err::Incomplete(_))),
+                "{}: {:?}",
+                end,
+                res
+            );
+        }
+        let res = root_at_eof::<VerboseError<&[u8]>>(br#"{"a": , "id": 1}"#, &need);
+        assert!(matches!(res, Err(nom::Err::Failure(_))), "{:?}", res);
+
+        // a mistake in a skipped value is reported as if it had been built
+        let error =
+            |input: &[u8], need: &Projection| match root_at_eof::<VerboseError<&[u8]>>(input, need)
+            {
+                Err(nom::Err::Failure(e)) => {
+                    (describe_error(&e), input.len() - e.errors[0].0.len())
+                }
+                res => panic!("{:?}", res),
+            };
+        for input in [
+            r#"{"b": [1,,}}, "id": 1}"#,
+            r#"{"b": [1}, "id": 1}"#,
+            r#"{"b": {"x" 1}, "id": 1}"#,
+            r#"{"b": {"x": 1,}, "id": 1}"#,
+            r#"{"b": {1: 2}, "id": 1}"#,
+            r#"{"b": "a\q", "id": 1}"#,
+            r#"{"b": [tru], "id": 1}"#,
+            r#"{"b": [-], "id": 1}"#,
+        ] {
+            let input = input.as_bytes();
+            assert_eq!(
+                error(input, &need),
+                error(input, &Projection::All),
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn it_borrows_from_the_input() {
+        let input = br#"{"a": {"b": ["plain", "esc\u00e9ped"]}, "a": {"b": [1, 2]}, "n": null} "#;
+        let (_, v) = root_at_eof::<VerboseError<&[u8]>>(input, &Projection::All).unwrap();
+
+        let (key, first) = match &v {
+            JsonValueRef::Object(o) => &o[0],
+            _ => panic!("not an object: {:?}", v),
+        };
+        assert!(matches!(key, Cow::Borrowed("a")));
+        match first.get("b") {
+            Some(JsonValueRef::Array(a)) => {
+                assert!(matches!(a[0], JsonValueRef::Str(Cow::Borrowed("plain"))));
+                assert!(matches!(&a[1], JsonValueRef::Str(Cow::Owned(s)) if s == "escéped"));
+            }
+            b => panic!("not an array: {:?}", b),
+        }
+
+        let path =
+            |p: &str| -> Vec<JsonValue> { parse_value(p).unwrap().as_array().unwrap().clone() };
+        let lookup = |p: &str| v.lookup(&path(p)).map(JsonValueRef::to_value);
+        // the last of the duplicate keys is the one that counts
+        assert_eq!(lookup(r#"["a", "b", -1]"#), Some(JsonValue::Num(2.0)));
+        assert_eq!(lookup(r#"["a", "b", 5]"#), Some(JsonValue::Null));
+        assert_eq!(lookup(r#"["n", "x", 0]"#), Some(JsonValue::Null));
+        assert_eq!(lookup(r#"["a", "b", "c"]"#), None);
+        assert_eq!(v.to_value()["a"]["b"][0], JsonValue::Num(1.0));
+    }
 }
@@ -1,7 +1,35 @@
+mod builtins;
+mod dates;
+mod diagnostic;
+mod formats;
+mod inputs;
+mod interpreter;
 mod jq_parser;
 mod json_parser;
+mod mapped;
+mod math;
+mod modules;
+mod path;
+mod projection;
+mod regex;
 mod streamer;
+#[cfg(test)]
+mod test_util;
 
+pub use diagnostic::Diagnostic;
+pub use inputs::Inputs;
+pub use inputs::OnError;
+pub use interpreter::Env;
+pub use interpreter::RuntimeError;
+pub use jq_parser::compile;
+pub use jq_parser::compile_with;
 pub use jq_parser::parse_filter;
+pub use jq_parser::parse_program;
 pub use jq_parser::Pipeline;
+pub use json_parser::JsonParseError;
+pub use json_parser::JsonValue;
+pub use json_parser::JsonValueRef;
+pub use modules::Loader;
+pub use modules::Modules;
+pub use projection::Projection;
 pub use streamer::Streamer;
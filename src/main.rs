@@ -1,61 +1,426 @@
-use std::io::Read;
+use std::collections::BTreeMap;
 
-use anyhow::{bail, Result};
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{CommandFactory, FromArgMatches, Parser};
 use tracing::info;
 
-use jqr::{parse_filter, Streamer};
+use jqr::{compile_with, Env, Inputs, JsonValue, Loader, OnError, Pipeline, RuntimeError};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     /// Filter string
-    #[clap()]
-    filter: String,
+    #[clap(required_unless_present = "from-file")]
+    filter: Option<String>,
+
+    /// Read the filter from a file. The filter argument is then the first input file
+    #[clap(short = 'f', long, value_name = "FILE")]
+    from_file: Option<std::path::PathBuf>,
 
-    /// Input path (empty for stdin)
+    /// Input files, read one after the other. `-` or none at all is stdin. The ones
+    /// after --args or --jsonargs are positional arguments for $ARGS instead
     #[clap()]
-    input_file: Option<std::path::PathBuf>,
+    positional: Vec<String>,
+
+    /// Run the filter once with null as its input. `input` and `inputs` still read the input
+    #[clap(short = 'n', long)]
+    null_input: bool,
+
+    /// Read every input into one array, or with -R the whole text into one string
+    #[clap(short = 's', long)]
+    slurp: bool,
+
+    /// Read each line of the input as a string instead of parsing it as JSON
+    #[clap(short = 'R', long)]
+    raw_input: bool,
+
+    /// Bind $NAME to the string VALUE
+    #[clap(long, number_of_values = 2, value_names = &["NAME", "VALUE"], multiple_occurrences = true)]
+    arg: Vec<String>,
+
+    /// Bind $NAME to the JSON value TEXT
+    #[clap(long, number_of_values = 2, value_names = &["NAME", "TEXT"], multiple_occurrences = true)]
+    argjson: Vec<String>,
+
+    /// Bind $NAME to an array of the JSON values in FILE
+    #[clap(long, number_of_values = 2, value_names = &["NAME", "FILE"], multiple_occurrences = true)]
+    slurpfile: Vec<String>,
+
+    /// Bind $NAME to the contents of FILE as a string
+    #[clap(long, number_of_values = 2, value_names = &["NAME", "FILE"], multiple_occurrences = true)]
+    rawfile: Vec<String>,
+
+    /// What to do about input that isn't valid JSON: stop reading it (fail), or
+    /// report it (warn) or not (skip) and carry on from the next line
+    #[clap(long, value_name = "MODE", default_value = "fail", possible_values = &["fail", "warn", "skip"])]
+    on_error: OnError,
+
+    /// Same as --on-error skip
+    #[clap(long)]
+    skip_invalid: bool,
+
+    /// Write each line skipped for not being valid JSON to FILE
+    #[clap(long, value_name = "FILE")]
+    reject_file: Option<std::path::PathBuf>,
+
+    /// Read JSON input BYTES at a time. A value bigger than that is still read
+    /// whole
+    #[clap(long, value_name = "BYTES", default_value = "65536")]
+    buffer_size: usize,
+
+    /// Map regular input files into memory rather than reading them. The files
+    /// must not change while jqr runs: one that's cut short crashes it
+    #[clap(long)]
+    mmap: bool,
+
+    /// Search DIR for modules. Without any, ~/.jq, $ORIGIN/../lib/jq and $ORIGIN/../lib are searched. A ~/.jq file is included in every program
+    #[clap(short = 'L', value_name = "DIR", multiple_occurrences = true)]
+    library_path: Vec<String>,
+
+    /// Exit with 1 if the last output was false or null, or 4 if there was no output
+    #[clap(short = 'e', long)]
+    exit_status: bool,
+
+    /// Treat the positional arguments as strings for $ARGS.positional
+    #[clap(long)]
+    args: bool,
+
+    /// Treat the positional arguments as JSON values for $ARGS.positional
+    #[clap(long)]
+    jsonargs: bool,
+
+    /// how many of the positional arguments come before --args or --jsonargs.
+    /// those are still input files, and only the rest go in $ARGS
+    #[clap(skip)]
+    files: Option<usize>,
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+impl Args {
+    /// parses the command line, noting where --args or --jsonargs is in it
+    fn parse_with_files() -> Self {
+        let matches = Args::command().get_matches();
+        let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let start = ["args", "jsonargs"]
+            .iter()
+            .filter_map(|flag| matches.index_of(flag))
+            .min();
+        args.files = start.map(|start| {
+            let before = |id| {
+                matches
+                    .indices_of(id)
+                    .map_or(0, |at| at.filter(|&i| i < start).count())
+            };
+            // with -f, the filter argument is really the first positional one
+            let filter = if args.from_file.is_some() {
+                before("filter")
+            } else {
+                0
+            };
+            filter + before("positional")
+        });
+        args
+    }
 
-    if let Err(std::env::VarError::NotPresent) = std::env::var("RUST_LOG") {
-        std::env::set_var("RUST_LOG", "info");
+    /// the positional arguments that are input files, and those for $ARGS
+    fn split_positional(&self) -> (&[String], &[String]) {
+        match self.files {
+            Some(n) => self.positional.split_at(n),
+            None => (&self.positional, &[]),
+        }
     }
+}
 
-    let args = Args::parse();
+/// jq's exit codes
+mod exit {
+    /// `-e`: the last output was false or null
+    pub const FALSY: i32 = 1;
+    /// bad arguments, files that can't be read and invalid input
+    pub const USAGE: i32 = 2;
+    /// the filter doesn't parse, or its modules can't be loaded
+    pub const COMPILE: i32 = 3;
+    /// `-e`: there was no output at all
+    pub const NO_OUTPUT: i32 = 4;
+    /// the filter raised an error
+    pub const RUNTIME: i32 = 5;
+}
 
-    let filter = match parse_filter(&args.filter) {
-        Ok(f) => f,
-        Err(e) => {
-            let msg = nom::error::convert_error::<&str>(&args.filter, e.clone());
-            bail!("failed to parse filter: {}", msg);
+/// what has happened so far, to work out the exit code from
+#[derive(Default)]
+struct Status {
+    /// whether the last output was truthy, if there's been one
+    last: Option<bool>,
+    runtime_error: bool,
+    input_error: bool,
+}
+
+impl Status {
+    fn code(&self, exit_status: bool) -> i32 {
+        if self.runtime_error {
+            exit::RUNTIME
+        } else if self.input_error {
+            exit::USAGE
+        } else if !exit_status {
+            0
+        } else {
+            match self.last {
+                Some(true) => 0,
+                Some(false) => exit::FALSY,
+                None => exit::NO_OUTPUT,
+            }
         }
+    }
+}
+
+/// the `--arg`-style variables, then `$ARGS` holding them along with the
+/// positional arguments, and the positional arguments on their own
+fn variables(args: &Args) -> Result<(BTreeMap<String, JsonValue>, JsonValue, JsonValue)> {
+    let mut named = BTreeMap::new();
+    for pair in args.arg.chunks(2) {
+        named.insert(pair[0].clone(), JsonValue::Str(pair[1].clone()));
+    }
+    for pair in args.argjson.chunks(2) {
+        let v = pair[1]
+            .parse()
+            .map_err(|_| anyhow!("invalid JSON text passed to --argjson"))?;
+        named.insert(pair[0].clone(), v);
+    }
+    for pair in args.slurpfile.chunks(2) {
+        let file = std::fs::File::open(&pair[1])
+            .map_err(|e| anyhow!("Could not open {}: {}", pair[1], e))?;
+        let values = Inputs::new(Box::new(file), Some(pair[1].clone()))
+            .slurp()
+            .next()
+            .transpose()?
+            .unwrap_or(JsonValue::Array(vec![].into()));
+        named.insert(pair[0].clone(), values);
+    }
+    for pair in args.rawfile.chunks(2) {
+        let text = std::fs::read_to_string(&pair[1])
+            .map_err(|e| anyhow!("Could not open {}: {}", pair[1], e))?;
+        named.insert(pair[0].clone(), JsonValue::Str(text));
+    }
+
+    let (_, positional) = args.split_positional();
+    let positional = if args.jsonargs {
+        positional
+            .iter()
+            .map(|a| a.parse())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("invalid JSON text passed to --jsonargs"))?
+    } else if args.args {
+        positional.iter().cloned().map(JsonValue::Str).collect()
+    } else {
+        vec![]
     };
 
-    info!("filter: {:?}", filter);
+    let positional = JsonValue::Array(positional.into());
+    let all = JsonValue::from(BTreeMap::from([
+        ("positional".to_string(), positional.clone()),
+        ("named".to_string(), JsonValue::Object(named.clone().into())),
+    ]));
+    Ok((named, all, positional))
+}
+
+/// runs the filter against one input and prints its outputs
+fn run(filter: &Pipeline, env: &Env, input: JsonValue, status: &mut Status) {
+    // like jq, an error stops this input's outputs but not the next input's
+    for output in filter.apply_with(env, input) {
+        if !emit(output, status) {
+            break;
+        }
+    }
+}
 
-    let s = std::io::stdin();
-    let reader: Box<dyn Read> = match args.input_file {
-        // TODO: support Some("-")
-        None => Box::new(s.lock()),
-        Some(p) => Box::new(std::fs::File::open(p)?),
+/// prints one output of the filter, returning false if it was an error
+fn emit(output: Result<JsonValue, RuntimeError>, status: &mut Status) -> bool {
+    if let Ok(v) = &output {
+        status.last = Some(v.is_truthy());
+    }
+    match output {
+        // strings are printed raw, everything else as JSON
+        Ok(JsonValue::Str(s)) => println!("{}", s),
+        Ok(j) => println!("{}", j),
+        Err(e) => {
+            eprintln!("jqr: error: {}", e);
+            // input that `input` or `inputs` couldn't parse is bad input, like
+            // any other, not an error in the filter
+            match e {
+                RuntimeError::Input(_) => status.input_error = true,
+                _ => status.runtime_error = true,
+            }
+            return false;
+        }
+    }
+    true
+}
+
+/// an input, or just the part of it a lookup filter picks out
+enum Input {
+    /// the filter's one output, already looked up
+    Selected(JsonValue),
+    /// the whole input, to run the filter on
+    Whole(JsonValue),
+}
+
+fn main() {
+    // logging goes to stderr so that it never mixes with the outputs, and is
+    // quiet unless RUST_LOG asks for more, e.g. RUST_LOG=info for the filter
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(tracing::Level::WARN);
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(level)
+        .init();
+
+    // clap exits with 2 itself when the arguments don't make sense
+    let args = Args::parse_with_files();
+    // parsing and printing a value recurse once for each level it's nested,
+    // and the parser takes values 10000 levels deep, as jq does. the stack is
+    // only given memory as it's used, so a big one costs nothing until then
+    let run = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || try_main(args))
+        .expect("the main thread starts");
+    let code = match run.join().expect("the main thread doesn't panic") {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("jqr: error: {}", e);
+            exit::USAGE
+        }
     };
+    std::process::exit(code);
+}
 
-    let hack = reader.chain("\nnull\n".as_bytes());
+/// the stack for running everything on, with room for the deepest input
+const STACK_SIZE: usize = 256 * 1024 * 1024;
 
-    let streamer = Streamer::new(hack);
+/// runs everything, returning the exit code. errors here are system errors
+fn try_main(mut args: Args) -> Result<i32> {
+    let program = match &args.from_file {
+        Some(path) => {
+            let first = args.filter.take();
+            args.positional.splice(0..0, first);
+            std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Could not open {}: {}", path.display(), e))?
+        }
+        // clap makes sure there's a filter when there's no -f
+        None => args.filter.clone().unwrap_or_default(),
+    };
 
-    for v in streamer {
-        let v = v?;
-        let output = filter.apply(&v);
+    // imports are found relative to the program file, or else the current directory
+    let origin = match args.from_file.as_deref().and_then(|p| p.parent()) {
+        Some(dir) => dir.to_path_buf(),
+        None => std::path::PathBuf::from("."),
+    };
+    let file = match &args.from_file {
+        Some(path) => path.display().to_string(),
+        None => "<filter>".to_string(),
+    };
+    let mut modules = match Loader::new(args.library_path.clone()).load(&origin) {
+        Ok(modules) => modules,
+        Err(e) => {
+            eprintln!("jqr: error: {}", e);
+            return Ok(exit::COMPILE);
+        }
+    };
 
-        if let Some(j) = output {
-            println!("{}", j)
+    let text = program;
+    let program = match compile_with(&text, &|name, arity| modules.defines(name, arity)) {
+        Ok(p) => p,
+        Err(d) => {
+            eprintln!("jqr: error: {}", d.render(&file));
+            return Ok(exit::COMPILE);
         }
+    };
+
+    info!("filter: {:?}", program.pipeline);
+
+    if let Err(e) = modules.import(&program) {
+        eprintln!("jqr: error: {}", e);
+        return Ok(exit::COMPILE);
     }
+    if let Err(d) = modules.check(&text, &program) {
+        eprintln!("jqr: error: {}", d.render(&file));
+        return Ok(exit::COMPILE);
+    }
+    let filter = program.pipeline;
+
+    let (named, all, positional) = variables(&args)?;
 
-    Ok(())
+    let stdin = ["-".to_string()];
+    let files = match args.split_positional() {
+        ([], _) => &stdin[..],
+        (files, _) => files,
+    };
+    let on_error = if args.skip_invalid {
+        OnError::Skip
+    } else {
+        args.on_error
+    };
+    let mut inputs = Inputs::files(files, args.raw_input)
+        .on_error(on_error)
+        .buffer_capacity(args.buffer_size);
+    if let Some(path) = &args.reject_file {
+        let file = std::fs::File::create(path)
+            .map_err(|e| anyhow!("Could not open {}: {}", path.display(), e))?;
+        inputs = inputs.reject_to(Box::new(std::io::BufWriter::new(file)));
+    }
+    if args.mmap {
+        // SAFETY: --mmap is the user's word that the files won't change
+        inputs = unsafe { inputs.map_files() };
+    }
+    if args.slurp {
+        inputs = inputs.slurp();
+    } else if !modules.shadow_builtins() {
+        // only the parts of each input the filter can reach are built. slurped
+        // inputs make up one array, which the filter's paths don't describe.
+        // a module's function named like a builtin isn't what the projection
+        // takes it for
+        inputs = inputs.project(filter.projection());
+    }
+
+    // the filter can read inputs too, so it shares the stream with this loop
+    let mut env = Env::with_inputs(inputs);
+    for (name, value) in named {
+        env = env.bind(&name, value);
+    }
+    env = modules.bind(env);
+    env = env.bind("ARGS", all).bind("__prog_args", positional);
+
+    let mut status = Status::default();
+    if args.null_input {
+        run(&filter, &env, JsonValue::Null, &mut status);
+        return Ok(status.code(args.exit_status));
+    }
+
+    // a filter that's nothing but a lookup with a constant path, like `.a.b[0]`,
+    // picks what it looks up out of each input while it's parsed, so the rest
+    // of the input is never copied. any other filter gets each input copied
+    // whole, and so does a lookup on an input it doesn't work on, which the
+    // filter then runs on as usual to report the error
+    let lookup = filter.constant_path().filter(|p| !p.is_empty());
+    let next_input = || match &lookup {
+        Some(path) => env.next_input_with(|v| match v.lookup(path) {
+            Some(found) => Input::Selected(found.to_value()),
+            None => Input::Whole(v.into_value()),
+        }),
+        None => env.next_input().map(|v| v.map(Input::Whole)),
+    };
+
+    // a file that can't be read is reported, but the others still are
+    while let Some(v) = next_input() {
+        match v {
+            Ok(Input::Selected(v)) => {
+                emit(Ok(v), &mut status);
+            }
+            Ok(Input::Whole(v)) => run(&filter, &env, v, &mut status),
+            Err(e) => {
+                eprintln!("jqr: error: {}", e);
+                status.input_error = true;
+            }
+        }
+    }
+    Ok(status.code(args.exit_status))
 }
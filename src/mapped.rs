@@ -0,0 +1,122 @@
+//! reading a regular file mapped into memory. the whole file is there at once,
+//! so values are parsed straight out of it with no buffer to manage, and
+//! there's never more to wait for
+
+use std::fs::File;
+
+use anyhow::Result;
+use memmap2::Mmap;
+
+use crate::json_parser::{JsonValue, JsonValueRef};
+use crate::projection::Projection;
+use crate::streamer::{first_line, parse_last, Position};
+
+/// the JSON values in a file mapped into memory
+pub struct Mapped {
+    map: Mmap,
+    /// where parsing is in `map`
+    at: Position,
+    /// the parts of each value to build
+    projection: Projection,
+}
+
+impl Mapped {
+    /// maps `file` into memory
+    ///
+    /// # Safety
+    ///
+    /// `file` mustn't change until the `Mapped` is dropped. a mapping sees
+    /// changes to the file: one that's truncated faults on the next read of the
+    /// missing pages, and strings that were checked to be UTF-8 as they were
+    /// parsed can stop being so while they're still borrowed
+    pub unsafe fn new(file: &File) -> Result<Self> {
+        // SAFETY: the caller promises that the file won't change under the map
+        let map = unsafe { Mmap::map(file)? };
+        Ok(Self {
+            map,
+            at: Position::default(),
+            projection: Projection::All,
+        })
+    }
+
+    /// only builds the parts of each value in `projection`, skipping the rest
+    pub fn project(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    /// how many lines of the file have been parsed
+    pub fn line_number(&self) -> usize {
+        self.at.lines
+    }
+
+    /// after an error, moves on past the line the bad value started on,
+    /// returning that line and its number
+    pub fn skip_line(&mut self) -> (usize, Vec<u8>) {
+        let rest = &self.map[self.at.offset..];
+        let blank = rest.iter().take_while(|c| c.is_ascii_whitespace()).count();
+        self.at.advance(&rest[..blank]);
+        self.at.records += 1;
+        let number = self.at.lines + 1;
+
+        let rest = &self.map[self.at.offset..];
+        let (n, line) = first_line(rest, true).unwrap_or((rest.len(), rest));
+        let line = line.to_vec();
+        self.at.advance(&rest[..n]);
+        (number, line)
+    }
+
+    /// parses the next value and passes it to `f` as it is in the file,
+    /// returning what `f` makes of it
+    pub fn next_with<T>(&mut self, f: impl FnOnce(JsonValueRef<'_>) -> T) -> Option<Result<T>> {
+        let rest = &self.map[self.at.offset..];
+        match parse_last(rest, &self.at, &self.projection) {
+            Ok((val, n)) => {
+                let out = val.map(f);
+                self.at.advance(&rest[..n]);
+                if out.is_some() {
+                    self.at.records += 1;
+                }
+                out.map(Ok)
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl Iterator for Mapped {
+    type Item = Result<JsonValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(|v| v.into_value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_parser::JsonParseError;
+
+    #[test]
+    fn it_reads_a_mapped_file() {
+        let path = std::env::temp_dir().join(format!("jqr-mapped-{}", std::process::id()));
+        std::fs::write(&path, "{\"a\": 1}\n[2, 3]\n{\"a\": x}\n\n  null").unwrap();
+        // SAFETY: the file is only this test's, and nothing else writes to it
+        let mut mapped = unsafe { Mapped::new(&File::open(&path).unwrap()) }.unwrap();
+
+        assert_eq!(mapped.next().unwrap().unwrap()["a"], JsonValue::Num(1.0));
+        assert_eq!(mapped.next().unwrap().unwrap()[1], JsonValue::Num(3.0));
+        assert_eq!(mapped.line_number(), 2);
+
+        let err = mapped.next().unwrap().unwrap_err();
+        let err = err.downcast::<JsonParseError>().unwrap();
+        assert_eq!((err.line, err.column, err.record), (3, 7, 3));
+        assert_eq!(mapped.skip_line(), (3, b"{\"a\": x}".to_vec()));
+
+        assert_eq!(mapped.next().unwrap().unwrap(), JsonValue::Null);
+        assert_eq!(mapped.line_number(), 4);
+        assert!(mapped.next().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+//! jq's libm-based math builtins. most map straight onto f64's methods; the few
+//! that std doesn't have come from the C math library, which std already links
+
+use std::os::raw::c_int;
+
+use crate::interpreter::RuntimeError;
+use crate::json_parser::JsonValue;
+
+mod libm {
+    use std::os::raw::c_int;
+
+    extern "C" {
+        pub fn exp10(x: f64) -> f64;
+        pub fn significand(x: f64) -> f64;
+        pub fn logb(x: f64) -> f64;
+        pub fn lgamma(x: f64) -> f64;
+        pub fn lgamma_r(x: f64, sign: *mut c_int) -> f64;
+        pub fn tgamma(x: f64) -> f64;
+        pub fn nearbyint(x: f64) -> f64;
+        pub fn rint(x: f64) -> f64;
+        pub fn j0(x: f64) -> f64;
+        pub fn j1(x: f64) -> f64;
+        pub fn y0(x: f64) -> f64;
+        pub fn y1(x: f64) -> f64;
+        pub fn frexp(x: f64, exp: *mut c_int) -> f64;
+        pub fn modf(x: f64, int: *mut f64) -> f64;
+        pub fn drem(x: f64, y: f64) -> f64;
+        pub fn fdim(x: f64, y: f64) -> f64;
+        pub fn fmod(x: f64, y: f64) -> f64;
+        pub fn ldexp(x: f64, exp: c_int) -> f64;
+        pub fn nextafter(x: f64, y: f64) -> f64;
+        pub fn scalb(x: f64, exp: f64) -> f64;
+        pub fn scalbln(x: f64, exp: i64) -> f64;
+    }
+}
+
+pub(crate) type MathFn = fn(&[f64]) -> JsonValue;
+
+fn num(n: f64) -> JsonValue {
+    JsonValue::Num(n)
+}
+
+fn pair(a: f64, b: f64) -> JsonValue {
+    JsonValue::Array(vec![JsonValue::Num(a), JsonValue::Num(b)].into())
+}
+
+/// the math builtin called `name` taking `arity` arguments. the one-argument
+/// functions (`floor`, `sqrt`, ...) are arity 0 in jq and apply to their input
+pub(crate) fn function(name: &str, arity: usize) -> Option<MathFn> {
+    let f: MathFn = match (name, arity) {
+        ("floor", 0) => |x| num(x[0].floor()),
+        ("ceil", 0) => |x| num(x[0].ceil()),
+        ("round", 0) => |x| num(x[0].round()),
+        ("trunc", 0) => |x| num(x[0].trunc()),
+        ("fabs" | "abs", 0) => |x| num(x[0].abs()),
+        ("sqrt", 0) => |x| num(x[0].sqrt()),
+        ("cbrt", 0) => |x| num(x[0].cbrt()),
+        ("exp", 0) => |x| num(x[0].exp()),
+        ("exp2", 0) => |x| num(x[0].exp2()),
+        ("exp10" | "pow10", 0) => |x| num(unsafe { libm::exp10(x[0]) }),
+        ("expm1", 0) => |x| num(x[0].exp_m1()),
+        ("log", 0) => |x| num(x[0].ln()),
+        ("log2", 0) => |x| num(x[0].log2()),
+        ("log10", 0) => |x| num(x[0].log10()),
+        ("log1p", 0) => |x| num(x[0].ln_1p()),
+        ("logb", 0) => |x| num(unsafe { libm::logb(x[0]) }),
+        ("significand", 0) => |x| num(unsafe { libm::significand(x[0]) }),
+        ("sin", 0) => |x| num(x[0].sin()),
+        ("cos", 0) => |x| num(x[0].cos()),
+        ("tan", 0) => |x| num(x[0].tan()),
+        ("asin", 0) => |x| num(x[0].asin()),
+        ("acos", 0) => |x| num(x[0].acos()),
+        ("atan", 0) => |x| num(x[0].atan()),
+        ("sinh", 0) => |x| num(x[0].sinh()),
+        ("cosh", 0) => |x| num(x[0].cosh()),
+        ("tanh", 0) => |x| num(x[0].tanh()),
+        ("asinh", 0) => |x| num(x[0].asinh()),
+        ("acosh", 0) => |x| num(x[0].acosh()),
+        ("atanh", 0) => |x| num(x[0].atanh()),
+        ("gamma" | "lgamma", 0) => |x| num(unsafe { libm::lgamma(x[0]) }),
+        ("tgamma", 0) => |x| num(unsafe { libm::tgamma(x[0]) }),
+        ("nearbyint", 0) => |x| num(unsafe { libm::nearbyint(x[0]) }),
+        ("rint", 0) => |x| num(unsafe { libm::rint(x[0]) }),
+        ("j0", 0) => |x| num(unsafe { libm::j0(x[0]) }),
+        ("j1", 0) => |x| num(unsafe { libm::j1(x[0]) }),
+        ("y0", 0) => |x| num(unsafe { libm::y0(x[0]) }),
+        ("y1", 0) => |x| num(unsafe { libm::y1(x[0]) }),
+        ("lgamma_r", 0) => |x| {
+            let mut sign: c_int = 0;
+            let v = unsafe { libm::lgamma_r(x[0], &mut sign) };
+            pair(v, sign as f64)
+        },
+        ("frexp", 0) => |x| {
+            let mut exp: c_int = 0;
+            let mantissa = unsafe { libm::frexp(x[0], &mut exp) };
+            pair(mantissa, exp as f64)
+        },
+        ("modf", 0) => |x| {
+            let mut int = 0.0;
+            let frac = unsafe { libm::modf(x[0], &mut int) };
+            pair(frac, int)
+        },
+        ("pow", 2) => |x| num(x[0].powf(x[1])),
+        ("atan2", 2) => |x| num(x[0].atan2(x[1])),
+        ("hypot", 2) => |x| num(x[0].hypot(x[1])),
+        ("copysign", 2) => |x| num(x[0].copysign(x[1])),
+        ("fmin", 2) => |x| num(x[0].min(x[1])),
+        ("fmax", 2) => |x| num(x[0].max(x[1])),
+        ("fmod", 2) => |x| num(unsafe { libm::fmod(x[0], x[1]) }),
+        ("fdim", 2) => |x| num(unsafe { libm::fdim(x[0], x[1]) }),
+        ("drem", 2) => |x| num(unsafe { libm::drem(x[0], x[1]) }),
+        ("ldexp", 2) => |x| num(unsafe { libm::ldexp(x[0], x[1] as c_int) }),
+        ("scalb", 2) => |x| num(unsafe { libm::scalb(x[0], x[1]) }),
+        ("scalbln", 2) => |x| num(unsafe { libm::scalbln(x[0], x[1] as i64) }),
+        ("nextafter" | "nexttoward", 2) => |x| num(unsafe { libm::nextafter(x[0], x[1]) }),
+        ("fma", 3) => |x| num(x[0].mul_add(x[1], x[2])),
+        _ => return None,
+    };
+    Some(f)
+}
+
+pub(crate) fn number(v: &JsonValue) -> Result<f64, RuntimeError> {
+    v.as_num()
+        .copied()
+        .ok_or_else(|| RuntimeError::invalid(v, "number required"))
+}
+
+/// calls a math builtin, checking that everything it's given is a number
+pub(crate) fn apply(f: MathFn, values: &[JsonValue]) -> Result<JsonValue, RuntimeError> {
+    let nums = values.iter().map(number).collect::<Result<Vec<_>, _>>()?;
+    Ok(f(&nums))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::run;
+
+    #[test]
+    fn it_works() {
+        let cases = [
+            ("3.7 | floor, ceil, round, trunc", "3, 4, 4, 3"),
+            ("-2.5 | fabs, round", "2.5, -3"),
+            ("16 | sqrt, log2", "4, 4"),
+            ("[1, 10, 100] | [.[] | log10]", "[0, 1, 2]"),
+            ("pow(2; 10), pow(2, 3; 2)", "1024, 4, 9"),
+            ("atan2(1; 1) * 4 | . * 1000 | round", "3142"),
+            (
+                "fma(2; 3; 4), ldexp(3; 2), fmin(1; 2), fmax(1; 2)",
+                "10, 12, 1, 2",
+            ),
+            ("8 | frexp, modf", "[0.5, 4], [0, 8]"),
+            ("3.5 | modf", "[0.5, 3]"),
+            ("8 | significand, logb", "1, 3"),
+            ("5 | tgamma, (lgamma_r | .[1])", "24, 1"),
+            ("2.5 | nearbyint, rint", "2, 2"),
+            ("3 | exp10 | round", "1000"),
+            ("infinite, -infinite, nan | isinfinite", "true, true, false"),
+            ("nan, 1 | isnan", "true, false"),
+            ("1, 0, 1e-310 | isnormal", "true, false, false"),
+            ("[nan] | tostring", r#""[null]""#),
+            ("infinite | tostring", r#""1.7976931348623157e+308""#),
+            ("nan < 1, nan > nan", "true, false"),
+        ];
+
+        for (filter, expected) in cases {
+            assert_eq!(run(filter), run(expected), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn it_rejects_non_numbers() {
+        let err = run(r#""a" | floor"#).expect_err("strings have no floor");
+        assert_eq!(err.to_string(), r#"string ("a") number required"#);
+        let err = run(r#"pow(2; "a")"#).expect_err("strings are not exponents");
+        assert_eq!(err.to_string(), r#"string ("a") number required"#);
+    }
+}
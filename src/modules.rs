@@ -0,0 +1,592 @@
+//! finding and loading the modules a program imports. a module path like
+//! `"a/b"` is looked for as `a/b.jq` or `a/b/b.jq` under each search path in
+//! turn, and data modules as `.json` files the same way.
+//!
+//! `import "path" as name;` makes a code module's functions callable as
+//! `name::f`, `include "path";` makes them callable as they are, and
+//! `import "path" as $name;` binds a data module's values to `$name`. modules
+//! can import other modules, relative to their own directory, but not
+//! themselves, however indirectly. a `~/.jq` file is included in every program
+
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::builtins;
+use crate::inputs::Inputs;
+use crate::interpreter::{Env, RuntimeError};
+use crate::jq_parser::{compile_module, parse_module, Directive, Module, Pipeline, Program};
+use crate::json_parser::JsonValue;
+
+/// where jq looks for modules when no `-L` is given
+const DEFAULT_SEARCH: &[&str] = &["~/.jq", "$ORIGIN/../lib/jq", "$ORIGIN/../lib"];
+
+pub struct Loader {
+    search: Vec<String>,
+}
+
+/// the modules a program pulls in, ready to be bound into its environment
+pub struct Modules {
+    loader: Loader,
+    /// the directory of the program file, or the current directory for a
+    /// program given inline
+    origin: PathBuf,
+    /// every code module loaded, each after the ones it imports
+    loaded: Vec<Loaded>,
+    /// what the program pulls in, `~/.jq` first
+    imports: Vec<Import>,
+}
+
+struct Loaded {
+    /// where the module is, without any symlinks, to tell whether two
+    /// imports are the same module
+    path: PathBuf,
+    module: Module,
+    imports: Vec<Import>,
+    /// the functions it defines or includes, which are what importing it gives
+    exports: Vec<(String, usize)>,
+}
+
+enum Import {
+    /// the functions of the `module`th loaded module, under `prefix::` or,
+    /// for an include, as they are
+    Code {
+        module: usize,
+        prefix: Option<String>,
+    },
+    /// the values of a data module, bound to `$name`
+    Data { name: String, value: JsonValue },
+}
+
+impl Loader {
+    /// searches `paths`, the `-L` options, or jq's defaults if there are none.
+    /// a path can start with `~` for the home directory, `$ORIGIN` for the
+    /// directory jqr is in, or `./` for the directory of the importing file.
+    /// other relative paths are relative to the current directory
+    pub fn new(paths: Vec<String>) -> Self {
+        let search = if paths.is_empty() {
+            DEFAULT_SEARCH.iter().map(|p| p.to_string()).collect()
+        } else {
+            paths
+        };
+        Self { search }
+    }
+
+    /// the modules for a program in `origin`, before its own imports are
+    /// loaded: just `~/.jq`, if that's a file
+    pub fn load(self, origin: &Path) -> Result<Modules> {
+        let mut modules = Modules {
+            loader: self,
+            origin: origin.to_path_buf(),
+            loaded: vec![],
+            imports: vec![],
+        };
+        let home = std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".jq"));
+        if let Some(file) = home.filter(|f| f.is_file()) {
+            let module = modules.load_file(&file, &mut vec![])?;
+            modules.imports.push(Import::Code {
+                module,
+                prefix: None,
+            });
+        }
+        Ok(modules)
+    }
+
+    /// the file for the module `path`. a `search` key in the import's metadata
+    /// is searched before the search paths
+    fn resolve(
+        &self,
+        path: &str,
+        ext: &str,
+        meta: Option<&Pipeline>,
+        origin: &Path,
+    ) -> Result<PathBuf> {
+        if path.is_empty() || path.split('/').any(|c| c.is_empty() || c == "..") {
+            bail!("invalid module path: {}", path);
+        }
+        let last = path.rsplit('/').next().unwrap_or(path);
+
+        // the metadata's paths are relative to the importing file, like `./` ones
+        let mut search = match meta {
+            Some(meta) => meta_search(meta)?
+                .into_iter()
+                .map(|d| match d.starts_with(['~', '$', '/']) {
+                    true => d,
+                    false => format!("./{}", d),
+                })
+                .collect(),
+            None => vec![],
+        };
+        search.extend(self.search.iter().cloned());
+
+        for dir in search.iter().filter_map(|d| expand(d, origin)) {
+            let candidates = [
+                dir.join(format!("{}.{}", path, ext)),
+                dir.join(path).join(format!("{}.{}", last, ext)),
+            ];
+            if let Some(found) = candidates.into_iter().find(|c| c.is_file()) {
+                return Ok(found);
+            }
+        }
+        Err(anyhow!(
+            "module not found: {} (searched {})",
+            path,
+            search.join(", ")
+        ))
+    }
+}
+
+impl Modules {
+    /// loads the modules `program` imports, and the ones they import
+    pub fn import(&mut self, program: &Program) -> Result<()> {
+        let origin = self.origin.clone();
+        for directive in &program.directives {
+            let import = self.import_one(directive, &origin, &mut vec![])?;
+            self.imports.push(import);
+        }
+        Ok(())
+    }
+
+    /// whether what the program imports defines `name`
+    pub fn defines(&self, name: &str, arity: usize) -> bool {
+        self.imports.iter().any(|i| self.exports(i, name, arity))
+    }
+
+    /// whether something the program includes defines a function a builtin
+    /// has the name of. the program can't be projected then, since calls to
+    /// that name no longer mean what they usually do
+    pub fn shadow_builtins(&self) -> bool {
+        self.imports.iter().any(|i| match i {
+            Import::Code {
+                module,
+                prefix: None,
+            } => self.loaded[*module]
+                .exports
+                .iter()
+                .any(|(name, arity)| builtins::is_defined(name, *arity)),
+            _ => false,
+        })
+    }
+
+    /// checks that every call in `program`, whose text is `source`, is to a
+    /// function that's defined now that the modules are loaded
+    pub fn check(&self, source: &str, program: &Program) -> Result<(), crate::Diagnostic> {
+        let defined =
+            |name: &str, arity| builtins::is_defined(name, arity) || self.defines(name, arity);
+        match program.pipeline.undefined_call(&defined) {
+            Some((name, arity)) => Err(crate::diagnostic::undefined_function(source, name, arity)),
+            None => Ok(()),
+        }
+    }
+
+    /// `env` with the functions and variables of the program's imports
+    pub fn bind<'a>(&'a self, env: Env<'a>) -> Env<'a> {
+        let env = env.with_modules(self);
+        let top = env.top_level();
+        let mut envs: Vec<Env<'a>> = vec![];
+        for loaded in &self.loaded {
+            let env = loaded
+                .imports
+                .iter()
+                .fold(top.clone(), |env, import| bind_import(&envs, env, import));
+            envs.push(
+                loaded
+                    .module
+                    .defs
+                    .iter()
+                    .fold(env, |env, def| env.define(def)),
+            );
+        }
+        self.imports
+            .iter()
+            .fold(env, |env, import| bind_import(&envs, env, import))
+    }
+
+    fn import_one(
+        &mut self,
+        directive: &Directive,
+        origin: &Path,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<Import> {
+        match directive {
+            Directive::Import {
+                path,
+                name,
+                data: true,
+                meta,
+            } => {
+                let file = self.loader.resolve(path, "json", meta.as_ref(), origin)?;
+                Ok(Import::Data {
+                    name: name.clone(),
+                    value: read_data(&file)?,
+                })
+            }
+            Directive::Import {
+                path, name, meta, ..
+            } => {
+                let file = self.loader.resolve(path, "jq", meta.as_ref(), origin)?;
+                Ok(Import::Code {
+                    module: self.load_file(&file, stack)?,
+                    prefix: Some(name.clone()),
+                })
+            }
+            Directive::Include { path, meta } => {
+                let file = self.loader.resolve(path, "jq", meta.as_ref(), origin)?;
+                Ok(Import::Code {
+                    module: self.load_file(&file, stack)?,
+                    prefix: None,
+                })
+            }
+        }
+    }
+
+    /// loads the code module in `file`, unless it already has been, and gives
+    /// its index. `stack` is the modules importing it, to catch cycles
+    fn load_file(&mut self, file: &Path, stack: &mut Vec<PathBuf>) -> Result<usize> {
+        let path = file
+            .canonicalize()
+            .map_err(|e| anyhow!("Could not open {}: {}", file.display(), e))?;
+        if let Some(start) = stack.iter().position(|p| *p == path) {
+            let cycle: Vec<_> = stack[start..]
+                .iter()
+                .chain(Some(&path))
+                .map(|p| p.display().to_string())
+                .collect();
+            bail!("import cycle: {}", cycle.join(" -> "));
+        }
+        if let Some(i) = self.loaded.iter().position(|m| m.path == path) {
+            return Ok(i);
+        }
+
+        let text = std::fs::read_to_string(file)
+            .map_err(|e| anyhow!("Could not open {}: {}", file.display(), e))?;
+        let parsed = parse_module(&text).map_err(|e| {
+            anyhow!(
+                "{}",
+                crate::diagnostic::syntax_error(&text, &e).render(&file.display().to_string())
+            )
+        })?;
+
+        let dir = file.parent().unwrap_or(Path::new("."));
+        stack.push(path.clone());
+        let imports = parsed
+            .directives
+            .iter()
+            .map(|d| self.import_one(d, dir, stack))
+            .collect::<Result<Vec<_>>>();
+        stack.pop();
+        let imports = imports?;
+
+        let defined = |name: &str, arity| imports.iter().any(|i| self.exports(i, name, arity));
+        let module = compile_module(&text, &defined)
+            .map_err(|d| anyhow!("{}", d.render(&file.display().to_string())))?;
+        constant_object(module.meta.as_ref())?;
+
+        let mut exports: Vec<_> = imports
+            .iter()
+            .filter_map(|i| match i {
+                Import::Code {
+                    module,
+                    prefix: None,
+                } => Some(self.loaded[*module].exports.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        exports.extend(module.defs.iter().map(|d| (d.name.clone(), d.params.len())));
+
+        self.loaded.push(Loaded {
+            path,
+            module,
+            imports,
+            exports,
+        });
+        Ok(self.loaded.len() - 1)
+    }
+
+    fn exports(&self, import: &Import, name: &str, arity: usize) -> bool {
+        let (module, name) = match import {
+            Import::Code {
+                module,
+                prefix: None,
+            } => (module, name),
+            Import::Code {
+                module,
+                prefix: Some(prefix),
+            } => match name
+                .strip_prefix(prefix.as_str())
+                .and_then(|n| n.strip_prefix("::"))
+            {
+                Some(name) => (module, name),
+                None => return false,
+            },
+            Import::Data { .. } => return false,
+        };
+        self.loaded[*module]
+            .exports
+            .iter()
+            .any(|(n, a)| n == name && *a == arity)
+    }
+}
+
+/// `env` with what `import` pulls in. `envs` are the environments the loaded
+/// modules' functions were defined in
+fn bind_import<'a>(envs: &[Env<'a>], env: Env<'a>, import: &Import) -> Env<'a> {
+    match import {
+        Import::Code { module, prefix } => env.import(&envs[*module], prefix.as_deref()),
+        // jq 1.6 calls it `$name::name`, later versions just `$name`
+        Import::Data { name, value } => env
+            .bind(&format!("{}::{}", name, name), value.clone())
+            .bind(name, value.clone()),
+    }
+}
+
+/// `modulemeta`: the metadata of the module `name`, with what it imports as
+/// `deps` and the functions it defines as `defs`. it's found the way the
+/// program's imports were, or else in jq's default places
+pub(crate) fn meta(modules: Option<&Modules>, name: &str) -> Result<JsonValue, RuntimeError> {
+    let meta = match modules {
+        Some(m) => module_meta(&m.loader, name, &m.origin),
+        None => module_meta(&Loader::new(vec![]), name, Path::new(".")),
+    };
+    meta.map_err(|e| RuntimeError::Custom(JsonValue::Str(e.to_string())))
+}
+
+fn module_meta(loader: &Loader, name: &str, origin: &Path) -> Result<JsonValue> {
+    let file = loader.resolve(name, "jq", None, origin)?;
+    let text = std::fs::read_to_string(&file)
+        .map_err(|e| anyhow!("Could not open {}: {}", file.display(), e))?;
+    let module = parse_module(&text).map_err(|e| {
+        anyhow!(
+            "{}",
+            crate::diagnostic::syntax_error(&text, &e).render(&file.display().to_string())
+        )
+    })?;
+
+    let mut meta = constant_object(module.meta.as_ref())?;
+    let deps = module
+        .directives
+        .iter()
+        .map(|d| {
+            let (path, name, data, dep_meta) = match d {
+                Directive::Import {
+                    path,
+                    name,
+                    data,
+                    meta,
+                } => (path, Some(name), *data, meta),
+                Directive::Include { path, meta } => (path, None, false, meta),
+            };
+            let mut dep = constant_object(dep_meta.as_ref())?;
+            if let Some(name) = name {
+                dep.insert("as".to_string(), JsonValue::Str(name.clone()));
+            }
+            dep.insert("is_data".to_string(), JsonValue::Boolean(data));
+            dep.insert("relpath".to_string(), JsonValue::Str(path.clone()));
+            Ok(JsonValue::Object(dep.into()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let defs = module
+        .defs
+        .iter()
+        .map(|d| JsonValue::Str(format!("{}/{}", d.name, d.params.len())))
+        .collect::<Vec<_>>();
+    meta.insert("deps".to_string(), JsonValue::Array(deps.into()));
+    meta.insert("defs".to_string(), JsonValue::Array(defs.into()));
+    Ok(JsonValue::Object(meta.into()))
+}
+
+/// module or import metadata, which has to be a constant object
+fn constant_object(
+    meta: Option<&Pipeline>,
+) -> Result<std::collections::BTreeMap<String, JsonValue>> {
+    let meta = match meta {
+        Some(meta) => meta
+            .apply(JsonValue::Null)
+            .next()
+            .transpose()
+            .map_err(|e| anyhow!("module metadata must be constant: {}", e))?,
+        None => None,
+    };
+    match meta {
+        None => Ok(Default::default()),
+        Some(JsonValue::Object(o)) => Ok(Rc::unwrap_or_clone(o)),
+        Some(_) => bail!("module metadata must be an object"),
+    }
+}
+
+/// the search paths an import's metadata gives, as a string or an array of them
+fn meta_search(meta: &Pipeline) -> Result<Vec<String>> {
+    match constant_object(Some(meta))?.get("search") {
+        None | Some(JsonValue::Null) => Ok(vec![]),
+        Some(JsonValue::Str(s)) => Ok(vec![s.clone()]),
+        Some(JsonValue::Array(a)) => a
+            .iter()
+            .map(|s| match s {
+                JsonValue::Str(s) => Ok(s.clone()),
+                _ => Err(anyhow!("module search paths must be strings")),
+            })
+            .collect(),
+        Some(_) => bail!("module search paths must be strings"),
+    }
+}
+
+/// a search path as a directory, or `None` if it names a place that doesn't exist
+fn expand(dir: &str, origin: &Path) -> Option<PathBuf> {
+    if dir == "~" || dir.starts_with("~/") {
+        let home = PathBuf::from(std::env::var_os("HOME")?);
+        return Some(home.join(dir.trim_start_matches('~').trim_start_matches('/')));
+    }
+    if let Some(rest) = dir.strip_prefix("$ORIGIN") {
+        let exe = std::env::current_exe().ok()?;
+        return Some(exe.parent()?.join(rest.trim_start_matches('/')));
+    }
+    if dir == "." || dir.starts_with("./") {
+        return Some(origin.join(dir));
+    }
+    Some(PathBuf::from(dir))
+}
+
+/// all the JSON values in a data module, as an array
+fn read_data(file: &Path) -> Result<JsonValue> {
+    let reader = std::fs::File::open(file)
+        .map_err(|e| anyhow!("Could not open {}: {}", file.display(), e))?;
+    let values = Inputs::new(Box::new(reader), Some(file.display().to_string()))
+        .slurp()
+        .next()
+        .transpose()?;
+    Ok(values.unwrap_or(JsonValue::Array(vec![].into())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jq_parser::parse_program;
+
+    /// `text` with its imports loaded from `dir`, searching `search`
+    fn load(dir: &Path, text: &str, search: &[&str]) -> Result<(Program, Modules)> {
+        let program = parse_program(text).expect("the program parses");
+        let search = search.iter().map(|s| s.to_string()).collect();
+        let mut modules = Loader::new(search).load(dir)?;
+        modules.import(&program)?;
+        Ok((program, modules))
+    }
+
+    fn run(dir: &Path, text: &str) -> Result<Vec<JsonValue>> {
+        let lib = dir.join("lib").display().to_string();
+        let (program, modules) = load(dir, text, &[&lib])?;
+        let env = modules.bind(Env::default());
+        let outputs = program.pipeline.apply_with(&env, JsonValue::Null);
+        outputs
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jqr-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(dir.join("lib/nested")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn it_loads_data_modules() {
+        let dir = temp_dir("data");
+        std::fs::write(dir.join("lib/data.json"), "[1] [2]").unwrap();
+        std::fs::write(dir.join("lib/nested/nested.json"), "{}").unwrap();
+
+        let data: JsonValue = "[[1], [2]]".parse().unwrap();
+        assert_eq!(
+            run(&dir, r#"import "data" as $d; $d, $d::d"#).unwrap(),
+            vec![data.clone(), data]
+        );
+        assert_eq!(
+            run(&dir, r#"import "nested" as $n {search: "./lib"}; $n"#).unwrap(),
+            vec!["[{}]".parse().unwrap()]
+        );
+
+        let err = load(&dir, r#"import "data" as $d; ."#, &["./elsewhere"])
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().starts_with("module not found: data"),
+            "{}",
+            err
+        );
+        assert!(load(&dir, r#"import "../data" as $d; ."#, &["./lib"]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_loads_code_modules() {
+        let dir = temp_dir("code");
+        let lib = dir.join("lib").display().to_string();
+        let write =
+            |name: &str, text: &str| std::fs::write(dir.join("lib").join(name), text).unwrap();
+        write(
+            "a.jq",
+            "module {name: \"a\"};\nimport \"b\" as b;\ninclude \"c\";\ndef twice(f): f, f;\ndef both: [b::one, inc];",
+        );
+        write("b.jq", "def one: 1;");
+        write("c.jq", "def inc: . + 1;");
+        write(
+            "nested/nested.jq",
+            "import \"a\" as a {search: \"..\"}; def three: a::twice(3);",
+        );
+
+        let outputs = |text| run(&dir, text).unwrap();
+        assert_eq!(
+            outputs(r#"import "a" as a; [a::twice(2)], (0 | a::both), (1 | a::inc)"#),
+            run_expected("[2, 2], [1, 1], 2")
+        );
+        assert_eq!(
+            outputs(r#"include "a"; [twice(1)]"#),
+            run_expected("[1, 1]")
+        );
+        assert_eq!(
+            outputs(r#"import "nested" as n; [n::three]"#),
+            run_expected("[3, 3]")
+        );
+
+        // what a module imports under a prefix stays its own
+        let (program, modules) = load(&dir, r#"import "a" as a; a::one"#, &[&lib]).unwrap();
+        assert_eq!(
+            modules
+                .check("import \"a\" as a; a::one", &program)
+                .unwrap_err()
+                .message(),
+            "a::one/0 is not defined"
+        );
+
+        write("x.jq", "import \"y\" as y; def x: 1;");
+        write("y.jq", "include \"x\"; def y: 1;");
+        let err = load(&dir, r#"import "x" as x; ."#, &[&lib]).err().unwrap();
+        assert!(err.to_string().starts_with("import cycle: "), "{}", err);
+        assert!(err.to_string().ends_with("x.jq"), "{}", err);
+
+        write("bad.jq", "def f: nope;");
+        let err = load(&dir, r#"include "bad"; ."#, &[&lib]).err().unwrap();
+        assert!(
+            err.to_string().starts_with("nope/0 is not defined"),
+            "{}",
+            err
+        );
+
+        let modules = Loader::new(vec![lib]).load(&dir).unwrap();
+        assert_eq!(
+            meta(Some(&modules), "a").unwrap(),
+            r#"{"name": "a", "deps": [{"as": "b", "is_data": false, "relpath": "b"},
+                {"is_data": false, "relpath": "c"}], "defs": ["twice/1", "both/0"]}"#
+                .parse()
+                .unwrap()
+        );
+        assert!(meta(Some(&modules), "nope").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn run_expected(expected: &str) -> Vec<JsonValue> {
+        crate::test_util::run(expected).unwrap()
+    }
+}
@@ -0,0 +1,317 @@
+//! jq's path operations. a path is an array of object keys, array indices and
+//! slices (`{"start": s, "end": e}`) leading from a value down to one of its
+//! children; `path(f)` produces them and `getpath`, `setpath` and `delpaths`
+//! consume them.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::interpreter::RuntimeError;
+use crate::json_parser::{compare_slices, JsonValue};
+
+pub(crate) type Path = Vec<JsonValue>;
+
+// jq refuses to pad arrays out to absurd lengths, so do we
+const MAX_ARRAY_INDEX: usize = 536_870_912;
+
+fn cannot_index(v: &JsonValue, key: &JsonValue) -> RuntimeError {
+    match key {
+        JsonValue::Str(k) => {
+            RuntimeError::Type(format!("Cannot index {} with \"{}\"", v.type_name(), k))
+        }
+        _ => RuntimeError::Type(format!(
+            "Cannot index {} with {}",
+            v.type_name(),
+            key.type_name()
+        )),
+    }
+}
+
+/// resolves a (possibly negative) jq array index against an array of length `len`.
+/// returns None if it points before the start of the array
+fn array_index(len: usize, n: f64) -> Option<usize> {
+    let idx = n.floor() as i64;
+    let idx = if idx < 0 { idx + len as i64 } else { idx };
+    usize::try_from(idx).ok()
+}
+
+/// builds the path component jq uses for `.[from:to]`
+pub(crate) fn slice_key(from: JsonValue, to: JsonValue) -> JsonValue {
+    [("start".to_string(), from), ("end".to_string(), to)]
+        .into_iter()
+        .collect()
+}
+
+/// resolves a slice key against a sequence of length `len`, clamping to its bounds
+fn slice_range(
+    len: usize,
+    key: &BTreeMap<String, JsonValue>,
+) -> Result<(usize, usize), RuntimeError> {
+    let bound = |name: &str, default: f64| match key.get(name) {
+        None | Some(JsonValue::Null) => Ok(default),
+        Some(JsonValue::Num(n)) => Ok(*n),
+        Some(_) => Err(RuntimeError::Type(
+            "Start and end indices of an array slice must be numbers".to_string(),
+        )),
+    };
+    let len_f = len as f64;
+    let resolve = |n: f64| if n < 0.0 { n + len_f } else { n }.clamp(0.0, len_f);
+
+    let start = resolve(bound("start", 0.0)?).floor() as usize;
+    let end = resolve(bound("end", len_f)?).ceil() as usize;
+    Ok((start, end.max(start)))
+}
+
+fn is_slice(key: &BTreeMap<String, JsonValue>) -> bool {
+    key.keys().all(|k| k == "start" || k == "end")
+}
+
+/// `.[key]`
+pub(crate) fn get(v: &JsonValue, key: &JsonValue) -> Result<JsonValue, RuntimeError> {
+    match (v, key) {
+        (JsonValue::Object(o), JsonValue::Str(k)) => {
+            Ok(o.get(k).cloned().unwrap_or(JsonValue::Null))
+        }
+        (JsonValue::Array(a), JsonValue::Num(n)) => Ok(array_index(a.len(), *n)
+            .and_then(|i| a.get(i))
+            .cloned()
+            .unwrap_or(JsonValue::Null)),
+        (JsonValue::Null, JsonValue::Str(_) | JsonValue::Num(_) | JsonValue::Object(_)) => {
+            Ok(JsonValue::Null)
+        }
+        (JsonValue::Array(a), JsonValue::Object(k)) if is_slice(k) => {
+            let (start, end) = slice_range(a.len(), k)?;
+            Ok(JsonValue::Array(a[start..end].to_vec().into()))
+        }
+        (JsonValue::Str(s), JsonValue::Object(k)) if is_slice(k) => {
+            let chars = s.chars().collect::<Vec<_>>();
+            let (start, end) = slice_range(chars.len(), k)?;
+            Ok(JsonValue::Str(chars[start..end].iter().collect()))
+        }
+        _ => Err(cannot_index(v, key)),
+    }
+}
+
+/// `.field`, taking the child out of `v` rather than cloning it if nothing
+/// else shares the object
+pub(crate) fn get_field(v: JsonValue, field: &str) -> Result<JsonValue, RuntimeError> {
+    match v {
+        JsonValue::Object(o) => Ok(match Rc::try_unwrap(o) {
+            Ok(mut o) => o.remove(field),
+            Err(o) => o.get(field).cloned(),
+        }
+        .unwrap_or(JsonValue::Null)),
+        JsonValue::Null => Ok(JsonValue::Null),
+        v => Err(cannot_index(&v, &JsonValue::Str(field.to_string()))),
+    }
+}
+
+/// `.[]`
+pub(crate) fn iterate(v: JsonValue) -> Result<Vec<JsonValue>, RuntimeError> {
+    match v {
+        JsonValue::Array(a) => Ok(Rc::unwrap_or_clone(a)),
+        JsonValue::Object(o) => Ok(match Rc::try_unwrap(o) {
+            Ok(o) => o.into_values().collect(),
+            Err(o) => o.values().cloned().collect(),
+        }),
+        v => Err(RuntimeError::Type(format!(
+            "Cannot iterate over {}",
+            v.type_name()
+        ))),
+    }
+}
+
+/// `.[]`, keeping the key or index each child lives at
+pub(crate) fn entries(v: JsonValue) -> Result<Vec<(JsonValue, JsonValue)>, RuntimeError> {
+    match v {
+        JsonValue::Array(a) => Ok(Rc::unwrap_or_clone(a)
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| (JsonValue::Num(i as f64), e))
+            .collect()),
+        JsonValue::Object(o) => Ok(Rc::unwrap_or_clone(o)
+            .into_iter()
+            .map(|(k, e)| (JsonValue::Str(k), e))
+            .collect()),
+        v => Err(RuntimeError::Type(format!(
+            "Cannot iterate over {}",
+            v.type_name()
+        ))),
+    }
+}
+
+pub(crate) fn getpath(v: &JsonValue, path: &[JsonValue]) -> Result<JsonValue, RuntimeError> {
+    match path.split_first() {
+        None => Ok(v.clone()),
+        Some(_) if v.is_null() => Ok(JsonValue::Null),
+        Some((key, rest)) => getpath(&get(v, key)?, rest),
+    }
+}
+
+pub(crate) fn setpath(
+    v: JsonValue,
+    path: &[JsonValue],
+    new: JsonValue,
+) -> Result<JsonValue, RuntimeError> {
+    let mut new = Some(new);
+    update(v, path, &mut |_| Ok(new.take()))
+}
+
+/// replaces the value at `path` with `f` of it, creating the path if it doesn't
+/// exist. the old value is moved out of `v` and handed to `f` rather than copied.
+/// if `f` returns None the path is deleted instead
+pub(crate) fn update(
+    v: JsonValue,
+    path: &[JsonValue],
+    f: &mut dyn FnMut(JsonValue) -> Result<Option<JsonValue>, RuntimeError>,
+) -> Result<JsonValue, RuntimeError> {
+    let (key, rest) = match path.split_first() {
+        None => return Ok(f(v)?.unwrap_or(JsonValue::Null)),
+        Some(split) => split,
+    };
+
+    match (v, key) {
+        (JsonValue::Null, JsonValue::Str(_)) => {
+            update(JsonValue::Object(BTreeMap::new().into()), path, f)
+        }
+        (JsonValue::Null, JsonValue::Num(_) | JsonValue::Object(_)) => {
+            update(JsonValue::Array(vec![].into()), path, f)
+        }
+        (JsonValue::Object(o), JsonValue::Str(k)) => {
+            // the object is only copied if something else shares it
+            let mut o = Rc::unwrap_or_clone(o);
+            let child = o.remove(k).unwrap_or(JsonValue::Null);
+            let updated = if rest.is_empty() {
+                f(child)?
+            } else {
+                Some(update(child, rest, f)?)
+            };
+            if let Some(updated) = updated {
+                o.insert(k.clone(), updated);
+            }
+            Ok(JsonValue::Object(o.into()))
+        }
+        (JsonValue::Array(a), JsonValue::Num(n)) => {
+            let mut a = Rc::unwrap_or_clone(a);
+            let idx = array_index(a.len(), *n).ok_or_else(|| {
+                RuntimeError::Path("Out of bounds negative array index".to_string())
+            })?;
+            if idx > MAX_ARRAY_INDEX {
+                return Err(RuntimeError::Path("Array index too large".to_string()));
+            }
+            let len = a.len();
+            if idx >= len {
+                a.resize(idx + 1, JsonValue::Null);
+            }
+            let child = std::mem::replace(&mut a[idx], JsonValue::Null);
+            let updated = if rest.is_empty() {
+                f(child)?
+            } else {
+                Some(update(child, rest, f)?)
+            };
+            match updated {
+                Some(updated) => a[idx] = updated,
+                None if idx < len => {
+                    a.remove(idx);
+                }
+                // there's nothing past the end to delete, so it isn't padded out either
+                None => a.truncate(len),
+            }
+            Ok(JsonValue::Array(a.into()))
+        }
+        (JsonValue::Array(a), JsonValue::Object(k)) if is_slice(k) => {
+            let mut a = Rc::unwrap_or_clone(a);
+            let (start, end) = slice_range(a.len(), k)?;
+            let slice = JsonValue::Array(a[start..end].to_vec().into());
+            let updated = if rest.is_empty() {
+                f(slice)?
+            } else {
+                Some(update(slice, rest, f)?)
+            };
+            match updated {
+                Some(JsonValue::Array(replacement)) => {
+                    a.splice(start..end, Rc::unwrap_or_clone(replacement));
+                }
+                Some(_) => {
+                    return Err(RuntimeError::Type(
+                        "A slice of an array can only be assigned another array".to_string(),
+                    ))
+                }
+                None => {
+                    a.drain(start..end);
+                }
+            }
+            Ok(JsonValue::Array(a.into()))
+        }
+        (v, key) => Err(cannot_index(&v, key)),
+    }
+}
+
+/// deletes every path in `paths`. they're removed deepest-and-last first so that
+/// deleting one array element doesn't shift the indices of the others
+pub(crate) fn delpaths(mut v: JsonValue, mut paths: Vec<Path>) -> Result<JsonValue, RuntimeError> {
+    paths.sort_by(|a, b| compare_slices(a, b));
+    for path in paths.iter().rev() {
+        v = delpath(v, path)?;
+    }
+    Ok(v)
+}
+
+fn delpath(v: JsonValue, path: &[JsonValue]) -> Result<JsonValue, RuntimeError> {
+    let (key, rest) = match path.split_first() {
+        None => return Ok(JsonValue::Null),
+        Some(split) => split,
+    };
+
+    match (v, key) {
+        (JsonValue::Null, _) => Ok(JsonValue::Null),
+        (JsonValue::Object(o), JsonValue::Str(k)) => {
+            let mut o = Rc::unwrap_or_clone(o);
+            if rest.is_empty() {
+                o.remove(k);
+            } else if let Some(child) = o.remove(k) {
+                o.insert(k.clone(), delpath(child, rest)?);
+            }
+            Ok(JsonValue::Object(o.into()))
+        }
+        (JsonValue::Array(a), JsonValue::Num(n)) => {
+            let mut a = Rc::unwrap_or_clone(a);
+            match array_index(a.len(), *n).filter(|i| *i < a.len()) {
+                Some(idx) if rest.is_empty() => {
+                    a.remove(idx);
+                }
+                Some(idx) => {
+                    let child = std::mem::replace(&mut a[idx], JsonValue::Null);
+                    a[idx] = delpath(child, rest)?;
+                }
+                None if *n < 0.0 && rest.is_empty() => {
+                    return Err(RuntimeError::Path(
+                        "Out of bounds negative array index".to_string(),
+                    ))
+                }
+                None => {}
+            }
+            Ok(JsonValue::Array(a.into()))
+        }
+        (JsonValue::Array(a), JsonValue::Object(k)) if is_slice(k) => {
+            let mut a = Rc::unwrap_or_clone(a);
+            let (start, end) = slice_range(a.len(), k)?;
+            if rest.is_empty() {
+                a.drain(start..end);
+            } else {
+                match delpath(JsonValue::Array(a[start..end].to_vec().into()), rest)? {
+                    JsonValue::Array(replacement) => {
+                        a.splice(start..end, Rc::unwrap_or_clone(replacement));
+                    }
+                    _ => unreachable!("deleting inside an array leaves an array"),
+                }
+            }
+            Ok(JsonValue::Array(a.into()))
+        }
+        (v, key) => Err(RuntimeError::Type(format!(
+            "Cannot delete field at {} index of {}",
+            key.type_name(),
+            v.type_name()
+        ))),
+    }
+}
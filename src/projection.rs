@@ -0,0 +1,231 @@
+//! working out which parts of its input a filter can reach, so the parser can
+//! skip the rest. only object members are ever left out: everything else about
+//! a value, like what type it is, is always there for the filter to see
+
+use std::collections::BTreeMap;
+
+use crate::jq_parser::{Filter, ObjectKey, Pipeline, StringPart};
+use crate::json_parser::JsonValue;
+
+/// the parts of a value that are needed
+#[derive(Debug, PartialEq, Clone)]
+pub enum Projection {
+    /// the whole value
+    All,
+    /// if it's an object, only these members of it, and only so much of each.
+    /// anything else is needed whole
+    Fields(BTreeMap<String, Projection>),
+}
+
+/// builtins that don't look at their input at all
+const IGNORE_INPUT: &[&str] = &["empty", "env", "input_filename", "input_line_number", "now"];
+
+impl Projection {
+    /// none of an object's members, though the value itself is still needed
+    pub fn none() -> Self {
+        Projection::Fields(BTreeMap::new())
+    }
+
+    /// what `self` and `other` need between them
+    fn union(self, other: Projection) -> Projection {
+        match (self, other) {
+            (Projection::Fields(mut a), Projection::Fields(b)) => {
+                for (k, v) in b {
+                    let v = match a.remove(&k) {
+                        Some(w) => w.union(v),
+                        None => v,
+                    };
+                    a.insert(k, v);
+                }
+                Projection::Fields(a)
+            }
+            _ => Projection::All,
+        }
+    }
+
+    /// `self`, of what's at `path` in the value. an index into an array needs
+    /// the whole array, since arrays are never cut down
+    fn under(self, path: &[JsonValue]) -> Projection {
+        path.iter().rev().fold(self, |need, key| match key {
+            JsonValue::Str(k) => Projection::Fields(BTreeMap::from([(k.clone(), need)])),
+            _ => Projection::All,
+        })
+    }
+}
+
+impl Pipeline {
+    /// the parts of its input the pipeline can reach. a pipeline that reads
+    /// more inputs itself needs those whole, and they're parsed the same way
+    pub fn projection(&self) -> Projection {
+        if self
+            .calls()
+            .iter()
+            .any(|(name, _)| matches!(*name, "input" | "inputs"))
+        {
+            return Projection::All;
+        }
+        self.reach(Projection::All)
+    }
+
+    /// how much of its input the pipeline needs for `out` of its output. each
+    /// filter's output is the next one's input, so it works back from the end
+    fn reach(&self, out: Projection) -> Projection {
+        self.filters.iter().rev().fold(out, |out, f| f.reach(out))
+    }
+}
+
+impl Filter {
+    /// how much of its input the filter needs for `out` of its output
+    fn reach(&self, out: Projection) -> Projection {
+        let all = |p: &Pipeline| p.reach(Projection::All);
+        match self {
+            Filter::FieldAccessor { fields } => {
+                let path: Vec<_> = fields.iter().cloned().map(JsonValue::Str).collect();
+                out.under(&path)
+            }
+            Filter::Index { target, index } => match index.constant_key() {
+                Some(key) => target.reach(out.under(&[key])),
+                None => target.reach(Projection::All).union(all(index)),
+            },
+            Filter::Literal(_) | Filter::Variable(_) => Projection::none(),
+            // a function's body could do anything with its input
+            Filter::Recurse | Filter::Format(_) | Filter::Assign { .. } | Filter::Define { .. } => {
+                Projection::All
+            }
+            Filter::StringInterpolation { parts, .. } => {
+                parts
+                    .iter()
+                    .fold(Projection::none(), |need, part| match part {
+                        StringPart::Interpolation(p) => need.union(all(p)),
+                        StringPart::Literal(_) => need,
+                    })
+            }
+            Filter::Slice { target, from, to } => [from, to]
+                .into_iter()
+                .flatten()
+                .fold(target.reach(Projection::All), |need, p| need.union(all(p))),
+            Filter::Iterate { target } | Filter::Negate(target) => target.reach(Projection::All),
+            // the handler's input is the error, not this filter's input
+            Filter::Try { body, .. } => body.reach(out),
+            Filter::Parens(p) => p.reach(out),
+            Filter::ArrayConstruction(p) => p.as_deref().map_or(Projection::none(), all),
+            Filter::ObjectConstruction(entries) => {
+                entries
+                    .iter()
+                    .fold(Projection::none(), |need, (key, value)| {
+                        let need = match key {
+                            ObjectKey::Expr(p) => need.union(all(p)),
+                            ObjectKey::Literal(_) => need,
+                        };
+                        match (key, value) {
+                            (_, Some(p)) => need.union(all(p)),
+                            // `{a}` looks `a` up in the input
+                            (ObjectKey::Literal(k), None) => {
+                                need.union(Projection::All.under(&[JsonValue::Str(k.clone())]))
+                            }
+                            (ObjectKey::Expr(_), None) => Projection::All,
+                        }
+                    })
+            }
+            Filter::Comma(filters) => filters.iter().fold(Projection::none(), |need, f| {
+                need.union(f.reach(out.clone()))
+            }),
+            Filter::Alternative(lhs, rhs) => lhs.reach(out.clone()).union(rhs.reach(out)),
+            Filter::And(lhs, rhs) | Filter::Or(lhs, rhs) | Filter::Operation { lhs, rhs, .. } => {
+                lhs.reach(Projection::All).union(rhs.reach(Projection::All))
+            }
+            Filter::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let otherwise = match otherwise {
+                    Some(p) => p.reach(out.clone()),
+                    // no else passes the input through
+                    None => out.clone(),
+                };
+                all(cond).union(then.reach(out)).union(otherwise)
+            }
+            // the body runs on the same input as the source
+            Filter::Bind { source, body, .. } => {
+                source.reach(Projection::All).union(body.reach(out))
+            }
+            // the update and extract run on the state, not the input
+            Filter::Reduce { source, init, .. } | Filter::Foreach { source, init, .. } => {
+                source.reach(Projection::All).union(all(init))
+            }
+            Filter::FunctionCall { name, args, .. } => match (name.as_str(), args.as_slice()) {
+                // select passes its input through
+                ("select", [cond]) => all(cond).union(out),
+                (name, []) if IGNORE_INPUT.contains(&name) => Projection::none(),
+                _ => Projection::All,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jq_parser::parse_filter;
+
+    fn projection(filter: &str) -> Projection {
+        parse_filter(filter).unwrap().projection()
+    }
+
+    /// a projection written as JSON, with `true` for everything
+    fn parse(json: &str) -> Projection {
+        fn from(v: &JsonValue) -> Projection {
+            match v {
+                JsonValue::Object(o) => {
+                    Projection::Fields(o.iter().map(|(k, v)| (k.clone(), from(v))).collect())
+                }
+                _ => Projection::All,
+            }
+        }
+        from(&json.parse().unwrap())
+    }
+
+    #[test]
+    fn it_works_out_what_a_filter_reaches() {
+        let cases = [
+            (".a.b", r#"{"a": {"b": true}}"#),
+            (".a | .b, .c", r#"{"a": {"b": true, "c": true}}"#),
+            (r#".a["b"][0].c"#, r#"{"a": {"b": true}}"#),
+            (
+                r#"select(.type == "x") | .id"#,
+                r#"{"type": true, "id": true}"#,
+            ),
+            ("{a, b: .c.d}", r#"{"a": true, "c": {"d": true}}"#),
+            (
+                r#"if .a then .b.c else "no" end"#,
+                r#"{"a": true, "b": {"c": true}}"#,
+            ),
+            (".a as $x | .b | 1", r#"{"a": true, "b": {}}"#),
+            (
+                "reduce .a[] as $x (.n; . + $x)",
+                r#"{"a": true, "n": true}"#,
+            ),
+            ("[.a, input_line_number]", r#"{"a": true}"#),
+            (r#""\(.a) and \(.b.c)""#, r#"{"a": true, "b": {"c": true}}"#),
+            (".a // .b | .c", r#"{"a": {"c": true}, "b": {"c": true}}"#),
+            (".a[.i]", r#"{"a": true, "i": true}"#),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(projection(filter), parse(expected), "{}", filter);
+        }
+
+        for filter in [
+            ".",
+            "..",
+            "keys",
+            ".[0]",
+            ".[]",
+            "[inputs | .a]",
+            "tojson",
+            ".a = 1",
+        ] {
+            assert_eq!(projection(filter), Projection::All, "{}", filter);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! regular expressions, using the same engine (oniguruma) and flag letters as jq
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use onig::{Regex, RegexOptions, Region, SearchOptions, Syntax};
+
+use crate::interpreter::RuntimeError;
+use crate::json_parser::JsonValue;
+
+/// a compiled regex along with whether the `g` flag asked for every match
+pub(crate) struct Pattern {
+    regex: Regex,
+    global: bool,
+    /// the name of each capture group, by group number
+    names: Vec<Option<String>>,
+}
+
+impl Pattern {
+    /// compiles `re` with jq's flag letters, e.g. `"gi"`. null means no flags
+    pub(crate) fn new(re: &JsonValue, flags: &JsonValue) -> Result<Pattern, RuntimeError> {
+        let re = match re {
+            JsonValue::Str(re) => re,
+            re => return Err(RuntimeError::invalid(re, "is not a string")),
+        };
+        let flags = match flags {
+            JsonValue::Null => "",
+            JsonValue::Str(flags) => flags,
+            flags => return Err(RuntimeError::invalid(flags, "is not a string")),
+        };
+
+        let mut options = RegexOptions::REGEX_OPTION_CAPTURE_GROUP;
+        let mut global = false;
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'i' => options |= RegexOptions::REGEX_OPTION_IGNORECASE,
+                'x' => options |= RegexOptions::REGEX_OPTION_EXTEND,
+                'n' => options |= RegexOptions::REGEX_OPTION_FIND_NOT_EMPTY,
+                's' => options |= RegexOptions::REGEX_OPTION_SINGLELINE,
+                'p' => {
+                    options |=
+                        RegexOptions::REGEX_OPTION_MULTILINE | RegexOptions::REGEX_OPTION_SINGLELINE
+                }
+                'l' => options |= RegexOptions::REGEX_OPTION_FIND_LONGEST,
+                _ => {
+                    return Err(RuntimeError::Type(format!(
+                        "{} is not a valid modifier string",
+                        flags
+                    )))
+                }
+            }
+        }
+
+        let regex = Regex::with_options(re, options, Syntax::perl_ng()).map_err(|e| {
+            RuntimeError::Type(format!(
+                "{} (at offset 0) is not a valid regex: {}",
+                re,
+                e.description()
+            ))
+        })?;
+        let mut names = vec![None; regex.captures_len() + 1];
+        regex.foreach_name(|name, groups| {
+            for g in groups {
+                names[*g as usize] = Some(name.to_string());
+            }
+            true
+        });
+        Ok(Pattern {
+            regex,
+            global,
+            names,
+        })
+    }
+
+    pub(crate) fn is_match(&self, s: &str) -> bool {
+        !self.search(s, false).is_empty()
+    }
+
+    /// the regions matched in `s`: just the first one, or all of them with `g`
+    pub(crate) fn find(&self, s: &str) -> Vec<Region> {
+        self.search(s, self.global)
+    }
+
+    /// every region matched in `s`, whether or not `g` was given
+    pub(crate) fn find_all(&self, s: &str) -> Vec<Region> {
+        self.search(s, true)
+    }
+
+    /// like jq, after an empty match the search moves on by one character, so
+    /// `"ab" | match(""; "g")` matches at 0, 1 and 2
+    fn search(&self, s: &str, global: bool) -> Vec<Region> {
+        let mut regions = vec![];
+        let mut start = 0;
+        while start <= s.len() {
+            let mut region = Region::new();
+            let found = self.regex.search_with_options(
+                s,
+                start,
+                s.len(),
+                SearchOptions::SEARCH_OPTION_NONE,
+                Some(&mut region),
+            );
+            let (from, to) = match found.and_then(|_| region.pos(0)) {
+                Some(pos) => pos,
+                None => break,
+            };
+            regions.push(region);
+            if !global {
+                break;
+            }
+            start = if to == from {
+                to + s[to..].chars().next().map_or(1, char::len_utf8)
+            } else {
+                to
+            };
+        }
+        regions
+    }
+
+    /// `split($re; flags)`: the pieces of `s` between every match
+    pub(crate) fn split(&self, s: &str) -> JsonValue {
+        let mut parts = vec![];
+        let mut last = 0;
+        for region in self.find_all(s) {
+            if let Some((from, to)) = region.pos(0) {
+                parts.push(JsonValue::Str(s[last..from].to_string()));
+                last = to;
+            }
+        }
+        parts.push(JsonValue::Str(s[last..].to_string()));
+        JsonValue::Array(parts.into())
+    }
+
+    /// the object `match` produces for one region, with offsets and lengths in
+    /// codepoints. groups that didn't take part in the match have offset -1
+    pub(crate) fn match_object(&self, s: &str, region: &Region) -> JsonValue {
+        let span = |(from, to): (usize, usize)| {
+            let offset = s[..from].chars().count();
+            let text = &s[from..to];
+            (offset, text.chars().count(), text)
+        };
+        let (offset, length, text) = span(region.pos(0).unwrap_or((0, 0)));
+
+        let captures = (1..region.len())
+            .map(|g| {
+                let name = self.names[g]
+                    .clone()
+                    .map_or(JsonValue::Null, JsonValue::Str);
+                match region.pos(g) {
+                    Some(pos) => {
+                        let (offset, length, text) = span(pos);
+                        capture(
+                            offset as f64,
+                            length,
+                            JsonValue::Str(text.to_string()),
+                            name,
+                        )
+                    }
+                    None => capture(-1.0, 0, JsonValue::Null, name),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        JsonValue::from(BTreeMap::from([
+            ("offset".to_string(), JsonValue::Num(offset as f64)),
+            ("length".to_string(), JsonValue::Num(length as f64)),
+            ("string".to_string(), JsonValue::Str(text.to_string())),
+            ("captures".to_string(), JsonValue::Array(captures.into())),
+        ]))
+    }
+
+    /// what `scan` produces for one region: the text of each capture group if
+    /// there are any, otherwise the text of the whole match
+    pub(crate) fn scanned(&self, s: &str, region: &Region) -> JsonValue {
+        let text = |g| {
+            region.pos(g).map_or(JsonValue::Null, |(from, to)| {
+                JsonValue::Str(s[from..to].to_string())
+            })
+        };
+        if region.len() > 1 {
+            (1..region.len()).map(text).collect()
+        } else {
+            text(0)
+        }
+    }
+
+    /// the named captures of one region as an object, which is what `capture`
+    /// produces and what the replacement in `sub` sees as its input
+    pub(crate) fn named_captures(&self, s: &str, region: &Region) -> JsonValue {
+        (1..region.len())
+            .filter_map(|g| {
+                let name = self.names[g].clone()?;
+                let text = region.pos(g).map_or(JsonValue::Null, |(from, to)| {
+                    JsonValue::Str(s[from..to].to_string())
+                });
+                Some((name, text))
+            })
+            .collect()
+    }
+}
+
+fn capture(offset: f64, length: usize, text: JsonValue, name: JsonValue) -> JsonValue {
+    JsonValue::from(BTreeMap::from([
+        ("offset".to_string(), JsonValue::Num(offset)),
+        ("length".to_string(), JsonValue::Num(length as f64)),
+        ("string".to_string(), text),
+        ("name".to_string(), name),
+    ]))
+}
+
+/// remembers the last regex compiled at one call site, so that a filter like
+/// `select(test("^err"))` compiles its regex once rather than once per input
+#[derive(Default)]
+pub(crate) struct RegexCache(RefCell<Option<(JsonValue, JsonValue, Rc<Pattern>)>>);
+
+impl RegexCache {
+    pub(crate) fn get(
+        &self,
+        re: &JsonValue,
+        flags: &JsonValue,
+    ) -> Result<Rc<Pattern>, RuntimeError> {
+        let mut cached = self.0.borrow_mut();
+        match &*cached {
+            Some((r, f, pattern)) if r == re && f == flags => Ok(pattern.clone()),
+            _ => {
+                let pattern = Rc::new(Pattern::new(re, flags)?);
+                *cached = Some((re.clone(), flags.clone(), pattern.clone()));
+                Ok(pattern)
+            }
+        }
+    }
+}
+
+// the cache is an implementation detail of a call site, so it doesn't take part
+// in comparing or copying filters
+impl Clone for RegexCache {
+    fn clone(&self) -> Self {
+        RegexCache::default()
+    }
+}
+
+impl PartialEq for RegexCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for RegexCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RegexCache")
+    }
+}
@@ -1,70 +1,199 @@
 use std::io::Read;
-// use std::collections::VecDeque;
 
 use anyhow::Result;
-use nom::{error::VerboseError, Finish};
+use nom::error::VerboseError;
 use tracing::debug;
 
-use crate::json_parser::{root, JsonValue};
+use crate::json_parser::{
+    describe_error, root, root_at_eof, JsonParseError, JsonValue, JsonValueRef,
+};
+use crate::projection::Projection;
 
+/// reads JSON values one after another from `reader`. it reads into a buffer
+/// of a fixed capacity, moving what's left to the front when it fills up, and
+/// only grows it for a single value too big to fit, shrinking it back once
+/// that value has been parsed
 #[derive(Debug)]
 pub struct Streamer<R> {
+    /// `buf[start..end]` is read but not yet parsed
     buf: Vec<u8>,
     start: usize,
     end: usize,
     reader: R,
     eof: bool,
+    /// how big `buf` started out
+    capacity: usize,
+    /// where `buf[start]` is in the whole input
+    at: Position,
+    /// the parts of each value to build
+    projection: Projection,
 }
 
-const DEFAULT_BUF_SIZE: usize = 100;
-const ZEROS: [u8; DEFAULT_BUF_SIZE] = [0u8; DEFAULT_BUF_SIZE];
+/// how far into the input parsing has got, for line numbers and errors
+#[derive(Debug, Default)]
+pub(crate) struct Position {
+    /// newlines in the input parsed so far
+    pub(crate) lines: usize,
+    /// how far into the whole input parsing is
+    pub(crate) offset: usize,
+    /// how far into the whole input the current line starts
+    line_start: usize,
+    /// values parsed so far
+    pub(crate) records: usize,
+}
+
+/// how much of the input to show either side of an error
+const SNIPPET_LEN: usize = 20;
+
+pub(crate) const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+impl Position {
+    /// moves past `consumed`, the input from `offset` on
+    pub(crate) fn advance(&mut self, consumed: &[u8]) {
+        self.lines += consumed.iter().filter(|&&c| c == b'\n').count();
+        if let Some(last) = consumed.iter().rposition(|&c| c == b'\n') {
+            self.line_start = self.offset + last + 1;
+        }
+        self.offset += consumed.len();
+    }
+
+    /// an error `pos` bytes into `input`, the input from `offset` on as far as
+    /// it's been read
+    pub(crate) fn error_at(&self, input: &[u8], pos: usize, message: String) -> JsonParseError {
+        let before = &input[..pos];
+        let (line, line_start) = match before.iter().rposition(|&c| c == b'\n') {
+            Some(n) => (
+                self.lines + before.iter().filter(|&&c| c == b'\n').count(),
+                self.offset + n + 1,
+            ),
+            None => (self.lines, self.line_start),
+        };
+        let offset = self.offset + pos;
+
+        // the rest of the line either side, as far as it's read
+        let from = pos
+            .saturating_sub(SNIPPET_LEN)
+            .max(line_start.saturating_sub(self.offset));
+        let to = input[pos..]
+            .iter()
+            .take(SNIPPET_LEN)
+            .position(|&c| c == b'\n')
+            .map_or((pos + SNIPPET_LEN).min(input.len()), |n| pos + n);
+        JsonParseError {
+            message,
+            offset,
+            line: line + 1,
+            column: offset - line_start + 1,
+            record: self.records + 1,
+            snippet: String::from_utf8_lossy(&input[from..to]).into_owned(),
+        }
+    }
+
+    /// the error for `input` failing to parse with `err`
+    fn parse_error(&self, input: &[u8], err: &VerboseError<&[u8]>) -> JsonParseError {
+        // the innermost error is where parsing actually stopped
+        let pos = err
+            .errors
+            .first()
+            .map_or(0, |(rest, _)| input.len() - rest.len());
+        self.error_at(input, pos, describe_error(err))
+    }
+}
+
+/// the next value in `input`, which runs to the end of the whole input, and how
+/// many bytes to move past. there's no value if only whitespace is left
+pub(crate) fn parse_last<'a>(
+    input: &'a [u8],
+    at: &Position,
+    need: &Projection,
+) -> Result<(Option<JsonValueRef<'a>>, usize), JsonParseError> {
+    if input.iter().all(u8::is_ascii_whitespace) {
+        return Ok((None, input.len()));
+    }
+    match root_at_eof::<VerboseError<&[u8]>>(input, need) {
+        Ok((remaining, val)) => Ok((Some(val), input.len() - remaining.len())),
+        Err(nom::Err::Incomplete(_)) => {
+            let message = "unfinished JSON value at end of input".to_string();
+            Err(at.error_at(input, input.len(), message))
+        }
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) if need != &Projection::All => {
+            // the value is parsed again whole, so that what's reported about it
+            // doesn't depend on how much of it the filter needed
+            Err(parse_last(input, at, &Projection::All)
+                .err()
+                .unwrap_or_else(|| at.parse_error(input, &e)))
+        }
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(at.parse_error(input, &e)),
+    }
+}
+
+/// the line at the start of `input`, and how many bytes it takes up with its
+/// newline. `None` if it might carry on past `input`, which it can't at `eof`
+pub(crate) fn first_line(input: &[u8], eof: bool) -> Option<(usize, &[u8])> {
+    match input.iter().position(|&c| c == b'\n') {
+        Some(n) => Some((n + 1, &input[..n])),
+        None => eof.then_some((input.len(), input)),
+    }
+}
 
-// is this an iterator?
 impl<R: Read> Streamer<R> {
     pub fn new(reader: R) -> Self {
-        let mut buf = Vec::with_capacity(DEFAULT_BUF_SIZE);
-        buf.extend_from_slice(&ZEROS);
+        Self::with_capacity(reader, DEFAULT_CAPACITY)
+    }
+
+    /// reads `capacity` bytes at a time, or less if that's all there is
+    pub fn with_capacity(reader: R, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
         Self {
-            buf,
+            buf: vec![0; capacity],
+            capacity,
             reader,
             start: 0,
             end: 0,
             eof: false,
+            at: Position::default(),
+            projection: Projection::All,
         }
     }
 
-    // returns bytes consumed. 0 -> EOF
-    fn consume(&mut self) -> Result<usize> {
-        debug!(
-            "consume - buflen={}; cap={}, start={}, end={}",
-            self.buf.len(),
-            self.buf.capacity(),
-            self.start,
-            self.end
-        );
-
-        if self.end >= self.buf.len() {
-            self.grow_buf();
-        }
-        let b = &mut self.buf[self.end..];
-
-        debug!("{}", b.len());
-
-        let n = self.reader.read(b)?;
+    /// only builds the parts of each value in `projection`, skipping the rest
+    pub fn project(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
 
-        self.end += n;
+    /// how many lines of input have been parsed, as `input_line_number` reports
+    pub fn line_number(&self) -> usize {
+        self.at.lines
+    }
 
+    /// reads more into the buffer, returning how much. 0 -> EOF
+    ///
+    /// a value that's already outgrown the buffer's capacity is parsed from
+    /// the start again each time more is read, so it fills the buffer first.
+    /// a pipe only hands over a little at a time, and parsing after every read
+    /// would take time quadratic in the size of the value
+    fn consume(&mut self) -> Result<usize> {
+        if self.end == self.buf.len() {
+            self.make_room();
+        }
+        let mut n = 0;
+        loop {
+            let read = self.reader.read(&mut self.buf[self.end..])?;
+            self.end += read;
+            n += read;
+            let big = self.end - self.start >= self.capacity;
+            if read == 0 || !big || self.end == self.buf.len() {
+                break;
+            }
+        }
         debug!(
-            "consumed; buf={:?}",
-            "<".to_string()
-                + std::str::from_utf8(&self.buf[0..self.start]).unwrap()
-                + "> | <"
-                + std::str::from_utf8(self.buf()).unwrap()
-                + "> | <"
-                + std::str::from_utf8(&self.buf[self.end..]).unwrap()
-                + ">"
+            "consumed {}; start={}, end={}, cap={}",
+            n,
+            self.start,
+            self.end,
+            self.buf.len()
         );
-
         Ok(n)
     }
 
@@ -72,95 +201,134 @@ impl<R: Read> Streamer<R> {
         &self.buf[self.start..self.end]
     }
 
-    // probably can be better, with a vecdeque maybe, idk
-    fn realign_buf(&mut self) {
-        let mut new = Vec::with_capacity(DEFAULT_BUF_SIZE);
-        new.extend_from_slice(self.buf());
-        self.buf = new;
+    /// frees up the end of the buffer to read into. what's left is moved to
+    /// the front once at least half the buffer has been parsed, so each byte is
+    /// moved at most once on average. otherwise a value is taking up more than
+    /// half the buffer, so it doubles
+    fn make_room(&mut self) {
+        if self.start < self.buf.len() / 2 {
+            let len = self.buf.len();
+            self.buf.resize(len * 2, 0);
+        }
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        self.shrink();
     }
 
-    fn grow_buf(&mut self) {
-        // TODO: if buf is too big and start is big too, realign_buf here
-        // for now, the buf keeps growing on consume
-        self.buf.reserve(DEFAULT_BUF_SIZE);
-        self.buf.extend_from_slice(&ZEROS);
+    /// gives back the space the buffer grew by for a big value, once that value
+    /// is out of it. the buffer has to have been moved to the front first
+    fn shrink(&mut self) {
+        if self.start == 0 && self.buf.len() > self.capacity && self.end <= self.capacity / 2 {
+            self.buf.truncate(self.capacity);
+            self.buf.shrink_to_fit();
+        }
     }
 
     fn advance_by(&mut self, n: usize) {
-        let len = self.end - self.start;
-
+        self.at.advance(&self.buf[self.start..self.start + n]);
         self.start += n;
-        if self.start >= self.end {
-            self.realign_buf();
+        if self.start == self.end {
             self.start = 0;
-            self.end = len;
+            self.end = 0;
+            self.shrink();
         }
     }
-}
-
-impl<R: Read> Iterator for Streamer<R> {
-    type Item = Result<JsonValue>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let input = self.buf();
-        let input_len = input.len();
-        debug!("{:?}", std::str::from_utf8(input).unwrap());
-
-        let res = root::<VerboseError<&[u8]>>(input);
-
-        match res {
-            Ok((remaining, val)) => {
-                debug!("{:?}", &std::str::from_utf8(remaining));
-                debug!("{:?}", &val);
-                // is remaining always at the end? i think so
-                let remaining_len = remaining.len();
-                self.advance_by(input_len - remaining_len);
-                Some(Ok(val))
+    /// after an error, throws away the rest of the line the bad value started on,
+    /// so parsing picks up again on the next line. returns the number of that
+    /// line and what was thrown away
+    pub fn skip_line(&mut self) -> Result<(usize, Vec<u8>)> {
+        let blank = self
+            .buf()
+            .iter()
+            .take_while(|c| c.is_ascii_whitespace())
+            .count();
+        self.advance_by(blank);
+        self.at.records += 1;
+        let number = self.at.lines + 1;
+        loop {
+            if let Some((n, line)) = first_line(self.buf(), self.eof) {
+                let line = line.to_vec();
+                self.advance_by(n);
+                return Ok((number, line));
             }
-            Err(ref e) if e.is_incomplete() => {
-                debug!("{:?}", &e);
+            if self.consume()? == 0 {
+                self.eof = true;
+            }
+        }
+    }
 
-                if self.eof {
-                    debug!("done");
-                    return None;
+    /// the next value once everything has been read, passed to `f`
+    fn finish<T>(&mut self, f: impl FnOnce(JsonValueRef<'_>) -> T) -> Option<Result<T>> {
+        match parse_last(self.buf(), &self.at, &self.projection) {
+            Ok((val, n)) => {
+                let out = val.map(f);
+                self.advance_by(n);
+                if out.is_some() {
+                    self.at.records += 1;
                 }
+                out.map(Ok)
+            }
+            Err(e) => Some(Err(e.into())),
+        }
+    }
 
-                match self.consume() {
-                    Ok(0) => {
-                        self.eof = true;
-                        debug!("got eof but still trying again");
-                        // try again. TODO: better
-                        self.next()
-                    }
-                    Err(e) => {
-                        debug!("consume err");
-                        Some(Err(e))
-                    }
-                    Ok(_) => {
-                        debug!("trying again");
-                        // try again. TODO: better
-                        self.next()
-                    }
-                }
+    /// parses the next value and passes it to `f` while it still points into
+    /// the buffer, returning what `f` makes of it
+    pub fn next_with<T>(&mut self, f: impl FnOnce(JsonValueRef<'_>) -> T) -> Option<Result<T>> {
+        loop {
+            if self.eof {
+                return self.finish(f);
             }
-            Err(_) => {
-                let err = res.finish().unwrap_err();
-                dbg!(err
-                    .errors
-                    .iter()
-                    .map(|(data, kind)| (std::str::from_utf8(data).unwrap(), kind))
-                    .collect::<Vec<_>>());
-
-                // dbg!(nom::error::convert_error(
-                //     std::str::from_utf8(input).unwrap(),
-                //     err
-                // ));
-                return Some(Err(anyhow::anyhow!("idk")));
+            let input = self.buf();
+            let input_len = input.len();
+            debug!("parsing {} bytes", input_len);
+
+            match root::<VerboseError<&[u8]>>(input, &self.projection) {
+                Ok((remaining, val)) => {
+                    debug!("{:?}", &val);
+                    let n = input_len - remaining.len();
+                    let out = f(val);
+                    self.advance_by(n);
+                    self.at.records += 1;
+                    return Some(Ok(out));
+                }
+                // the value might carry on past what's been read, so read more and
+                // parse it again
+                Err(nom::Err::Incomplete(_)) => match self.consume() {
+                    Ok(0) => self.eof = true,
+                    Ok(_) => {}
+                    Err(e) => return Some(Err(e)),
+                },
+                Err(nom::Err::Error(e) | nom::Err::Failure(e))
+                    if self.projection != Projection::All =>
+                {
+                    // as in parse_last, the error reported is the one in the whole value
+                    let e = match root::<VerboseError<&[u8]>>(input, &Projection::All) {
+                        Err(nom::Err::Error(whole) | nom::Err::Failure(whole)) => whole,
+                        _ => e,
+                    };
+                    return Some(Err(self.at.parse_error(input, &e).into()));
+                }
+                Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                    return Some(Err(self.at.parse_error(input, &e).into()))
+                }
             }
         }
     }
 }
 
+impl<R: Read> Iterator for Streamer<R> {
+    type Item = Result<JsonValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(|v| v.into_value())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,10 +364,126 @@ mod tests {
         assert_eq!(v[0], JsonValue::Str("wat".to_string()));
         assert_eq!(v[1], JsonValue::Num(101.0));
 
-        // TODO: it always fails to parse the last thing. why??
-
         assert!(streamer.next().is_none());
 
         Ok(())
     }
+
+    #[test]
+    fn it_reads_values_of_any_kind() {
+        // a small buffer cuts the numbers short, so they have to be read again
+        let text = "12345 \"abc\" true\nnull -6.5e2 [1] 789";
+        let mut streamer = Streamer::with_capacity(text.as_bytes(), 2);
+        let values: Vec<_> = streamer.by_ref().map(Result::unwrap).collect();
+        let expected: JsonValue = r#"[12345, "abc", true, null, -650, [1], 789]"#.parse().unwrap();
+        assert_eq!(JsonValue::from(values), expected);
+        assert_eq!(streamer.line_number(), 1);
+    }
+
+    #[test]
+    fn it_reports_errors_in_skipped_parts_as_in_the_whole_value() {
+        let need = Projection::Fields([("a".to_string(), Projection::All)].into());
+        // the second is only found out to be bad at the end of the input
+        for text in ["{\"b\": [1,,], \"a\": 1}\n{\"a\": 2}", "{\"b\": {\"c\" 1}}"] {
+            let error = |mut streamer: Streamer<&[u8]>| {
+                let err = streamer.next().unwrap().unwrap_err();
+                err.downcast::<JsonParseError>().unwrap()
+            };
+            let projected = error(Streamer::new(text.as_bytes()).project(need.clone()));
+            assert_eq!(projected, error(Streamer::new(text.as_bytes())), "{}", text);
+        }
+    }
+
+    #[test]
+    fn it_shrinks_the_buffer_after_a_big_value() {
+        let text = format!("{:?}\n{}", vec![1; 100], "[2] ".repeat(200));
+        let mut streamer = Streamer::with_capacity(text.as_bytes(), 16);
+        assert_eq!(streamer.next().unwrap().unwrap()[99], JsonValue::Num(1.0));
+        assert!(streamer.buf.len() > 16);
+        // the small values go through the buffer at its own size again
+        for _ in 0..200 {
+            assert_eq!(streamer.next().unwrap().unwrap()[0], JsonValue::Num(2.0));
+        }
+        assert_eq!(streamer.buf.len(), 16);
+        assert!(streamer.next().is_none());
+    }
+
+    #[test]
+    fn it_reports_where_parsing_failed() {
+        // a small buffer and enough records first that it has been compacted a few times
+        let mut text = "[1]\n".repeat(100);
+        text.push_str("{\"a\": 1,\n  \"b\" 2}\n");
+        let mut streamer = Streamer::with_capacity(text.as_bytes(), 16);
+        for _ in 0..100 {
+            streamer.next().unwrap().unwrap();
+        }
+
+        let err = streamer.next().unwrap().unwrap_err();
+        let err = err.downcast::<JsonParseError>().unwrap();
+        assert_eq!(
+            err,
+            JsonParseError {
+                message: "expected `:` in an object".into(),
+                offset: 400 + 9 + 6,
+                line: 102,
+                column: 7,
+                record: 101,
+                snippet: "  \"b\" 2}".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn it_only_reports_values_that_are_invalid() {
+        let error = |text: &str| {
+            let mut streamer = Streamer::new(text.as_bytes());
+            let err = streamer.find_map(Result::err).expect("an error");
+            let err = err.downcast::<JsonParseError>().unwrap();
+            (err.message, err.column, err.record)
+        };
+        assert_eq!(error("1 \"a\" x"), ("invalid JSON value".into(), 7, 3));
+        assert_eq!(error("true \"a\\q\""), ("invalid string".into(), 9, 2));
+        assert_eq!(
+            error("null tru"),
+            ("unfinished JSON value at end of input".into(), 9, 2)
+        );
+    }
+
+    #[test]
+    fn it_skips_bad_lines() {
+        let text = "{\"a\": 1\n{\"b\": 2}\n[oops]\n[3]\n";
+        let mut streamer = Streamer::new(text.as_bytes());
+
+        // the first line is cut short, which only shows on the second
+        let err = streamer.next().unwrap().unwrap_err();
+        assert_eq!(err.downcast::<JsonParseError>().unwrap().line, 2);
+        assert_eq!(streamer.skip_line().unwrap(), (1, b"{\"a\": 1".to_vec()));
+        assert_eq!(streamer.next().unwrap().unwrap()["b"], JsonValue::Num(2.0));
+
+        let err = streamer.next().unwrap().unwrap_err();
+        let err = err.downcast::<JsonParseError>().unwrap();
+        assert_eq!((err.line, err.record), (3, 3));
+        assert_eq!(streamer.skip_line().unwrap(), (3, b"[oops]".to_vec()));
+        assert_eq!(streamer.next().unwrap().unwrap()[0], JsonValue::Num(3.0));
+        assert_eq!(streamer.line_number(), 4);
+        assert!(streamer.next().is_none());
+    }
+
+    #[test]
+    fn it_keeps_to_its_capacity() {
+        let text = "[1, 2]\n".repeat(1000) + "[3]\n";
+        let mut streamer = Streamer::with_capacity(text.as_bytes(), 64);
+        for _ in 0..1000 {
+            assert_eq!(streamer.next().unwrap().unwrap()[1], JsonValue::Num(2.0));
+        }
+        assert_eq!(streamer.buf.len(), 64);
+        assert_eq!(streamer.line_number(), 1000);
+
+        // a value bigger than the buffer makes it grow
+        let big = format!("[{}0]\n[1]\n", "0, ".repeat(100));
+        let mut streamer = Streamer::with_capacity(big.as_bytes(), 8);
+        let v = streamer.next().unwrap().unwrap();
+        assert_eq!(v, JsonValue::Array(vec![JsonValue::Num(0.0); 101].into()));
+        assert!(streamer.buf.len() >= 300);
+    }
 }
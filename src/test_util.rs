@@ -0,0 +1,13 @@
+//! helpers shared by the unit tests
+
+use crate::interpreter::RuntimeError;
+use crate::jq_parser::parse_filter;
+use crate::json_parser::JsonValue;
+
+/// the outputs of `filter` run on null, or its first error
+pub(crate) fn run(filter: &str) -> Result<Vec<JsonValue>, RuntimeError> {
+    parse_filter(filter)
+        .expect("filter parses")
+        .apply(JsonValue::Null)
+        .collect()
+}
@@ -0,0 +1,273 @@
+//! running the built binary, for what can't be checked from inside the library
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// what a run of jqr printed and how it exited
+struct Run {
+    stdout: String,
+    stderr: String,
+    code: i32,
+}
+
+/// runs jqr with `args` and `env`, with `input` on stdin
+fn jqr(args: &[&str], env: &[(&str, &str)], input: &str) -> Run {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jqr"))
+        .args(args)
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("jqr runs");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    Run {
+        stdout: String::from_utf8(out.stdout).unwrap(),
+        stderr: String::from_utf8(out.stderr).unwrap(),
+        code: out.status.code().unwrap(),
+    }
+}
+
+#[test]
+fn it_follows_tz() {
+    let filter = concat!(
+        r#"1425599621 | localtime, strflocaltime("%H:%M %Z"), "#,
+        "(localtime | mktime)",
+    );
+    let run = jqr(&["-n", filter], &[("TZ", "Asia/Tokyo")], "");
+    assert_eq!(run.stderr, "");
+    assert_eq!(
+        run.stdout,
+        "[2015,2,6,8,53,41,5,64]\n08:53 JST\n1425632021\n"
+    );
+    assert_eq!(run.code, 0);
+}
+
+#[test]
+fn it_names_files_it_cannot_open() {
+    for flag in ["--slurpfile", "--rawfile"] {
+        let run = jqr(&["-n", flag, "x", "/no/such/file", "$x"], &[], "");
+        assert!(
+            run.stderr
+                .starts_with("jqr: error: Could not open /no/such/file: "),
+            "{}",
+            run.stderr
+        );
+        assert_eq!(run.code, 2);
+    }
+}
+
+#[test]
+fn it_slurps_values_of_any_kind() {
+    let file = std::env::temp_dir().join(format!("jqr-slurp-{}.json", std::process::id()));
+    std::fs::write(&file, "5 \"a\"\ntrue null {\"b\": 1} 2.5").unwrap();
+
+    let run = jqr(
+        &["-n", "--slurpfile", "a", file.to_str().unwrap(), "$a"],
+        &[],
+        "",
+    );
+    assert_eq!(run.stderr, "");
+    assert_eq!(run.stdout, "[5,\"a\",true,null,{\"b\":1},2.5]\n");
+    assert_eq!(run.code, 0);
+
+    let run = jqr(&["-s", "."], &[], "1 \"x\" false 42");
+    assert_eq!(run.stdout, "[1,\"x\",false,42]\n");
+    assert_eq!(run.code, 0);
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn it_exits_with_2_for_bad_input_read_by_the_filter() {
+    let run = jqr(&["-n", "[inputs]"], &[], "[1]\n{\n");
+    assert!(run.stderr.starts_with("jqr: error: "), "{}", run.stderr);
+    assert_eq!(run.stdout, "");
+    assert_eq!(run.code, 2);
+
+    let run = jqr(&["-n", "input | error"], &[], "[1]\n");
+    assert_eq!(run.code, 5);
+}
+
+#[test]
+fn it_reports_lookups_that_dont_work() {
+    let run = jqr(&[".a"], &[], "{\"a\": 1}\n[2]\n{\"a\": 3}\n");
+    assert_eq!(run.stdout, "1\n3\n");
+    assert_eq!(run.stderr, "jqr: error: Cannot index array with \"a\"\n");
+    assert_eq!(run.code, 5);
+
+    // the same as when the filter isn't just a lookup
+    let run = jqr(&[".a | select(true)"], &[], "{\"a\": 1}\n[2]\n{\"a\": 3}\n");
+    assert_eq!(run.stdout, "1\n3\n");
+    assert_eq!(run.stderr, "jqr: error: Cannot index array with \"a\"\n");
+    assert_eq!(run.code, 5);
+}
+
+#[test]
+fn it_rejects_bad_json_it_doesnt_need() {
+    let input = "{\"a\":1,\"b\":[1,,}}\n{\"a\":2}\n";
+    let run = jqr(&[".a"], &[], input);
+    let whole = jqr(&["."], &[], input);
+    assert_eq!(run.stdout, "");
+    assert!(
+        run.stderr
+            .starts_with("jqr: error: invalid JSON value in an array at line 1, column 15"),
+        "{}",
+        run.stderr
+    );
+    assert_eq!(run.stderr, whole.stderr);
+    assert_eq!(run.code, 2);
+
+    let rejects = std::env::temp_dir().join(format!("jqr-rejects-{}", std::process::id()));
+    let run = jqr(
+        &[
+            ".a",
+            "--on-error",
+            "skip",
+            "--reject-file",
+            rejects.to_str().unwrap(),
+        ],
+        &[],
+        input,
+    );
+    assert_eq!(run.stdout, "2\n");
+    assert_eq!(run.code, 0);
+    assert_eq!(
+        std::fs::read_to_string(&rejects).unwrap(),
+        "{\"a\":1,\"b\":[1,,}}\n"
+    );
+    std::fs::remove_file(&rejects).unwrap();
+}
+
+#[test]
+fn it_projects_without_changing_what_is_printed() {
+    let input = concat!(
+        "{\"a\": {\"b\": 1, \"c\": [2]}, \"d\": \"x\"}\n",
+        "{\"a\": 3, \"d\": {\"e\": null}}\n",
+        "{\"d\": [1, {\"f\": true}], \"a\": {\"b\": \"y\"}}\n",
+        "[4]\n",
+        "{\"a\": 6, \"d\": [1,,]}\n",
+        "{\"a\": {\"b\": 5}}\n",
+    );
+    // `. as $v | ...` needs the whole input, so it isn't cut down
+    for filter in [".a.b", "[.a, .d]", "select(.d) | .a"] {
+        for on_error in ["fail", "warn", "skip"] {
+            let args = [filter, "--on-error", on_error];
+            let projected = jqr(&args, &[], input);
+            let whole = jqr(
+                &[
+                    &format!(". as $v | $v | {}", filter),
+                    "--on-error",
+                    on_error,
+                ],
+                &[],
+                input,
+            );
+            assert_eq!(projected.stdout, whole.stdout, "{}", filter);
+            assert_eq!(projected.stderr, whole.stderr, "{}", filter);
+            assert_eq!(projected.code, whole.code, "{}", filter);
+        }
+    }
+}
+
+#[test]
+fn it_reads_files_given_before_args() {
+    let file = std::env::temp_dir().join(format!("jqr-args-{}.json", std::process::id()));
+    std::fs::write(&file, "{\"x\": 1}\n").unwrap();
+    let file_arg = file.to_str().unwrap();
+
+    let run = jqr(
+        &["[$ARGS.positional, .x]", file_arg, "--args", "a", "b"],
+        &[],
+        "",
+    );
+    assert_eq!(run.stdout, "[[\"a\",\"b\"],1]\n");
+    assert_eq!(run.code, 0);
+
+    let run = jqr(
+        &["-n", "$ARGS.positional", file_arg, "--jsonargs", "1", "{}"],
+        &[],
+        "",
+    );
+    assert_eq!(run.stdout, "[1,{}]\n");
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn it_includes_the_jq_file_in_home() {
+    let home = std::env::temp_dir().join(format!("jqr-home-{}", std::process::id()));
+    std::fs::create_dir_all(home.join("lib")).unwrap();
+    // `now` ignores its input, so a projection would build nothing of it
+    std::fs::write(home.join(".jq"), "def hello: \"hi\"; def now: keys;").unwrap();
+    std::fs::write(home.join("lib/m.jq"), "module {v: 1};\ndef f: 1;").unwrap();
+    let env = [("HOME", home.to_str().unwrap())];
+
+    let run = jqr(&["[hello, now]"], &env, "{\"a\": 1, \"b\": 2}");
+    assert_eq!(run.stderr, "");
+    assert_eq!(run.stdout, "[\"hi\",[\"a\",\"b\"]]\n");
+
+    let run = jqr(&["-n", "-L", "~/lib", "\"m\" | modulemeta"], &env, "");
+    assert_eq!(run.stdout, "{\"defs\":[\"f/0\"],\"deps\":[],\"v\":1}\n");
+
+    let run = jqr(&["-n", "hello"], &[("HOME", "/nonexistent")], "");
+    assert!(
+        run.stderr.contains("hello/0 is not defined"),
+        "{}",
+        run.stderr
+    );
+    assert_eq!(run.code, 3);
+
+    std::fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn it_maps_files_only_when_asked() {
+    let file = std::env::temp_dir().join(format!("jqr-mmap-{}.json", std::process::id()));
+    std::fs::write(&file, "{\"a\": 1}\n{\"a\": x}\n[2]\n").unwrap();
+    let file_arg = file.to_str().unwrap();
+
+    let read = jqr(&[".", file_arg, "--on-error", "warn"], &[], "");
+    let mapped = jqr(&[".", file_arg, "--on-error", "warn", "--mmap"], &[], "");
+    assert_eq!(mapped.stdout, "{\"a\":1}\n[2]\n");
+    assert_eq!(mapped.stdout, read.stdout);
+    assert_eq!(mapped.stderr, read.stderr);
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn it_reads_through_a_buffer_of_any_size() {
+    let input = "{\"a\": [1, 2, 3], \"b\": \"long enough to outgrow the buffer\"}\n[4] 5\n";
+    let small = jqr(&["--buffer-size", "4", "."], &[], input);
+    let default = jqr(&["."], &[], input);
+    assert_eq!(small.stdout, default.stdout);
+    assert_eq!(small.code, 0);
+}
+
+#[test]
+fn it_limits_how_deeply_input_nests() {
+    let nested = |depth: usize| format!("{}{}\n", "[".repeat(depth), "]".repeat(depth));
+
+    let run = jqr(&["length"], &[], &nested(10000));
+    assert_eq!(run.stdout, "1\n");
+
+    for depth in [10001, 50000] {
+        let input = format!("{}{{\"a\": 3}}\n", nested(depth));
+        let run = jqr(&["length"], &[], &input);
+        assert!(run.stderr.contains("Exceeds depth limit for parsing"));
+        assert_eq!(run.code, 2);
+
+        // the whole of it is built for `.[]`, while `.a` only skips over it
+        for filter in [".[]", ".a"] {
+            let run = jqr(&[filter, "--on-error", "skip"], &[], &input);
+            assert_eq!((run.stdout.as_str(), run.code), ("3\n", 0), "{}", filter);
+        }
+    }
+}